@@ -1,5 +1,7 @@
 const GEOSUBMIT_ENDPOINT = "https://api.beacondb.net/v2/geosubmit";
 const RUST_SERVER_URL = "http://192.168.0.251:3030/network_json";
+// compat.token from config.json, when one is set
+const COMPAT_TOKEN = "";
 
 let logBuffer = "";
 
@@ -31,6 +33,9 @@ async function getPosition() {
 async function fetchJson() {
   try {
     const req = new Request(RUST_SERVER_URL);
+    if (COMPAT_TOKEN) {
+      req.headers = { Authorization: "Bearer " + COMPAT_TOKEN };
+    }
     const json = await req.loadJSON();
     log("[JSON] Fetched items: " + (json.items ? json.items.length : 0));
     return json;
@@ -0,0 +1,325 @@
+//! Command-line subcommands
+//!
+//! Without a subcommand the service starts as usual. Subcommands run once and exit.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use local_ip_address::local_ip;
+
+use crate::config::{self, HTTP_SERVER_PORT, Settings};
+use crate::devices::{self, DeviceRegistry};
+use crate::export::{self, ExportFormat};
+use crate::geosubmit::filter::SkipReason;
+use crate::geosubmit::{self, HttpClient, LocationSource, Position};
+use crate::history::{History, HistoryFilter};
+use crate::import::{self, ImportSummary};
+use crate::server::AppState;
+use crate::server::handlers::{Acceptance, accept_items};
+
+pub const USAGE: &str = "\
+Usage: service_berry [COMMAND]
+
+Commands:
+  geolocate [--lat <deg> --lon <deg> [--accuracy <m>]]
+      Scan, ask the geolocate endpoint where we are, and compare the answer
+      with a known reference position
+  export <wigle|geojson|kml> [--output <file>] [--since <time>] [--until <time>]
+         [--bbox <west,south,east,north>]
+      Write the stored history to a file, or to stdout. Times are milliseconds
+      since the Unix epoch or UTC dates like 2025-06-01 or 2025-06-01T12:00:00Z
+  import <file>... [--commit]
+      Read WiGLE or Tower Collector CSV exports and summarise what would be
+      submitted; with --commit, queue it for upload. Stop the daemon first
+  devices [list]
+      List the paired phones
+  devices pair
+      Show a one-time PIN (and QR code) for pairing a phone
+  devices rename <id> <name>
+      Rename a paired phone
+  devices revoke <id>
+      Unpair a phone; its token stops working immediately
+  devices certificate <id> <cert.pem>
+      Pin a phone's client certificate, for pairing.mutual_tls
+  help
+      Show this message";
+
+pub enum Command {
+    /// Run the service
+    Serve,
+    Geolocate {
+        reference: Option<Position>,
+    },
+    Export {
+        format: ExportFormat,
+        output: Option<PathBuf>,
+        filter: HistoryFilter,
+    },
+    Import {
+        files: Vec<PathBuf>,
+        commit: bool,
+    },
+    Devices(DeviceCommand),
+    Help,
+}
+
+pub enum DeviceCommand {
+    List,
+    Pair,
+    Rename { id: String, name: String },
+    Revoke { id: String },
+    Certificate { id: String, path: PathBuf },
+}
+
+impl Command {
+    /// Parse the arguments that follow the program name
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let Some(command) = args.next() else {
+            return Ok(Command::Serve);
+        };
+
+        match command.as_str() {
+            "geolocate" => {
+                let (mut lat, mut lon, mut accuracy) = (None, None, 0.0);
+                while let Some(flag) = args.next() {
+                    match flag.as_str() {
+                        "--lat" => lat = Some(parse_number(&flag, args.next())?),
+                        "--lon" => lon = Some(parse_number(&flag, args.next())?),
+                        "--accuracy" => accuracy = parse_number(&flag, args.next())?,
+                        other => return Err(format!("Unknown option '{}'", other)),
+                    }
+                }
+
+                let reference = match (lat, lon) {
+                    (Some(latitude), Some(longitude)) => {
+                        Some(manual_position(latitude, longitude, accuracy))
+                    }
+                    (None, None) => None,
+                    _ => return Err("--lat and --lon must be given together".into()),
+                };
+                Ok(Command::Geolocate { reference })
+            }
+            "export" => {
+                let format = args
+                    .next()
+                    .ok_or("export needs a format: wigle, geojson or kml")?
+                    .parse::<ExportFormat>()
+                    .map_err(|e| e.to_string())?;
+
+                let (mut output, mut since, mut until, mut bbox) = (None, None, None, None);
+                while let Some(flag) = args.next() {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} needs a value", flag))?;
+                    match flag.as_str() {
+                        "--output" | "-o" => output = Some(PathBuf::from(value)),
+                        "--since" => since = Some(value),
+                        "--until" => until = Some(value),
+                        "--bbox" => bbox = Some(value),
+                        other => return Err(format!("Unknown option '{}'", other)),
+                    }
+                }
+
+                let filter =
+                    export::history_filter(since.as_deref(), until.as_deref(), bbox.as_deref())
+                        .map_err(|e| e.to_string())?;
+                Ok(Command::Export {
+                    format,
+                    output,
+                    filter,
+                })
+            }
+            "import" => {
+                let (mut files, mut commit) = (Vec::new(), false);
+                for arg in args {
+                    match arg.as_str() {
+                        "--commit" => commit = true,
+                        other if other.starts_with("--") => {
+                            return Err(format!("Unknown option '{}'", other));
+                        }
+                        _ => files.push(PathBuf::from(arg)),
+                    }
+                }
+
+                if files.is_empty() {
+                    return Err("import needs at least one file".into());
+                }
+                Ok(Command::Import { files, commit })
+            }
+            "devices" => {
+                let command = match args.next().as_deref() {
+                    None | Some("list") => DeviceCommand::List,
+                    Some("pair") => DeviceCommand::Pair,
+                    Some("rename") => {
+                        let id = args.next().ok_or("rename needs a device id")?;
+                        let name = args.collect::<Vec<_>>().join(" ");
+                        if name.is_empty() {
+                            return Err("rename needs a new name".into());
+                        }
+                        DeviceCommand::Rename { id, name }
+                    }
+                    Some("revoke") => DeviceCommand::Revoke {
+                        id: args.next().ok_or("revoke needs a device id")?,
+                    },
+                    Some("certificate") => DeviceCommand::Certificate {
+                        id: args.next().ok_or("certificate needs a device id")?,
+                        path: PathBuf::from(args.next().ok_or("certificate needs a PEM file")?),
+                    },
+                    Some(other) => return Err(format!("Unknown devices command '{}'", other)),
+                };
+                Ok(Command::Devices(command))
+            }
+            "help" | "--help" | "-h" => Ok(Command::Help),
+            other => Err(format!("Unknown command '{}'", other)),
+        }
+    }
+}
+
+fn parse_number(flag: &str, value: Option<String>) -> Result<f64, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: '{}'", flag, value))
+}
+
+fn manual_position(latitude: f64, longitude: f64, accuracy: f64) -> Position {
+    Position {
+        latitude,
+        longitude,
+        accuracy,
+        altitude: 0.0,
+        altitudeAccuracy: 0.0,
+        heading: None,
+        speed: None,
+        source: LocationSource::Manual,
+    }
+}
+
+/// Run a self-check against the configured geolocate endpoint and print the result
+pub async fn geolocate(
+    settings: &Settings,
+    reference: Option<Position>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(position) = &reference {
+        position.validate()?;
+    }
+
+    let http = HttpClient::from_settings(&settings.http)?;
+    let check = geosubmit::self_check(
+        &http,
+        &settings.submission.geolocate_endpoint,
+        reference,
+        Vec::new(),
+    )
+    .await?;
+
+    println!("{}", serde_json::to_string_pretty(&check)?);
+    Ok(())
+}
+
+/// Export the stored history to a file, or to stdout
+pub fn export(
+    format: ExportFormat,
+    output: Option<PathBuf>,
+    filter: &HistoryFilter,
+) -> Result<(), Box<dyn Error>> {
+    let history = History::open(&config::history_path())?;
+
+    let mut out: Box<dyn Write> = match &output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    export::export(&history, format, filter, &mut out)?;
+
+    if let Some(path) = output {
+        eprintln!("Exported history to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Import wardriving exports. Without `commit`, only report what would be submitted.
+pub fn import(settings: Settings, files: &[PathBuf], commit: bool) -> Result<(), Box<dyn Error>> {
+    let mut summary = ImportSummary {
+        committed: commit,
+        ..Default::default()
+    };
+
+    let mut observations = Vec::new();
+    for path in files {
+        let parsed = import::read_file(path)?;
+        summary.add_file(path, &parsed);
+        observations.extend(parsed.observations);
+    }
+
+    let submissions = import::group_observations(observations);
+    summary.submissions = submissions.len();
+
+    let mut tally = |acceptance| match acceptance {
+        Acceptance::Queued => summary.accepted += 1,
+        Acceptance::InsideExclusionZone => summary.inside_exclusion_zones += 1,
+        Acceptance::Skipped(SkipReason::TooFewObservations) => summary.too_few_observations += 1,
+        Acceptance::Skipped(SkipReason::Duplicate) => summary.duplicates += 1,
+    };
+
+    // screened against the daemon's queue, history and excluded emitters, so it
+    // mustn't be running; a dry run goes through the same checks but writes nothing
+    let _data_dir_lock = config::lock_data_dir()?;
+    let state = AppState::new(settings)?;
+    for submission in submissions {
+        tally(accept_items(&state, submission, !commit)?);
+    }
+
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    if !commit {
+        eprintln!(
+            "Dry run: nothing was queued. Run again with --commit to queue {} submission(s).",
+            summary.accepted
+        );
+    }
+    Ok(())
+}
+
+/// Manage paired phones. The daemon picks up changes without a restart.
+pub fn devices(settings: &Settings, command: DeviceCommand) -> Result<(), Box<dyn Error>> {
+    let registry = DeviceRegistry::open(config::devices_path())?;
+
+    match command {
+        DeviceCommand::List => {
+            println!("{}", serde_json::to_string_pretty(&registry.devices()?)?);
+        }
+        DeviceCommand::Pair => {
+            let ttl = Duration::from_secs(settings.pairing.pin_ttl_secs);
+            let pin = registry.open_pin(ttl)?;
+
+            let instance_name = hostname::get()
+                .unwrap_or_else(|_| config::DEFAULT_HOSTNAME.into())
+                .to_string_lossy()
+                .to_string();
+            let identity = config::load_identity(instance_name, config::config_dir())?;
+            let host = local_ip()?.to_string();
+            let uri = devices::pairing_uri(&host, HTTP_SERVER_PORT, &identity.certs_hash, &pin);
+            devices::print_pin(&pin, ttl, &uri, settings.pairing.show_qr);
+        }
+        DeviceCommand::Rename { id, name } => {
+            registry.rename(&id, &name)?;
+            eprintln!("Renamed {} to '{}'", id, name.trim());
+        }
+        DeviceCommand::Revoke { id } => {
+            let revoked = registry.revoke(&id)?;
+            eprintln!("Revoked '{}' ({})", revoked.name, revoked.id);
+        }
+        DeviceCommand::Certificate { id, path } => {
+            let certificate = devices::parse_certificate_pem(&fs::read_to_string(&path)?)?;
+            registry.pin_certificate(&id, &certificate)?;
+            eprintln!(
+                "Pinned certificate {} for {}",
+                devices::certificate_fingerprint(&certificate),
+                id
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Wall-clock helpers: Unix timestamps in milliseconds, and the UTC date formats
+//! used by the CLI, exports and GPS receivers
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
+
+/// Milliseconds since the Unix epoch
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Parse a time as milliseconds since the Unix epoch, or as a UTC date
+/// (`2025-06-01`) or date and time (`2025-06-01T12:00:00Z`)
+pub fn parse_time(value: &str) -> Result<i64> {
+    let invalid = || Error::Validation(format!("invalid time '{}'", value));
+
+    if let Ok(millis) = value.parse::<i64>() {
+        return Ok(millis);
+    }
+
+    let value = value.trim_end_matches('Z');
+    let (date, time) = value.split_once(['T', ' ']).unwrap_or((value, "00:00:00"));
+
+    let date = date
+        .split('-')
+        .map(str::parse::<i64>)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    // Fractions of a second, as in gpsd's `2025-06-01T12:00:00.250Z`
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let millis = match fraction {
+        "" => 0,
+        digits if digits.bytes().all(|b| b.is_ascii_digit()) => {
+            format!("{:0<3}", &digits[..digits.len().min(3)])
+                .parse::<i64>()
+                .map_err(|_| invalid())?
+        }
+        _ => return Err(invalid()),
+    };
+    let time = time
+        .split(':')
+        .map(str::parse::<i64>)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+
+    let (&[year, month, day], &[hour, minute, second]) = (&date[..], &time[..]) else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..=60).contains(&second)
+    {
+        return Err(invalid());
+    }
+
+    let days = days_from_civil(year, month, day);
+    // a day past the end of its month, such as February 30th
+    if civil_from_days(days) != (year, month, day) {
+        return Err(invalid());
+    }
+    Ok(((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + millis)
+}
+
+/// Format milliseconds since the Unix epoch as `YYYY-MM-DD<sep>HH:MM:SS` in UTC
+pub fn format_time(millis: i64, separator: char) -> String {
+    let seconds = millis.div_euclid(1000);
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let of_day = seconds.rem_euclid(86_400);
+
+    format!(
+        "{:04}-{:02}-{:02}{}{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        separator,
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60
+    )
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86_400_000;
+
+    #[test]
+    fn parses_dates_around_the_epoch() {
+        assert_eq!(parse_time("1970-01-01").unwrap(), 0);
+        assert_eq!(parse_time("1970-01-02T00:00:00Z").unwrap(), DAY);
+        assert_eq!(parse_time("1969-12-31T23:59:59Z").unwrap(), -1000);
+        assert_eq!(parse_time("1900-03-01").unwrap(), -2_203_891_200_000);
+        assert_eq!(parse_time("1600-01-01").unwrap(), -11_676_096_000_000);
+        assert_eq!(
+            parse_time("2025-06-01 12:00:00.25").unwrap(),
+            1_748_779_200_250
+        );
+        assert_eq!(parse_time("1748779200000").unwrap(), 1_748_779_200_000);
+    }
+
+    #[test]
+    fn knows_which_years_are_leap_years() {
+        assert_eq!(parse_time("2000-02-29T12:00:00Z").unwrap(), 951_825_600_000);
+        assert_eq!(parse_time("2024-02-29").unwrap(), 1_709_164_800_000);
+        for date in [
+            "1900-02-29",
+            "2100-02-29",
+            "2025-02-29",
+            "2025-04-31",
+            "2025-13-01",
+        ] {
+            assert!(parse_time(date).is_err(), "{}", date);
+        }
+        assert_eq!(
+            parse_time("2024-03-01").unwrap() - parse_time("2024-02-28").unwrap(),
+            2 * DAY
+        );
+    }
+
+    #[test]
+    fn formats_times_before_and_after_the_epoch() {
+        assert_eq!(format_time(0, 'T'), "1970-01-01T00:00:00");
+        assert_eq!(format_time(-1, ' '), "1969-12-31 23:59:59");
+        assert_eq!(format_time(951_825_600_000, 'T'), "2000-02-29T12:00:00");
+        assert_eq!(format_time(-2_203_891_200_000, 'T'), "1900-03-01T00:00:00");
+    }
+
+    #[test]
+    fn days_round_trip_through_civil_dates() {
+        // about 2200 years either side of the epoch
+        for days in (-800_000..800_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days, "{}", days);
+        }
+
+        let mut days = days_from_civil(1899, 12, 31);
+        for year in 1900..2101 {
+            for month in 1..=12 {
+                let length = match month {
+                    2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+                    2 => 28,
+                    4 | 6 | 9 | 11 => 30,
+                    _ => 31,
+                };
+                for day in 1..=length {
+                    days += 1;
+                    assert_eq!(civil_from_days(days), (year, month, day));
+                }
+            }
+        }
+    }
+}
@@ -3,14 +3,85 @@
 use directories::ProjectDirs;
 use rcgen::generate_simple_self_signed;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use std::{error::Error, fs, path::PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::geosubmit::privacy::PrivacyPolicy;
+use crate::geosubmit::zones::ExclusionZone;
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
 
 pub const SCAN_DURATION_SECS: u64 = 10;
+pub const WIFI_INTERFACE: &str = "wlan0";
+pub const REQUEST_SCAN_MAX_AGE_SECS: u64 = 30;
 pub const GEOSUBMIT_ENDPOINT: &str = "https://api.beacondb.net/v2/geosubmit";
+pub const GEOLOCATE_ENDPOINT: &str = "https://api.beacondb.net/v1/geolocate";
+pub const GEOSUBMIT_PROVIDER_NAME: &str = "beacondb";
+pub const DRY_RUN_PROVIDER_NAME: &str = "dry-run";
 pub const APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 pub const MDNS_SERVICE_TYPE: &str = "serviceberry"; // no capitals
 pub const HTTP_SERVER_PORT: u16 = 8080;
+pub const PAIRING_PIN_TTL_SECS: u64 = 5 * 60;
+pub const PAIRING_MAX_ATTEMPTS: u32 = 5;
+pub const DEVICE_NAME_MAX_CHARS: usize = 64;
+pub const DEVICE_LAST_SEEN_RESOLUTION_SECS: i64 = 60;
+pub const METRICS_HTTP_PORT: u16 = 9464;
+pub const COMPAT_HTTP_PORT: u16 = 3030;
 pub const DEFAULT_HOSTNAME: &str = "turtle";
+pub const QUEUE_BATCH_MAX_ITEMS: usize = 50;
+pub const QUEUE_BATCH_MAX_BYTES: usize = 512 * 1024;
+pub const ARCHIVE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const ARCHIVE_MAX_AGE_HOURS: u64 = 0;
+pub const ARCHIVE_MAX_FILES: usize = 10;
+pub const HTTP_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const HTTP_TIMEOUT_SECS: u64 = 30;
+pub const HTTP_MAX_RETRIES: u32 = 5;
+pub const PRIVACY_MIN_DELAY_SECS: u64 = 10 * 60;
+pub const PRIVACY_MAX_DELAY_SECS: u64 = 60 * 60;
+pub const PRIVACY_FLUSH_INTERVAL_SECS: u64 = 15 * 60;
+pub const MIN_WIFI_ACCESS_POINTS: usize = 2;
+pub const MIN_CELL_TOWERS: usize = 1;
+pub const DEDUP_WINDOW_SECS: u64 = 5 * 60;
+pub const DEDUP_DISTANCE_METERS: f64 = 25.0;
+pub const DEDUP_BSSID_OVERLAP: f64 = 0.8;
+pub const LEARN_MAX_POSITION_ACCURACY_METERS: f64 = 50.0;
+pub const EMITTER_MIN_RADIUS_METERS: f64 = 25.0;
+pub const EMITTER_MAX_RADIUS_METERS: f64 = 500.0;
+pub const LOCAL_GEOLOCATE_MIN_EMITTERS: usize = 2;
+pub const HISTORY_RETENTION_DAYS: u64 = 365;
+pub const HISTORY_PRUNE_INTERVAL_SECS: u64 = 6 * 60 * 60;
+pub const IMPORT_GROUP_WINDOW_SECS: u64 = 5;
+pub const IMPORT_GROUP_DISTANCE_METERS: f64 = 30.0;
+pub const GPSD_ADDRESS: &str = "127.0.0.1:2947";
+pub const GPSD_RECONNECT_SECS: u64 = 5;
+/// Serial device of a receiver read directly, a USB one by default
+pub const NMEA_DEVICE: &str = "/dev/ttyACM0";
+/// The usual rate for NMEA 0183; USB receivers ignore it
+pub const NMEA_BAUD_RATE: u32 = 9600;
+pub const NMEA_REOPEN_SECS: u64 = 5;
+/// User equivalent range error, to turn HDOP into meters when gpsd gives no error estimate
+pub const GPS_UERE_METERS: f64 = 5.0;
+pub const MAX_FIX_AGE_SECS: u64 = 10;
+/// How much worse a fix is considered for each second of age when choosing between sources
+pub const FIX_AGE_PENALTY_METERS_PER_SEC: f64 = 5.0;
+pub const STATIONARY_INTERVAL_SECS: u64 = 15 * 60;
+pub const STATIONARY_BASELINE_SCANS: usize = 4;
+pub const STATIONARY_MIN_ACCESS_POINTS: usize = 3;
+pub const STATIONARY_MIN_OVERLAP: f64 = 0.6;
+pub const HEALTH_RECENT_ERRORS: usize = 100;
+pub const HEALTH_ERROR_WINDOW_SECS: u64 = 60 * 60;
+pub const CERTIFICATE_EXPIRY_WARNING_DAYS: i64 = 14;
+pub const QUEUE_RETRY_BASE_SECS: u64 = 5;
+pub const QUEUE_RETRY_MAX_SECS: u64 = 30 * 60;
+pub const QUEUE_POLL_SECS: u64 = 60;
+pub const QUEUE_MAX_ATTEMPTS: u32 = 100;
+pub const QUEUE_MAX_AGE_DAYS: u64 = 30;
+pub const RATE_LIMIT_BURST: u32 = 5;
+pub const RATE_LIMIT_PER_MINUTE: u32 = 6;
+pub const BREAKER_FAILURE_THRESHOLD: u32 = 5;
+pub const BREAKER_COOLDOWN_SECS: u64 = 10 * 60;
 
 /// Get the project configuration directory
 pub fn config_dir() -> PathBuf {
@@ -22,6 +93,387 @@ pub fn config_dir() -> PathBuf {
     config_dir.to_path_buf()
 }
 
+/// Get the project data directory
+pub fn data_dir() -> PathBuf {
+    let proj_dirs = ProjectDirs::from("org", "LimesKey", "serviceberry")
+        .expect("Failed to get project directories");
+
+    let data_dir = proj_dirs.data_dir();
+    fs::create_dir_all(data_dir).expect("Failed to create data directory");
+    data_dir.to_path_buf()
+}
+
+/// Lock the data directory for writing the queue, history and excluded emitters.
+/// The daemon holds it while running, so `import` can't write alongside it.
+pub fn lock_data_dir() -> crate::error::Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(data_dir().join("serviceberry.lock"))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(fs::TryLockError::WouldBlock) => Err(crate::error::Error::Other(
+            "the data directory is in use; stop the daemon first".to_string(),
+        )),
+        Err(fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+pub const HISTORY_FILE: &str = "history.sqlite3";
+pub const DEVICES_FILE: &str = "devices.json";
+
+/// Location of the SQLite observation history
+pub fn history_path() -> PathBuf {
+    data_dir().join(HISTORY_FILE)
+}
+
+/// Paired phones and their tokens
+pub fn devices_path() -> PathBuf {
+    data_dir().join(DEVICES_FILE)
+}
+
+/// Runtime settings, read from `config.json` in the config directory.
+/// Every field is optional; anything missing falls back to the defaults above.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub submission: SubmissionSettings,
+    pub http: HttpSettings,
+    pub provider: ProviderSettings,
+    pub privacy: PrivacyPolicy,
+    /// Places where nothing is ever submitted
+    pub exclusion_zones: Vec<ExclusionZone>,
+    pub history: HistorySettings,
+    pub positioning: PositioningSettings,
+    pub stationary: StationarySettings,
+    pub compat: CompatSettings,
+    pub metrics: MetricsSettings,
+    pub pairing: PairingSettings,
+}
+
+/// Pairing phones, so only they can use `/submit` and `/request`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PairingSettings {
+    /// Refuse `/submit` and `/request` without a paired device's token
+    pub required: bool,
+    /// How long a pairing PIN stays valid
+    pub pin_ttl_secs: u64,
+    /// Also show the PIN as a QR code in the terminal
+    pub show_qr: bool,
+    /// Ask for client certificates during the TLS handshake, accepting only those
+    /// pinned for a paired device, and let them stand in for a token
+    pub mutual_tls: bool,
+    /// Whether device tokens are accepted; turn off with `mutual_tls` so only
+    /// client certificates are
+    pub accept_tokens: bool,
+}
+
+impl Default for PairingSettings {
+    fn default() -> Self {
+        PairingSettings {
+            required: false,
+            pin_ttl_secs: PAIRING_PIN_TTL_SECS,
+            show_qr: true,
+            mutual_tls: false,
+            accept_tokens: true,
+        }
+    }
+}
+
+/// Prometheus metrics on `/metrics`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MetricsSettings {
+    pub enabled: bool,
+    /// Serve them over plain HTTP on `port` instead of on the HTTPS server
+    pub separate_listener: bool,
+    pub port: u16,
+    /// Read-only token the separate listener requires, as device tokens are
+    /// never accepted over plain HTTP
+    pub token: Option<String>,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            enabled: false,
+            separate_listener: false,
+            port: METRICS_HTTP_PORT,
+            token: None,
+        }
+    }
+}
+
+/// Support for the original Scriptable phone client (`script.js`)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CompatSettings {
+    /// Also serve `/network_json` over plain HTTP, as the script expects
+    pub plain_http: bool,
+    pub port: u16,
+    /// Read-only token the plain-HTTP listener requires, as device tokens are
+    /// never accepted over plain HTTP
+    pub token: Option<String>,
+}
+
+impl Default for CompatSettings {
+    fn default() -> Self {
+        CompatSettings {
+            plain_http: false,
+            port: COMPAT_HTTP_PORT,
+            token: None,
+        }
+    }
+}
+
+/// A node that stays put and submits from a fixed position, without a phone
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StationarySettings {
+    pub enabled: bool,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// How well the configured position is known, in meters
+    pub accuracy: f64,
+    pub altitude: f64,
+    /// Time between scheduled scans
+    pub interval_secs: u64,
+    /// Number of recent scans whose access points make up the baseline
+    pub baseline_scans: usize,
+    /// Scans with fewer access points than this are never submitted
+    pub min_access_points: usize,
+    /// Fraction (0-1) of a scan's access points that must be in the baseline
+    pub min_overlap: f64,
+}
+
+impl Default for StationarySettings {
+    fn default() -> Self {
+        StationarySettings {
+            enabled: false,
+            latitude: None,
+            longitude: None,
+            accuracy: 10.0,
+            altitude: 0.0,
+            interval_secs: STATIONARY_INTERVAL_SECS,
+            baseline_scans: STATIONARY_BASELINE_SCANS,
+            min_access_points: STATIONARY_MIN_ACCESS_POINTS,
+            min_overlap: STATIONARY_MIN_OVERLAP,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PositioningSettings {
+    /// Use this machine's GPS, through gpsd, when a submission has no position
+    pub gpsd: GpsdSettings,
+    /// ...or read its NMEA output directly
+    pub nmea: NmeaSettings,
+    /// Ignore local fixes older than this
+    pub max_fix_age_secs: u64,
+    /// Combine fixes from several sources that agree, rather than only using the best
+    pub fuse: bool,
+}
+
+impl Default for PositioningSettings {
+    fn default() -> Self {
+        PositioningSettings {
+            gpsd: GpsdSettings::default(),
+            nmea: NmeaSettings::default(),
+            max_fix_age_secs: MAX_FIX_AGE_SECS,
+            fuse: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GpsdSettings {
+    pub enabled: bool,
+    /// gpsd's `host:port`
+    pub address: String,
+}
+
+impl Default for GpsdSettings {
+    fn default() -> Self {
+        GpsdSettings {
+            enabled: false,
+            address: GPSD_ADDRESS.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HistorySettings {
+    /// Keep every accepted submission in `history.sqlite3` in the data directory
+    pub enabled: bool,
+    /// Delete submissions, and emitters last seen, longer ago than this; 0 keeps everything
+    pub retention_days: u64,
+    /// Keep at most this many of the newest submissions; 0 means no limit
+    pub max_submissions: u64,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        HistorySettings {
+            enabled: true,
+            retention_days: HISTORY_RETENTION_DAYS,
+            max_submissions: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub connect_timeout_secs: u64,
+    /// Timeout for a whole request, including the response body
+    pub timeout_secs: u64,
+    /// Retries for transient failures within a single upload attempt
+    pub max_retries: u32,
+    pub retry_min_backoff_ms: u64,
+    pub retry_max_backoff_secs: u64,
+    /// HTTP(S) or SOCKS5 proxy for provider requests, e.g. `socks5h://127.0.0.1:9050` for Tor
+    pub proxy: Option<String>,
+    /// PEM files with extra CA certificates to trust, for self-hosted providers
+    pub ca_certificates: Vec<PathBuf>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            connect_timeout_secs: HTTP_CONNECT_TIMEOUT_SECS,
+            timeout_secs: HTTP_TIMEOUT_SECS,
+            max_retries: HTTP_MAX_RETRIES,
+            retry_min_backoff_ms: 500,
+            retry_max_backoff_secs: 30,
+            proxy: None,
+            ca_certificates: Vec::new(),
+        }
+    }
+}
+
+/// How hard the geosubmit provider may be pushed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProviderSettings {
+    /// Uploads that may be made back to back before the rate limit applies
+    pub rate_limit_burst: u32,
+    /// Sustained uploads per minute
+    pub rate_limit_per_minute: u32,
+    /// Consecutive failed uploads after which submissions pause...
+    pub breaker_failure_threshold: u32,
+    /// ...for this long, before a trial upload
+    pub breaker_cooldown_secs: u64,
+}
+
+impl Default for ProviderSettings {
+    fn default() -> Self {
+        ProviderSettings {
+            rate_limit_burst: RATE_LIMIT_BURST,
+            rate_limit_per_minute: RATE_LIMIT_PER_MINUTE,
+            breaker_failure_threshold: BREAKER_FAILURE_THRESHOLD,
+            breaker_cooldown_secs: BREAKER_COOLDOWN_SECS,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SubmissionSettings {
+    /// Maximum number of queued items sent in one geosubmit request
+    pub batch_max_items: usize,
+    /// Maximum uncompressed size of one geosubmit request body, in bytes
+    pub batch_max_bytes: usize,
+    /// A queued submission whose delivery has failed this many times is quarantined; 0 for no limit
+    pub max_attempts: u32,
+    /// ...as is one that fails to deliver after being queued for this many days; 0 for no limit
+    pub max_age_days: u64,
+    /// Send request bodies with `Content-Encoding: gzip`
+    pub gzip: bool,
+    /// Write submissions to a JSON Lines archive in the data directory instead of uploading
+    pub dry_run: bool,
+    /// Size at which the dry-run archive is rotated, in bytes
+    pub archive_max_bytes: u64,
+    /// Age at which the dry-run archive is rotated, however small, in hours; 0 for no limit
+    pub archive_max_age_hours: u64,
+    /// Number of rotated archive files to keep
+    pub archive_max_files: usize,
+    /// A submission qualifies if it meets any one of these minimums; 0 means no
+    /// requirement for that radio, and with all three at 0 every submission qualifies
+    pub min_wifi_access_points: usize,
+    pub min_cell_towers: usize,
+    pub min_bluetooth_beacons: usize,
+    /// Skip a submission made within this many seconds of the last accepted one...
+    pub dedup_window_secs: u64,
+    /// ...from within this distance of it, in meters...
+    pub dedup_distance_meters: f64,
+    /// ...when this fraction of the observed BSSIDs is the same (Jaccard index, 0-1)
+    pub dedup_bssid_overlap: f64,
+    /// Ichnaea-compatible geolocate endpoint used by the self-check
+    pub geolocate_endpoint: String,
+}
+
+impl Default for SubmissionSettings {
+    fn default() -> Self {
+        SubmissionSettings {
+            batch_max_items: QUEUE_BATCH_MAX_ITEMS,
+            batch_max_bytes: QUEUE_BATCH_MAX_BYTES,
+            max_attempts: QUEUE_MAX_ATTEMPTS,
+            max_age_days: QUEUE_MAX_AGE_DAYS,
+            gzip: true,
+            dry_run: false,
+            archive_max_bytes: ARCHIVE_MAX_BYTES,
+            archive_max_age_hours: ARCHIVE_MAX_AGE_HOURS,
+            archive_max_files: ARCHIVE_MAX_FILES,
+            min_wifi_access_points: MIN_WIFI_ACCESS_POINTS,
+            min_cell_towers: MIN_CELL_TOWERS,
+            min_bluetooth_beacons: 0,
+            dedup_window_secs: DEDUP_WINDOW_SECS,
+            dedup_distance_meters: DEDUP_DISTANCE_METERS,
+            dedup_bssid_overlap: DEDUP_BSSID_OVERLAP,
+            geolocate_endpoint: GEOLOCATE_ENDPOINT.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NmeaSettings {
+    pub enabled: bool,
+    /// Serial device, or a recorded NMEA log to replay once
+    pub path: PathBuf,
+    pub baud_rate: u32,
+}
+
+impl Default for NmeaSettings {
+    fn default() -> Self {
+        NmeaSettings {
+            enabled: false,
+            path: PathBuf::from(NMEA_DEVICE),
+            baud_rate: NMEA_BAUD_RATE,
+        }
+    }
+}
+
+/// Load settings from `config.json`, using the defaults if the file doesn't exist
+pub fn load_settings(config_directory: &Path) -> Result<Settings, Box<dyn Error>> {
+    let settings_path = config_directory.join("config.json");
+
+    if !settings_path.exists() {
+        return Ok(Settings::default());
+    }
+
+    let contents = fs::read(&settings_path)?;
+    let settings = serde_json::from_slice(&contents)
+        .map_err(|e| format!("Invalid {}: {}", settings_path.display(), e))?;
+    Ok(settings)
+}
+
 pub struct Identity {
     pub certs: Vec<CertificateDer<'static>>,
     pub key: PrivateKeyDer<'static>,
@@ -63,10 +515,7 @@ pub fn load_identity(
     let keys = fs::read(key_path)?;
 
     let cert_content: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut &*certs) // load cert from file into PEM format
-        .collect::<Result<Vec<_>, _>>()?
-        .into_iter()
-        .map(CertificateDer::from)
-        .collect();
+        .collect::<Result<Vec<_>, _>>()?;
     let key_content = PrivateKeyDer::from(
         // load key from file into PEM format
         rustls_pemfile::pkcs8_private_keys(&mut &*keys)
@@ -75,7 +524,7 @@ pub fn load_identity(
             .ok_or("No private key found")?,
     );
 
-    Ok(Identity::new(cert_content, key_content)?)
+    Identity::new(cert_content, key_content)
 }
 
 impl Identity {
@@ -92,13 +541,19 @@ impl Identity {
         Ok(identity)
     }
 
+    /// When the certificate expires, in milliseconds since the Unix epoch
+    pub fn not_after(&self) -> Option<i64> {
+        let (_, cert) = x509_parser::parse_x509_certificate(self.certs.first()?).ok()?;
+        Some(cert.validity().not_after.timestamp() * 1000)
+    }
+
     /// Get SHA256 fingerprint of the certificate
     fn fingerprint_sha256(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
         use sha2::{Digest, Sha256};
 
         let cert = self
             .certs
-            .get(0)
+            .first()
             .ok_or("No certificates available for fingerprint")?;
 
         let mut hasher = Sha256::new();
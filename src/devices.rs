@@ -0,0 +1,579 @@
+//! Phones paired with this daemon, and the tokens they authenticate with
+//!
+//! The daemon shows a one-time PIN; a phone that sends it to `/pair` gets a
+//! device token to send as `Authorization: Bearer <token>` from then on. Only a
+//! hash of each token is stored. A device can also pin a client certificate and
+//! authenticate with mutual TLS instead. `service_berry devices` edits the same
+//! file, so it's reloaded whenever it changes on disk, and every change is made
+//! to the latest version under a file lock.
+
+use qrcode::QrCode;
+use qrcode::render::unicode;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
+
+use crate::clock::now_millis;
+use crate::config::{
+    DEVICE_LAST_SEEN_RESOLUTION_SECS, DEVICE_NAME_MAX_CHARS, PAIRING_MAX_ATTEMPTS,
+};
+use crate::error::{Error, Result};
+
+const DEFAULT_DEVICE_NAME: &str = "Phone";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Device {
+    id: String,
+    name: String,
+    token_sha256: String,
+    /// SHA-256 of the client certificate the device presents, if it has one
+    #[serde(default)]
+    certificate_sha256: Option<String>,
+    paired_at: i64,
+    last_seen: Option<i64>,
+}
+
+/// A paired device as listed by the CLI and `/status`, without its token hash
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceSummary {
+    pub id: String,
+    pub name: String,
+    pub certificate_sha256: Option<String>,
+    /// Milliseconds since the Unix epoch
+    pub paired_at: i64,
+    pub last_seen: Option<i64>,
+}
+
+impl From<&Device> for DeviceSummary {
+    fn from(device: &Device) -> Self {
+        DeviceSummary {
+            id: device.id.clone(),
+            name: device.name.clone(),
+            certificate_sha256: device.certificate_sha256.clone(),
+            paired_at: device.paired_at,
+            last_seen: device.last_seen,
+        }
+    }
+}
+
+/// What `/pair` answers with; the token is never shown again
+#[derive(Serialize, Debug)]
+pub struct PairedDevice {
+    pub device_id: String,
+    pub name: String,
+    pub token: String,
+}
+
+/// The PIN currently accepted by `/pair`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Pin {
+    code: String,
+    expires_at: i64,
+    failed_attempts: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Registry {
+    devices: Vec<Device>,
+    pin: Option<Pin>,
+}
+
+struct Loaded {
+    registry: Registry,
+    /// The file as it was when last read or written
+    version: Option<FileVersion>,
+}
+
+/// Tells versions of the file apart: every save renames a new file into place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileVersion {
+    modified: Option<SystemTime>,
+    inode: u64,
+    len: u64,
+}
+
+impl From<fs::Metadata> for FileVersion {
+    fn from(metadata: fs::Metadata) -> Self {
+        FileVersion {
+            modified: metadata.modified().ok(),
+            inode: metadata.ino(),
+            len: metadata.len(),
+        }
+    }
+}
+
+pub struct DeviceRegistry {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl std::fmt::Debug for DeviceRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeviceRegistry")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DeviceRegistry {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let registry = DeviceRegistry {
+            path,
+            loaded: Mutex::new(Loaded {
+                registry: Registry::default(),
+                version: None,
+            }),
+        };
+        registry.read(|_| ())?;
+        Ok(registry)
+    }
+
+    /// Start accepting a new PIN for `ttl`, replacing any earlier one
+    pub fn open_pin(&self, ttl: Duration) -> Result<String> {
+        let code = format!("{:06}", rand::rng().random_range(0..1_000_000));
+        self.update(|registry| {
+            registry.pin = Some(Pin {
+                code: code.clone(),
+                expires_at: now_millis() + ttl.as_millis() as i64,
+                failed_attempts: 0,
+            });
+            ((), true)
+        })?;
+        Ok(code)
+    }
+
+    /// Exchange the open PIN for a new device token, pinning the device's client
+    /// certificate if it sent one. The PIN is used up either way after
+    /// `PAIRING_MAX_ATTEMPTS` wrong guesses.
+    pub fn pair(
+        &self,
+        pin: &str,
+        name: Option<&str>,
+        certificate: Option<&[u8]>,
+    ) -> Result<PairedDevice> {
+        let name = name.map(str::trim).unwrap_or(DEFAULT_DEVICE_NAME);
+        validate_name(name)?;
+
+        self.update(|registry| {
+            let Some(open) = registry.pin.as_mut() else {
+                let error = Error::Unauthorized("no pairing PIN is open".to_string());
+                return (Err(error), false);
+            };
+            if open.expires_at <= now_millis() {
+                registry.pin = None;
+                let error = Error::Unauthorized("the pairing PIN has expired".to_string());
+                return (Err(error), true);
+            }
+            // in constant time, so response times don't give away digits
+            if !bool::from(open.code.as_bytes().ct_eq(pin.trim().as_bytes())) {
+                // saved too, so wrong guesses are counted across restarts
+                open.failed_attempts += 1;
+                if open.failed_attempts >= PAIRING_MAX_ATTEMPTS {
+                    tracing::warn!("[Pairing] Too many wrong PINs; closing pairing");
+                    registry.pin = None;
+                }
+                let error = Error::Unauthorized("wrong pairing PIN".to_string());
+                return (Err(error), true);
+            }
+
+            let token = hex::encode(rand::rng().random::<[u8; 32]>());
+            let device = Device {
+                id: unused_id(&registry.devices),
+                name: name.to_string(),
+                token_sha256: token_hash(&token),
+                certificate_sha256: certificate.map(certificate_fingerprint),
+                paired_at: now_millis(),
+                last_seen: None,
+            };
+            tracing::info!("[Pairing] Paired '{}' as {}", device.name, device.id);
+
+            let paired = PairedDevice {
+                device_id: device.id.clone(),
+                name: device.name.clone(),
+                token,
+            };
+            registry.devices.push(device);
+            registry.pin = None;
+            (Ok(paired), true)
+        })?
+    }
+
+    /// The device a token belongs to, if any
+    pub fn authenticate(&self, token: &str) -> Result<Option<DeviceSummary>> {
+        let hash = token_hash(token);
+        self.seen(|device| device.token_sha256 == hash)
+    }
+
+    /// The device a client certificate is pinned for, if any
+    pub fn authenticate_certificate(&self, certificate: &[u8]) -> Result<Option<DeviceSummary>> {
+        let fingerprint = certificate_fingerprint(certificate);
+        self.seen(|device| device.certificate_sha256.as_ref() == Some(&fingerprint))
+    }
+
+    /// Whether any device has this client certificate pinned
+    pub fn is_pinned(&self, certificate: &[u8]) -> Result<bool> {
+        let fingerprint = certificate_fingerprint(certificate);
+        self.read(|registry| {
+            registry
+                .devices
+                .iter()
+                .any(|device| device.certificate_sha256.as_ref() == Some(&fingerprint))
+        })
+    }
+
+    /// Pin a client certificate for a paired device, replacing any earlier one
+    pub fn pin_certificate(&self, id: &str, certificate: &[u8]) -> Result<()> {
+        let fingerprint = certificate_fingerprint(certificate);
+        self.update(|registry| match find(&mut registry.devices, id) {
+            Ok(device) => {
+                device.certificate_sha256 = Some(fingerprint);
+                (Ok(()), true)
+            }
+            Err(e) => (Err(e), false),
+        })?
+    }
+
+    pub fn devices(&self) -> Result<Vec<DeviceSummary>> {
+        self.read(|registry| registry.devices.iter().map(DeviceSummary::from).collect())
+    }
+
+    /// Whether a PIN is open and hasn't expired
+    pub fn pin_open(&self) -> Result<bool> {
+        self.read(|registry| {
+            registry
+                .pin
+                .as_ref()
+                .is_some_and(|pin| pin.expires_at > now_millis())
+        })
+    }
+
+    pub fn rename(&self, id: &str, name: &str) -> Result<()> {
+        let name = name.trim();
+        validate_name(name)?;
+        self.update(|registry| match find(&mut registry.devices, id) {
+            Ok(device) => {
+                device.name = name.to_string();
+                (Ok(()), true)
+            }
+            Err(e) => (Err(e), false),
+        })?
+    }
+
+    pub fn revoke(&self, id: &str) -> Result<DeviceSummary> {
+        self.update(|registry| match find(&mut registry.devices, id) {
+            Ok(device) => {
+                let revoked = DeviceSummary::from(&*device);
+                registry.devices.retain(|device| device.id != id);
+                tracing::info!("[Pairing] Revoked '{}' ({})", revoked.name, revoked.id);
+                (Ok(revoked), true)
+            }
+            Err(e) => (Err(e), false),
+        })?
+    }
+
+    /// Find a device and note that it was just seen
+    fn seen(&self, matches: impl Fn(&Device) -> bool) -> Result<Option<DeviceSummary>> {
+        // only write to disk now and then, not on every request
+        let now = now_millis();
+        let is_stale = |device: &Device| {
+            device
+                .last_seen
+                .is_none_or(|seen| now - seen >= DEVICE_LAST_SEEN_RESOLUTION_SECS * 1000)
+        };
+
+        let found = self.read(|registry| {
+            let device = registry.devices.iter().find(|device| matches(device))?;
+            Some((DeviceSummary::from(device), is_stale(device)))
+        })?;
+        let Some((summary, true)) = found else {
+            return Ok(found.map(|(summary, _)| summary));
+        };
+
+        self.update(|registry| match find(&mut registry.devices, &summary.id) {
+            Ok(device) if is_stale(device) => {
+                device.last_seen = Some(now);
+                (Some(DeviceSummary::from(&*device)), true)
+            }
+            Ok(device) => (Some(DeviceSummary::from(&*device)), false),
+            // revoked in the meantime
+            Err(_) => (None, false),
+        })
+    }
+
+    /// Run `read` on the up-to-date registry
+    fn read<R>(&self, read: impl FnOnce(&Registry) -> R) -> Result<R> {
+        let mut loaded = self.loaded.lock().unwrap();
+        self.reload_if_changed(&mut loaded)?;
+        Ok(read(&loaded.registry))
+    }
+
+    /// Run `change` on the registry as it is on disk, holding the file lock so
+    /// no other process writes in between. `change` returns its result and
+    /// whether the registry needs saving.
+    fn update<R>(&self, change: impl FnOnce(&mut Registry) -> (R, bool)) -> Result<R> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let _lock = self.lock()?;
+        // always reload: what another process saved may look like the version we have
+        loaded.version = None;
+        self.reload_if_changed(&mut loaded)?;
+
+        let (result, changed) = change(&mut loaded.registry);
+        if changed {
+            self.save(&mut loaded)?;
+        }
+        Ok(result)
+    }
+
+    /// Exclusive lock on a file next to the registry, released when dropped
+    fn lock(&self) -> Result<fs::File> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(self.path.with_extension("json.lock"))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    fn reload_if_changed(&self, loaded: &mut Loaded) -> Result<()> {
+        let version = match fs::metadata(&self.path) {
+            Ok(metadata) => FileVersion::from(metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                loaded.registry = Registry::default();
+                loaded.version = None;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if version.modified.is_none() || loaded.version != Some(version) {
+            loaded.registry = serde_json::from_slice(&fs::read(&self.path)?)?;
+            loaded.version = Some(version);
+        }
+        Ok(())
+    }
+
+    /// Write via a temp file readable only by us, then rename
+    fn save(&self, loaded: &mut Loaded) -> Result<()> {
+        let temp_path = self.path.with_extension("json.tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(&loaded.registry)?)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        loaded.version = Some(FileVersion::from(fs::metadata(&self.path)?));
+        Ok(())
+    }
+}
+
+fn find<'a>(devices: &'a mut [Device], id: &str) -> Result<&'a mut Device> {
+    devices
+        .iter_mut()
+        .find(|device| device.id == id)
+        .ok_or_else(|| Error::Validation(format!("no paired device with id '{}'", id)))
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.chars().count() > DEVICE_NAME_MAX_CHARS {
+        return Err(Error::Validation(format!(
+            "device names must be 1 to {} characters",
+            DEVICE_NAME_MAX_CHARS
+        )));
+    }
+    Ok(())
+}
+
+fn unused_id(devices: &[Device]) -> String {
+    loop {
+        let id = hex::encode(rand::rng().random::<[u8; 4]>());
+        if devices.iter().all(|device| device.id != id) {
+            return id;
+        }
+    }
+}
+
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// SHA-256 of a DER-encoded certificate, as hex
+pub fn certificate_fingerprint(certificate: &[u8]) -> String {
+    hex::encode(Sha256::digest(certificate))
+}
+
+/// The first certificate in a PEM document, as DER
+pub fn parse_certificate_pem(pem: &str) -> Result<Vec<u8>> {
+    rustls_pemfile::certs(&mut pem.as_bytes())
+        .next()
+        .ok_or_else(|| Error::Validation("no certificate found in the PEM data".to_string()))?
+        .map(|certificate| certificate.to_vec())
+        .map_err(|e| Error::Validation(format!("invalid certificate: {}", e)))
+}
+
+/// What a phone's camera needs to pair: where to connect, which certificate to
+/// expect and the PIN
+pub fn pairing_uri(host: &str, port: u16, cert_fingerprint: &[u8], pin: &str) -> String {
+    format!(
+        "serviceberry://pair?host={}&port={}&fingerprint={}&pin={}",
+        host,
+        port,
+        hex::encode(cert_fingerprint),
+        pin
+    )
+}
+
+/// Print a PIN for someone at the terminal, with a QR code of `uri` if asked
+pub fn print_pin(pin: &str, ttl: Duration, uri: &str, show_qr: bool) {
+    println!(
+        "Pairing PIN: {} (valid for {} minute(s))",
+        pin,
+        ttl.as_secs().div_ceil(60)
+    );
+    if show_qr {
+        match QrCode::new(uri.as_bytes()) {
+            Ok(code) => println!(
+                "{}",
+                code.render::<unicode::Dense1x2>().quiet_zone(true).build()
+            ),
+            Err(e) => tracing::warn!("[Pairing] Couldn't draw a QR code: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn registry() -> (DeviceRegistry, PathBuf) {
+        let path = test_support::temp_dir("devices").join("devices.json");
+        (DeviceRegistry::open(path.clone()).unwrap(), path)
+    }
+
+    fn pair(registry: &DeviceRegistry, name: &str) -> PairedDevice {
+        let pin = registry.open_pin(Duration::from_secs(60)).unwrap();
+        registry.pair(&pin, Some(name), None).unwrap()
+    }
+
+    #[test]
+    fn pairs_once_per_pin_and_stores_only_token_hashes() {
+        let (registry, path) = registry();
+        let pin = registry.open_pin(Duration::from_secs(60)).unwrap();
+        assert!(registry.pin_open().unwrap());
+
+        let paired = registry
+            .pair(&format!(" {} ", pin), Some("Pixel 8"), None)
+            .unwrap();
+        assert!(!registry.pin_open().unwrap());
+        assert!(matches!(
+            registry.pair(&pin, None, None),
+            Err(Error::Unauthorized(_))
+        ));
+
+        let device = registry.authenticate(&paired.token).unwrap().unwrap();
+        assert_eq!(device.id, paired.device_id);
+        assert_eq!(device.name, "Pixel 8");
+        assert!(device.last_seen.is_some());
+        assert!(registry.authenticate("not a token").unwrap().is_none());
+
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains(&paired.token));
+        assert!(saved.contains(&token_hash(&paired.token)));
+    }
+
+    #[test]
+    fn expired_pins_are_refused() {
+        let (registry, _) = registry();
+        let pin = registry.open_pin(Duration::ZERO).unwrap();
+
+        assert!(!registry.pin_open().unwrap());
+        let error = registry.pair(&pin, None, None).unwrap_err();
+        assert!(error.to_string().contains("expired"), "{}", error);
+        assert!(registry.devices().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wrong_guesses_close_pairing_even_across_restarts() {
+        let (registry, path) = registry();
+        let pin = registry.open_pin(Duration::from_secs(60)).unwrap();
+        let wrong = if pin == "000000" { "000001" } else { "000000" };
+
+        for _ in 1..PAIRING_MAX_ATTEMPTS {
+            assert!(registry.pair(wrong, None, None).is_err());
+        }
+        assert!(registry.pin_open().unwrap());
+
+        let restarted = DeviceRegistry::open(path).unwrap();
+        assert!(restarted.pair(wrong, None, None).is_err());
+        assert!(!restarted.pin_open().unwrap());
+        assert!(restarted.pair(&pin, None, None).is_err());
+    }
+
+    #[test]
+    fn revoked_devices_no_longer_authenticate() {
+        let (registry, _) = registry();
+        let certificate = b"not really DER, but only its hash is kept";
+        let kept = pair(&registry, "Tablet");
+        let revoked = pair(&registry, "Phone");
+        registry
+            .pin_certificate(&revoked.device_id, certificate)
+            .unwrap();
+        assert!(registry.is_pinned(certificate).unwrap());
+
+        assert_eq!(registry.revoke(&revoked.device_id).unwrap().name, "Phone");
+        assert!(registry.authenticate(&revoked.token).unwrap().is_none());
+        assert!(!registry.is_pinned(certificate).unwrap());
+        assert!(
+            registry
+                .authenticate_certificate(certificate)
+                .unwrap()
+                .is_none()
+        );
+        assert!(registry.authenticate(&kept.token).unwrap().is_some());
+        assert!(matches!(
+            registry.revoke(&revoked.device_id),
+            Err(Error::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn picks_up_changes_from_another_process() {
+        // the daemon and the CLI, each with its own view of the same file
+        let (daemon, path) = registry();
+        let cli = DeviceRegistry::open(path).unwrap();
+
+        let phone = pair(&cli, "Phone");
+        assert!(daemon.authenticate(&phone.token).unwrap().is_some());
+
+        // interleaved writes from both are all kept
+        let tablet = pair(&daemon, "Tablet");
+        cli.rename(&phone.device_id, "Old phone").unwrap();
+        daemon.rename(&tablet.device_id, "Old tablet").unwrap();
+        for registry in [&daemon, &cli] {
+            let mut names = registry
+                .devices()
+                .unwrap()
+                .into_iter()
+                .map(|device| device.name)
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names, ["Old phone", "Old tablet"]);
+        }
+
+        cli.revoke(&phone.device_id).unwrap();
+        assert!(daemon.authenticate(&phone.token).unwrap().is_none());
+    }
+}
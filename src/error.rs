@@ -1,10 +1,14 @@
 use std::fmt;
+use std::time::Duration;
 
 use axum::{
     Json,
+    extract::rejection::JsonRejection,
+    http::{HeaderValue, header},
     response::{IntoResponse, Response},
 };
 use hyper::StatusCode;
+use serde::Serialize;
 use serde_json::json;
 
 #[derive(Debug)]
@@ -15,12 +19,22 @@ pub enum Error {
     InvalidSsid(String),
 
     // Geosubmit errors
-    Transport(String),
-    HttpStatus { status: u16, body: String },
+    Transport(reqwest_middleware::Error),
+    HttpStatus {
+        status: u16,
+        body: String,
+    },
+    RateLimited {
+        status: u16,
+        retry_after: Option<Duration>,
+    },
     Serialization(String),
+    Validation(String),
 
     // Server errors
     Bind(String),
+    /// The caller isn't a paired device, or gave a wrong pairing PIN
+    Unauthorized(String),
 
     // Config errors
     Config(String),
@@ -28,30 +42,270 @@ pub enum Error {
     // IO and serialization
     Io(std::io::Error),
     Json(serde_json::Error),
+    Database(rusqlite::Error),
 
     // Other errors
     Other(String),
 }
 
+/// Broad classification of an error, used to decide whether to retry and which
+/// HTTP status to answer with
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// Connection failures and timeouts
+    Network,
+    /// Certificate or handshake failures
+    Tls,
+    /// The data was rejected as invalid (HTTP 400/422 or bad input)
+    Validation,
+    /// Our own settings are wrong: a bad config file, endpoint URL or proxy
+    /// credentials (HTTP 401/403/404/407)
+    Config,
+    /// The caller didn't prove it's a paired device
+    Unauthorized,
+    /// The upstream service failed (HTTP 5xx)
+    Upstream,
+    /// The upstream service asked us to slow down (HTTP 429/503)
+    RateLimited,
+    /// Local hardware such as the WiFi or Bluetooth adapter isn't usable
+    Unavailable,
+    /// Anything else that went wrong on our side
+    Internal,
+}
+
+impl Error {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::BleAdapter(_) | Error::WifiScan(_) => ErrorCategory::Unavailable,
+            Error::InvalidSsid(_)
+            | Error::Serialization(_)
+            | Error::Validation(_)
+            | Error::Json(_) => ErrorCategory::Validation,
+            Error::Transport(e) => transport_category(e),
+            Error::HttpStatus { status, .. } => match *status {
+                400 | 422 => ErrorCategory::Validation,
+                401 | 403 | 404 | 407 => ErrorCategory::Config,
+                408 => ErrorCategory::Network,
+                // e.g. 413: nothing wrong with the data itself, so keep it queued
+                _ => ErrorCategory::Upstream,
+            },
+            Error::RateLimited { .. } => ErrorCategory::RateLimited,
+            Error::Unauthorized(_) => ErrorCategory::Unauthorized,
+            Error::Config(_) => ErrorCategory::Config,
+            Error::Bind(_) | Error::Io(_) | Error::Database(_) | Error::Other(_) => {
+                ErrorCategory::Internal
+            }
+        }
+    }
+
+    /// Whether trying the same operation again later could succeed
+    pub fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
+    }
+
+    /// How long the upstream service asked us to wait, if it said
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// HTTP status to answer with when this error reaches a handler
+    pub fn status_code(&self) -> StatusCode {
+        if let Error::Transport(e) = self
+            && e.is_timeout()
+        {
+            return StatusCode::GATEWAY_TIMEOUT;
+        }
+
+        match self.category() {
+            ErrorCategory::Validation => StatusCode::BAD_REQUEST,
+            ErrorCategory::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCategory::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCategory::Network | ErrorCategory::Tls | ErrorCategory::Upstream => {
+                StatusCode::BAD_GATEWAY
+            }
+            // the provider refused our credentials or endpoint, not the caller's request
+            ErrorCategory::Config if matches!(self, Error::HttpStatus { .. }) => {
+                StatusCode::BAD_GATEWAY
+            }
+            ErrorCategory::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCategory::Config | ErrorCategory::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable, machine-readable identifier for this kind of error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::BleAdapter(_) => "ble_adapter_unavailable",
+            Error::WifiScan(_) => "wifi_scan_failed",
+            Error::InvalidSsid(_) => "invalid_ssid",
+            Error::Transport(_) if self.category() == ErrorCategory::Tls => "upstream_tls_failed",
+            Error::Transport(_) => "upstream_unreachable",
+            Error::HttpStatus { status, .. } if *status < 500 => "upstream_rejected",
+            Error::HttpStatus { .. } => "upstream_failed",
+            Error::RateLimited { .. } => "upstream_rate_limited",
+            Error::Serialization(_) => "invalid_payload",
+            Error::Validation(_) => "validation_failed",
+            Error::Json(_) => "invalid_json",
+            Error::Bind(_) => "bind_failed",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Config(_) => "config_invalid",
+            Error::Io(_) => "io_failed",
+            Error::Database(_) => "database_failed",
+            Error::Other(_) => "internal_error",
+        }
+    }
+
+    /// Extra structured context included in problem responses
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            Error::HttpStatus { status, body } => Some(json!({
+                "upstream_status": status,
+                "upstream_body": body,
+            })),
+            Error::RateLimited {
+                status,
+                retry_after,
+            } => Some(json!({
+                "upstream_status": status,
+                "retry_after_secs": retry_after.map(|d| d.as_secs()),
+            })),
+            Error::Json(e) => Some(json!({
+                "line": e.line(),
+                "column": e.column(),
+            })),
+            _ => None,
+        }
+    }
+}
+
+/// RFC 9457 `application/problem+json` response body
+#[derive(Serialize, Debug)]
+pub struct Problem {
+    /// `urn:serviceberry:error:<code>`
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: &'static str,
+    pub category: ErrorCategory,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl From<&Error> for Problem {
+    fn from(error: &Error) -> Self {
+        let status = error.status_code();
+        let code = error.code();
+
+        Problem {
+            problem_type: format!("urn:serviceberry:error:{}", code),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: error.to_string(),
+            code,
+            category: error.category(),
+            details: error.details(),
+        }
+    }
+}
+
+impl ErrorCategory {
+    pub fn is_retryable(self) -> bool {
+        match self {
+            ErrorCategory::Network
+            | ErrorCategory::Upstream
+            | ErrorCategory::RateLimited
+            | ErrorCategory::Unavailable => true,
+            ErrorCategory::Tls
+            | ErrorCategory::Validation
+            | ErrorCategory::Config
+            | ErrorCategory::Unauthorized
+            | ErrorCategory::Internal => false,
+        }
+    }
+}
+
+fn transport_category(error: &reqwest_middleware::Error) -> ErrorCategory {
+    if is_tls_error(error) {
+        return ErrorCategory::Tls;
+    }
+
+    match error {
+        reqwest_middleware::Error::Reqwest(e) if e.is_builder() || e.is_redirect() => {
+            ErrorCategory::Internal
+        }
+        reqwest_middleware::Error::Reqwest(e) if e.is_decode() => ErrorCategory::Upstream,
+        _ => ErrorCategory::Network,
+    }
+}
+
+/// Walk the source chain looking for a certificate or handshake failure
+fn is_tls_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(error);
+
+    while let Some(e) = current {
+        if e.downcast_ref::<rustls::Error>().is_some() {
+            return true;
+        }
+
+        // rustls reports handshake failures as an `InvalidData` IO error wrapping
+        // its own error, which is sometimes wrapped in another IO error. Their
+        // `source()` skips past what they wrap, so look at that directly.
+        if let Some(io) = e.downcast_ref::<std::io::Error>()
+            && let Some(inner) = io.get_ref()
+        {
+            current = Some(inner);
+            continue;
+        }
+
+        current = e.source();
+    }
+
+    false
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::BleAdapter(msg) => write!(f, "BLE adapter error: {}", msg),
             Error::WifiScan(msg) => write!(f, "WiFi scan error: {}", msg),
             Error::InvalidSsid(msg) => write!(f, "Invalid SSID: {}", msg),
-            Error::Transport(msg) => write!(f, "Transport error: {}", msg),
+            Error::Transport(e) => write!(f, "Transport error: {}", e),
             Error::HttpStatus { status, body } => write!(f, "HTTP {}: {}", status, body),
+            Error::RateLimited {
+                status,
+                retry_after: Some(delay),
+            } => write!(f, "Rate limited (HTTP {}), retry after {:?}", status, delay),
+            Error::RateLimited { status, .. } => write!(f, "Rate limited (HTTP {})", status),
             Error::Serialization(msg) => write!(f, "Serialization error: {}", msg),
+            Error::Validation(msg) => write!(f, "Validation error: {}", msg),
             Error::Bind(msg) => write!(f, "Bind error: {}", msg),
+            Error::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
             Error::Config(msg) => write!(f, "Config error: {}", msg),
             Error::Io(e) => write!(f, "IO error: {}", e),
             Error::Json(e) => write!(f, "JSON error: {}", e),
+            Error::Database(e) => write!(f, "Database error: {}", e),
             Error::Other(msg) => write!(f, "Error: {}", msg),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
@@ -65,29 +319,404 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+impl From<reqwest_middleware::Error> for Error {
+    fn from(e: reqwest_middleware::Error) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Transport(reqwest_middleware::Error::Reqwest(e))
+    }
+}
+
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Self {
+        Error::Serialization(rejection.body_text())
+    }
+}
+
 /// Result type alias
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Attached to error responses, so the error can be accounted for after the handler returns
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            Error::Other(ref msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            Error::Bind(ref msg) => (StatusCode::BAD_REQUEST, msg),
-            Error::BleAdapter(_) => todo!(),
-            Error::WifiScan(_) => todo!(),
-            Error::InvalidSsid(_) => todo!(),
-            Error::Transport(_) => todo!(),
-            Error::HttpStatus { status, body } => todo!(),
-            Error::Serialization(_) => todo!(),
-            Error::Config(_) => todo!(),
-            Error::Io(error) => todo!(),
-            Error::Json(error) => todo!(),
-        };
-
-        let body = Json(json!({
-            "error": error_message,
-        }));
-
-        (status, body).into_response()
+        let problem = Problem::from(&self);
+        let status = self.status_code();
+
+        let mut response = (status, Json(problem)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+
+        if let Some(delay) = self.retry_after() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(delay.as_secs()));
+        }
+
+        if status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+
+        if status.is_server_error() {
+            tracing::error!("Request failed: {}", self);
+        }
+
+        response.extensions_mut().insert(ErrorReport {
+            category: self.category(),
+            message: self.to_string(),
+        });
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use axum::body::to_bytes;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    fn http_status(status: u16) -> Error {
+        Error::HttpStatus {
+            status,
+            body: "nope".to_string(),
+        }
+    }
+
+    fn json_error() -> Error {
+        Error::Json(serde_json::from_str::<serde_json::Value>("{").unwrap_err())
+    }
+
+    /// Every variant that doesn't need a live request, with its expected mapping
+    fn local_errors() -> Vec<(Error, ErrorCategory, StatusCode, &'static str)> {
+        use ErrorCategory::*;
+
+        vec![
+            (
+                Error::BleAdapter("off".into()),
+                Unavailable,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ble_adapter_unavailable",
+            ),
+            (
+                Error::WifiScan("no wlan0".into()),
+                Unavailable,
+                StatusCode::SERVICE_UNAVAILABLE,
+                "wifi_scan_failed",
+            ),
+            (
+                Error::InvalidSsid("\0".into()),
+                Validation,
+                StatusCode::BAD_REQUEST,
+                "invalid_ssid",
+            ),
+            (
+                http_status(400),
+                Validation,
+                StatusCode::BAD_REQUEST,
+                "upstream_rejected",
+            ),
+            (
+                http_status(422),
+                Validation,
+                StatusCode::BAD_REQUEST,
+                "upstream_rejected",
+            ),
+            (
+                http_status(401),
+                Config,
+                StatusCode::BAD_GATEWAY,
+                "upstream_rejected",
+            ),
+            (
+                http_status(403),
+                Config,
+                StatusCode::BAD_GATEWAY,
+                "upstream_rejected",
+            ),
+            (
+                http_status(404),
+                Config,
+                StatusCode::BAD_GATEWAY,
+                "upstream_rejected",
+            ),
+            (
+                http_status(407),
+                Config,
+                StatusCode::BAD_GATEWAY,
+                "upstream_rejected",
+            ),
+            (
+                http_status(408),
+                Network,
+                StatusCode::BAD_GATEWAY,
+                "upstream_rejected",
+            ),
+            (
+                http_status(413),
+                Upstream,
+                StatusCode::BAD_GATEWAY,
+                "upstream_rejected",
+            ),
+            (
+                http_status(500),
+                Upstream,
+                StatusCode::BAD_GATEWAY,
+                "upstream_failed",
+            ),
+            (
+                Error::RateLimited {
+                    status: 429,
+                    retry_after: Some(Duration::from_secs(30)),
+                },
+                RateLimited,
+                StatusCode::TOO_MANY_REQUESTS,
+                "upstream_rate_limited",
+            ),
+            (
+                Error::Serialization("missing field".into()),
+                Validation,
+                StatusCode::BAD_REQUEST,
+                "invalid_payload",
+            ),
+            (
+                Error::Validation("latitude out of range".into()),
+                Validation,
+                StatusCode::BAD_REQUEST,
+                "validation_failed",
+            ),
+            (
+                Error::Bind("address in use".into()),
+                Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "bind_failed",
+            ),
+            (
+                Error::Unauthorized("wrong PIN".into()),
+                Unauthorized,
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+            ),
+            (
+                Error::Config("bad proxy".into()),
+                Config,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "config_invalid",
+            ),
+            (
+                Error::Io(std::io::Error::other("disk full")),
+                Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "io_failed",
+            ),
+            (
+                json_error(),
+                Validation,
+                StatusCode::BAD_REQUEST,
+                "invalid_json",
+            ),
+            (
+                Error::Database(rusqlite::Error::InvalidQuery),
+                Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database_failed",
+            ),
+            (
+                Error::Other("oops".into()),
+                Internal,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+            ),
+        ]
+    }
+
+    fn assert_mapping(error: &Error, category: ErrorCategory, status: StatusCode, code: &str) {
+        assert_eq!(error.category(), category, "category of {}", error);
+        assert_eq!(error.status_code(), status, "status of {}", error);
+        assert_eq!(error.code(), code, "code of {}", error);
+        assert_eq!(error.is_retryable(), category.is_retryable());
+    }
+
+    fn assert_problem(error: &Error) {
+        let problem = serde_json::to_value(Problem::from(error)).unwrap();
+        let status = error.status_code();
+
+        assert_eq!(
+            problem["type"],
+            format!("urn:serviceberry:error:{}", error.code())
+        );
+        assert_eq!(problem["title"], status.canonical_reason().unwrap());
+        assert_eq!(problem["status"], status.as_u16());
+        assert_eq!(problem["detail"], error.to_string());
+        assert_eq!(problem["code"], error.code());
+        assert_eq!(
+            problem["category"],
+            serde_json::to_value(error.category()).unwrap()
+        );
+        assert_eq!(problem.get("details").is_some(), error.details().is_some());
+    }
+
+    #[test]
+    fn maps_each_variant() {
+        for (error, category, status, code) in local_errors() {
+            assert_mapping(&error, category, status, code);
+        }
+    }
+
+    #[test]
+    fn problem_body_describes_each_variant() {
+        for (error, ..) in local_errors() {
+            assert_problem(&error);
+        }
+    }
+
+    #[test]
+    fn problem_details_carry_upstream_context() {
+        let problem = Problem::from(&http_status(400));
+        assert_eq!(
+            problem.details,
+            Some(json!({ "upstream_status": 400, "upstream_body": "nope" }))
+        );
+
+        let problem = Problem::from(&Error::RateLimited {
+            status: 503,
+            retry_after: Some(Duration::from_secs(30)),
+        });
+        assert_eq!(
+            problem.details,
+            Some(json!({ "upstream_status": 503, "retry_after_secs": 30 }))
+        );
+
+        let problem = Problem::from(&json_error());
+        assert_eq!(problem.details, Some(json!({ "line": 1, "column": 1 })));
+    }
+
+    #[test]
+    fn only_transient_categories_are_retryable() {
+        use ErrorCategory::*;
+
+        for category in [Network, Upstream, RateLimited, Unavailable] {
+            assert!(category.is_retryable(), "{:?}", category);
+        }
+        for category in [Tls, Validation, Config, Unauthorized, Internal] {
+            assert!(!category.is_retryable(), "{:?}", category);
+        }
+    }
+
+    #[tokio::test]
+    async fn response_has_problem_headers() {
+        let response = Error::RateLimited {
+            status: 429,
+            retry_after: Some(Duration::from_secs(30)),
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+        assert_eq!(
+            response.extensions().get::<ErrorReport>().unwrap().category,
+            ErrorCategory::RateLimited
+        );
+
+        let response = Error::Unauthorized("unknown device".into()).into_response();
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["code"], "unauthorized");
+        assert_eq!(problem["status"], 401);
+    }
+
+    async fn request_error(url: &str) -> Error {
+        test_support::install_crypto_provider();
+        reqwest::Client::new()
+            .get(url)
+            .send()
+            .await
+            .unwrap_err()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn maps_connection_failures_to_network() {
+        // bound then dropped, so nothing is listening
+        let address = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let error = request_error(&format!("http://{}/", address)).await;
+        assert_mapping(
+            &error,
+            ErrorCategory::Network,
+            StatusCode::BAD_GATEWAY,
+            "upstream_unreachable",
+        );
+        assert_problem(&error);
+    }
+
+    #[tokio::test]
+    async fn maps_invalid_urls_to_internal() {
+        let error = request_error("not a url").await;
+        assert_mapping(
+            &error,
+            ErrorCategory::Internal,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "upstream_unreachable",
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_untrusted_certificates_to_tls() {
+        let identity = test_support::self_signed();
+        let acceptor =
+            tokio_rustls::TlsAcceptor::from(Arc::new(test_support::server_config(&identity)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = acceptor.accept(stream).await;
+            }
+        });
+
+        let error = request_error(&format!("https://localhost:{}/", address.port())).await;
+        assert_mapping(
+            &error,
+            ErrorCategory::Tls,
+            StatusCode::BAD_GATEWAY,
+            "upstream_tls_failed",
+        );
+        assert_problem(&error);
+    }
+
+    #[test]
+    fn tls_words_in_other_errors_are_not_tls() {
+        let error = Error::Transport(reqwest_middleware::Error::middleware(
+            std::io::Error::other("failed to look up ssl.example.com: certificate store busy"),
+        ));
+        assert_eq!(error.category(), ErrorCategory::Network);
     }
 }
@@ -0,0 +1,441 @@
+//! Export the observation history as WiGLE CSV, GeoJSON or KML
+//!
+//! Exporters write straight to any `io::Write`, one row or feature at a time, so
+//! the same code serves the CLI (writing a file) and streaming HTTP downloads.
+
+use serde_json::json;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::clock::{format_time, parse_time};
+use crate::error::{Error, Result};
+use crate::geosubmit::RadioType;
+use crate::geosubmit::emitters::EmitterKind;
+use crate::history::{BoundingBox, History, HistoryFilter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    WigleCsv,
+    GeoJson,
+    Kml,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::WigleCsv => "text/csv; charset=utf-8",
+            ExportFormat::GeoJson => "application/geo+json",
+            ExportFormat::Kml => "application/vnd.google-earth.kml+xml",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::WigleCsv => "csv",
+            ExportFormat::GeoJson => "geojson",
+            ExportFormat::Kml => "kml",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "wigle" | "csv" => Ok(ExportFormat::WigleCsv),
+            "geojson" | "json" => Ok(ExportFormat::GeoJson),
+            "kml" => Ok(ExportFormat::Kml),
+            _ => Err(Error::Validation(format!(
+                "unknown export format '{}', expected wigle, geojson or kml",
+                s
+            ))),
+        }
+    }
+}
+
+/// Write the part of the history matching `filter` to `out`
+pub fn export(
+    history: &History,
+    format: ExportFormat,
+    filter: &HistoryFilter,
+    out: &mut dyn Write,
+) -> Result<()> {
+    match format {
+        ExportFormat::WigleCsv => export_wigle_csv(history, filter, out)?,
+        ExportFormat::GeoJson => export_geojson(history, filter, out)?,
+        ExportFormat::Kml => export_kml(history, filter, out)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// WiGLE's CSV format: a pre-header line identifying the app, then one row per observation
+fn export_wigle_csv(history: &History, filter: &HistoryFilter, out: &mut dyn Write) -> Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    writeln!(
+        out,
+        "WigleWifi-1.4,appRelease={},model=serviceberry,release={},device=serviceberry,display=,board=,brand=serviceberry",
+        version, version
+    )?;
+    writeln!(
+        out,
+        "MAC,SSID,AuthMode,FirstSeen,Channel,RSSI,CurrentLatitude,CurrentLongitude,AltitudeMeters,AccuracyMeters,Type"
+    )?;
+
+    history.for_each_submission(filter, |submission| {
+        let seen = format_time(submission.timestamp, ' ');
+        let position = &submission.position;
+        let mut row =
+            |mac: &str, name: &str, auth: &str, channel: String, rssi: String, kind: &str| {
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{},{},{},{}",
+                    csv_field(mac),
+                    csv_field(name),
+                    csv_field(auth),
+                    seen,
+                    channel,
+                    rssi,
+                    position.latitude,
+                    position.longitude,
+                    position.altitude,
+                    position.accuracy,
+                    kind
+                )
+            };
+
+        for ap in &submission.wifi {
+            row(
+                &ap.bssid.to_string(),
+                ap.ssid.as_deref().unwrap_or_default(),
+                "",
+                ap.channel.map(|c| c.to_string()).unwrap_or_default(),
+                ap.rssi.to_string(),
+                "WIFI",
+            )?;
+        }
+
+        for beacon in &submission.bluetooth {
+            row(
+                &beacon.mac_address.to_string(),
+                beacon.name.as_deref().unwrap_or_default(),
+                "Misc [LE]",
+                String::new(),
+                beacon.rssi.map(|r| r.to_string()).unwrap_or_default(),
+                "BLE",
+            )?;
+        }
+
+        // WiGLE identifies cells as <MCC><MNC>_<LAC>_<CID>
+        for tower in &submission.cells {
+            let Some(radio) = &tower.radioType else {
+                continue;
+            };
+            let kind = match radio {
+                RadioType::Gsm => "GSM",
+                RadioType::Wcdma => "WCDMA",
+                RadioType::Lte => "LTE",
+            };
+            let operator = format!("{}{:02}", tower.mobileCountryCode, tower.mobileNetworkCode);
+            row(
+                &format!("{}_{}_{}", operator, tower.locationAreaCode, tower.cellId),
+                "",
+                &format!("{};{}", kind, operator),
+                String::new(),
+                String::new(),
+                kind,
+            )?;
+        }
+
+        Ok(())
+    })
+}
+
+/// A FeatureCollection with a point for each position and each learned emitter
+fn export_geojson(history: &History, filter: &HistoryFilter, out: &mut dyn Write) -> Result<()> {
+    write!(out, r#"{{"type":"FeatureCollection","features":["#)?;
+    let mut first = true;
+    let mut feature = |out: &mut dyn Write, value: serde_json::Value| -> Result<()> {
+        if !first {
+            out.write_all(b",")?;
+        }
+        first = false;
+        out.write_all(b"\n")?;
+        serde_json::to_writer(&mut *out, &value)?;
+        Ok(())
+    };
+
+    history.for_each_submission(filter, |submission| {
+        feature(
+            out,
+            json!({
+                "type": "Feature",
+                "geometry": point(submission.position.latitude, submission.position.longitude),
+                "properties": {
+                    "kind": "position",
+                    "id": submission.id,
+                    "timestamp": submission.timestamp,
+                    "accuracy": submission.position.accuracy,
+                    "altitude": submission.position.altitude,
+                    "source": submission.position.source,
+                    "wifi_access_points": submission.wifi.len(),
+                    "bluetooth_beacons": submission.bluetooth.len(),
+                    "cell_towers": submission.cells.len(),
+                },
+            }),
+        )
+    })?;
+
+    history.for_each_emitter(filter, |(kind, address, aggregate)| {
+        let (latitude, longitude) = aggregate.location();
+        feature(
+            out,
+            json!({
+                "type": "Feature",
+                "geometry": point(latitude, longitude),
+                "properties": {
+                    "kind": kind,
+                    "address": address.to_string(),
+                    "radius": aggregate.radius(),
+                    "observations": aggregate.observations,
+                    "first_seen": aggregate.first_seen,
+                    "last_seen": aggregate.last_seen,
+                },
+            }),
+        )
+    })?;
+
+    writeln!(out, "\n]}}")?;
+    Ok(())
+}
+
+fn point(latitude: f64, longitude: f64) -> serde_json::Value {
+    json!({ "type": "Point", "coordinates": [longitude, latitude] })
+}
+
+/// A KML document with one folder of positions and one of learned emitters
+fn export_kml(history: &History, filter: &HistoryFilter, out: &mut dyn Write) -> Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(out, "<Document>\n<name>Serviceberry</name>")?;
+
+    writeln!(out, "<Folder>\n<name>Positions</name>")?;
+    history.for_each_submission(filter, |submission| {
+        let position = &submission.position;
+        writeln!(
+            out,
+            "<Placemark><name>{}</name><TimeStamp><when>{}</when></TimeStamp><description>{} WiFi, {} Bluetooth, {} cell; accuracy {} m</description><Point><coordinates>{},{},{}</coordinates></Point></Placemark>",
+            format_time(submission.timestamp, ' '),
+            format_time(submission.timestamp, 'T') + "Z",
+            submission.wifi.len(),
+            submission.bluetooth.len(),
+            submission.cells.len(),
+            position.accuracy,
+            position.longitude,
+            position.latitude,
+            position.altitude
+        )?;
+        Ok(())
+    })?;
+    writeln!(out, "</Folder>")?;
+
+    writeln!(out, "<Folder>\n<name>Access points and beacons</name>")?;
+    history.for_each_emitter(filter, |(kind, address, aggregate)| {
+        let (latitude, longitude) = aggregate.location();
+        writeln!(
+            out,
+            "<Placemark><name>{}</name><description>{} seen {} times between {} and {}, within {:.0} m</description><Point><coordinates>{},{}</coordinates></Point></Placemark>",
+            xml_escape(&address.to_string()),
+            match kind {
+                EmitterKind::Wifi => "WiFi access point",
+                EmitterKind::Bluetooth => "Bluetooth beacon",
+            },
+            aggregate.observations,
+            format_time(aggregate.first_seen, ' '),
+            format_time(aggregate.last_seen, ' '),
+            aggregate.radius(),
+            longitude,
+            latitude
+        )?;
+        Ok(())
+    })?;
+    writeln!(out, "</Folder>")?;
+
+    writeln!(out, "</Document>\n</kml>")?;
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Build a history filter from optional `since`/`until` times and a `west,south,east,north` box
+pub fn history_filter(
+    since: Option<&str>,
+    until: Option<&str>,
+    bbox: Option<&str>,
+) -> Result<HistoryFilter> {
+    Ok(HistoryFilter {
+        since: since.map(parse_time).transpose()?,
+        until: until.map(parse_time).transpose()?,
+        bbox: bbox.map(str::parse::<BoundingBox>).transpose()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geosubmit::{CellTower, EmitterIndex};
+    use crate::scanner::BleDevice;
+    use crate::test_support::{self, payload, position};
+    use btleplug::api::BDAddr;
+
+    const JUNE_1: &str = "2025-06-01T12:00:00Z";
+    const JUNE_2: &str = "2025-06-02T12:00:00Z";
+    const JUNE_3: &str = "2025-06-03T12:00:00Z";
+
+    /// Submissions in Berlin on June 1st, and either side of the antimeridian on the 2nd and 3rd
+    fn seeded_history() -> History {
+        let history =
+            History::open(&test_support::temp_dir("export").join("history.sqlite3")).unwrap();
+        let index = EmitterIndex::new();
+
+        let mut berlin = payload(
+            parse_time(JUNE_1).unwrap() as u128,
+            position(52.0, 13.0),
+            &[1],
+        );
+        berlin.wifiAccessPoints[0].ssid = Some("Cafe, \"Free\"".to_string());
+        berlin.bluetoothBeacons.push(BleDevice {
+            mac_address: BDAddr::from([0x0a, 0, 0, 0, 0, 1]),
+            rssi: Some(-70),
+            name: None,
+        });
+        berlin.CellTowers = Some(vec![CellTower {
+            radioType: Some(RadioType::Lte),
+            mobileCountryCode: 262,
+            mobileNetworkCode: 1,
+            locationAreaCode: 1234,
+            cellId: 5678,
+            age: None,
+            asu: None,
+        }]);
+        let east = payload(
+            parse_time(JUNE_2).unwrap() as u128,
+            position(0.0, 179.9),
+            &[2],
+        );
+        let west = payload(
+            parse_time(JUNE_3).unwrap() as u128,
+            position(0.0, -179.9),
+            &[3],
+        );
+
+        for payload in [berlin, east, west] {
+            history.record(&payload, &index.learn(&payload)).unwrap();
+        }
+        history
+    }
+
+    fn exported(history: &History, format: ExportFormat, filter: &HistoryFilter) -> String {
+        let mut out = Vec::new();
+        export(history, format, filter, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn geojson(history: &History, filter: &HistoryFilter) -> Vec<serde_json::Value> {
+        let document: serde_json::Value =
+            serde_json::from_str(&exported(history, ExportFormat::GeoJson, filter)).unwrap();
+        document["features"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn writes_wigle_csv_rows_for_every_radio() {
+        let history = seeded_history();
+        let filter = history_filter(None, Some(JUNE_2), None).unwrap();
+
+        let csv = exported(&history, ExportFormat::WigleCsv, &filter);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("WigleWifi-1.4,"));
+        assert_eq!(
+            &lines[2..],
+            [
+                "02:00:00:00:00:01,\"Cafe, \"\"Free\"\"\",,2025-06-01 12:00:00,6,-60,52,13,0,10,WIFI",
+                "0A:00:00:00:00:01,,Misc [LE],2025-06-01 12:00:00,,-70,52,13,0,10,BLE",
+                "26201_1234_5678,,LTE;26201,2025-06-01 12:00:00,,,52,13,0,10,LTE",
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_by_time_range() {
+        let history = seeded_history();
+
+        // since is inclusive, until exclusive
+        let filter = history_filter(Some(JUNE_2), Some(JUNE_3), None).unwrap();
+        let features = geojson(&history, &filter);
+        let timestamps = features
+            .iter()
+            .filter(|feature| feature["properties"]["kind"] == "position")
+            .map(|feature| feature["properties"]["timestamp"].as_i64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, [parse_time(JUNE_2).unwrap()]);
+        let addresses = features
+            .iter()
+            .filter_map(|feature| feature["properties"]["address"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, ["02:00:00:00:00:02"]);
+
+        let everything = geojson(&history, &HistoryFilter::default());
+        // three positions, three access points and a beacon
+        assert_eq!(everything.len(), 7);
+    }
+
+    #[test]
+    fn filters_by_bounding_box_across_the_antimeridian() {
+        let history = seeded_history();
+
+        let filter = history_filter(None, None, Some("179,-1,-179,1")).unwrap();
+        let features = geojson(&history, &filter);
+        let longitudes = features
+            .iter()
+            .map(|feature| feature["geometry"]["coordinates"][0].as_f64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(longitudes.len(), 4);
+        assert!(longitudes.iter().all(|longitude| longitude.abs() > 179.0));
+
+        let filter = history_filter(None, None, Some("12,51,14,53")).unwrap();
+        let kml = exported(&history, ExportFormat::Kml, &filter);
+        assert!(kml.starts_with("<?xml"));
+        assert!(kml.trim_end().ends_with("</kml>"));
+        assert_eq!(kml.matches("<Placemark>").count(), 3);
+        assert!(kml.contains("<when>2025-06-01T12:00:00Z</when>"));
+        assert!(kml.contains("<coordinates>13,52,0</coordinates>"));
+        assert!(kml.contains("Bluetooth beacon seen 1 times"));
+    }
+
+    #[test]
+    fn rejects_invalid_filters_and_formats() {
+        assert!(history_filter(Some("yesterday"), None, None).is_err());
+        assert!(history_filter(None, None, Some("13,53,14,52")).is_err());
+        assert!(history_filter(None, None, Some("13,52,14")).is_err());
+        assert!("shapefile".parse::<ExportFormat>().is_err());
+        assert_eq!(
+            "CSV".parse::<ExportFormat>().unwrap(),
+            ExportFormat::WigleCsv
+        );
+    }
+}
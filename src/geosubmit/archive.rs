@@ -0,0 +1,209 @@
+//! Rotating JSON Lines archive used as the dry-run submission target
+//!
+//! Each line is a complete geosubmit document, exactly as it would have been
+//! uploaded. The active file is `submissions.jsonl`; once it grows past the size
+//! limit, or gets too old, it's renamed to `submissions-<unix ms>.jsonl` and a
+//! new one is started.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::clock::now_millis;
+use crate::error::Result;
+
+use super::payload::GeoSubmission;
+
+const ACTIVE_FILE: &str = "submissions.jsonl";
+const ROTATED_PREFIX: &str = "submissions-";
+
+pub struct JsonlArchive {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// In milliseconds; 0 for no limit
+    max_age: i64,
+    max_files: usize,
+    /// When the active file was started, in milliseconds since the Unix epoch
+    started: Mutex<Option<i64>>,
+}
+
+impl JsonlArchive {
+    pub fn open(
+        dir: PathBuf,
+        max_bytes: u64,
+        max_age_hours: u64,
+        max_files: usize,
+    ) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        // without a recorded creation time, an existing file's age counts from now
+        let started = fs::metadata(dir.join(ACTIVE_FILE)).ok().map(|metadata| {
+            metadata
+                .created()
+                .ok()
+                .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+                .map_or_else(now_millis, |since_epoch| since_epoch.as_millis() as i64)
+        });
+
+        Ok(JsonlArchive {
+            dir,
+            max_bytes,
+            max_age: (max_age_hours as i64).saturating_mul(60 * 60 * 1000),
+            max_files,
+            started: Mutex::new(started),
+        })
+    }
+
+    /// Path of the file currently being appended to
+    pub fn active_path(&self) -> PathBuf {
+        self.dir.join(ACTIVE_FILE)
+    }
+
+    /// Append one document as a single line
+    pub fn append(&self, document: &GeoSubmission) -> Result<()> {
+        let mut line = serde_json::to_vec(document)?;
+        line.push(b'\n');
+
+        let mut started = self.started.lock().unwrap();
+        let now = now_millis();
+
+        let active_path = self.active_path();
+        let active_len = fs::metadata(&active_path).map(|m| m.len()).unwrap_or(0);
+        let too_large = active_len + line.len() as u64 > self.max_bytes;
+        let too_old = self.max_age > 0 && started.is_some_and(|time| now - time >= self.max_age);
+        if active_len > 0 && (too_large || too_old) {
+            self.rotate()?;
+            *started = None;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&active_path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+        started.get_or_insert(now);
+
+        Ok(())
+    }
+
+    /// Move the active file aside and drop the oldest rotated files beyond the limit
+    fn rotate(&self) -> Result<()> {
+        // rotations within the same millisecond take the next free name
+        let mut time = now_millis();
+        let mut rotated_path;
+        loop {
+            rotated_path = self
+                .dir
+                .join(format!("{}{:013}.jsonl", ROTATED_PREFIX, time));
+            if !rotated_path.exists() {
+                break;
+            }
+            time += 1;
+        }
+        fs::rename(self.active_path(), &rotated_path)?;
+
+        tracing::info!("[Archive] Rotated to {}", rotated_path.display());
+
+        let mut rotated = self.rotated_files()?;
+
+        // the timestamp suffix makes name order age order
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(self.max_files);
+        for path in rotated.into_iter().take(excess) {
+            fs::remove_file(&path)?;
+        }
+
+        Ok(())
+    }
+
+    fn rotated_files(&self) -> Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(ROTATED_PREFIX))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, payload, position};
+
+    fn document(timestamp: u128) -> GeoSubmission {
+        GeoSubmission {
+            items: vec![payload(timestamp, position(52.0, 13.0), &[1])],
+        }
+    }
+
+    fn line_len() -> u64 {
+        serde_json::to_vec(&document(1)).unwrap().len() as u64 + 1
+    }
+
+    /// Timestamps of the documents in each rotated file, oldest file first, then the active one
+    fn contents(archive: &JsonlArchive) -> Vec<Vec<u64>> {
+        let mut files = archive.rotated_files().unwrap();
+        files.sort();
+        files.push(archive.active_path());
+        files
+            .iter()
+            .map(|path| {
+                fs::read_to_string(path)
+                    .unwrap()
+                    .lines()
+                    .map(|line| {
+                        let document: serde_json::Value = serde_json::from_str(line).unwrap();
+                        document["items"][0]["timestamp"].as_u64().unwrap()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest_files() {
+        let dir = test_support::temp_dir("archive");
+        // room for two documents per file
+        let archive = JsonlArchive::open(dir, line_len() * 2, 0, 2).unwrap();
+
+        for timestamp in 1..=7 {
+            archive.append(&document(timestamp)).unwrap();
+        }
+        assert_eq!(contents(&archive), [vec![3, 4], vec![5, 6], vec![7]]);
+    }
+
+    #[test]
+    fn writes_a_document_larger_than_the_limit_on_its_own() {
+        let archive = JsonlArchive::open(test_support::temp_dir("archive"), 1, 0, 10).unwrap();
+
+        archive.append(&document(1)).unwrap();
+        archive.append(&document(2)).unwrap();
+        assert_eq!(contents(&archive), [vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn rotates_by_age() {
+        let dir = test_support::temp_dir("archive");
+        let archive = JsonlArchive::open(dir.clone(), u64::MAX, 1, 10).unwrap();
+
+        archive.append(&document(1)).unwrap();
+        archive.append(&document(2)).unwrap();
+        assert_eq!(contents(&archive), [vec![1, 2]]);
+
+        *archive.started.lock().unwrap() = Some(now_millis() - 60 * 60 * 1000);
+        archive.append(&document(3)).unwrap();
+        archive.append(&document(4)).unwrap();
+        assert_eq!(contents(&archive), [vec![1, 2], vec![3, 4]]);
+
+        // an existing file keeps its age across restarts
+        let reopened = JsonlArchive::open(dir, u64::MAX, 1, 10).unwrap();
+        assert!(reopened.started.lock().unwrap().is_some());
+    }
+}
@@ -1,47 +1,53 @@
 //! HTTP client for submitting geosubmit payloads
 
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
-use reqwest_tracing::TracingMiddleware;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::time::Instant;
+use flate2::{Compression, write::GzEncoder};
+use reqwest::StatusCode;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::config::{APP_USER_AGENT, GEOSUBMIT_ENDPOINT};
-use crate::scanner::{bluetooth, wifi};
 use crate::error::{Error, Result};
+use crate::scanner::{bluetooth, wifi};
+
+use super::http::{HttpClient, is_rate_limit_status};
+use super::payload::{GeoSubmission, items};
+use super::provider::Provider;
 
-use super::payload::items;
+/// Parse a `Retry-After` header value, in either delay-seconds or HTTP-date form
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
 
 /// Assemble geolocation payload from current scans
 pub async fn assemble_geo_payload(
     position: serde_json::Value,
     cell_towers: Option<serde_json::Value>,
 ) -> Result<items> {
-    let position: crate::geosubmit::payload::Position = serde_json::from_value(position)
-        .map_err(|e| Error::Serialization(e.to_string()))?;
+    let position: crate::geosubmit::payload::Position =
+        serde_json::from_value(position).map_err(|e| Error::Serialization(e.to_string()))?;
 
     let cell_towers: Option<Vec<crate::geosubmit::payload::CellTower>> = match cell_towers {
         Some(ct_value) => Some(
-            serde_json::from_value(ct_value)
-                .map_err(|e| Error::Serialization(e.to_string()))?,
+            serde_json::from_value(ct_value).map_err(|e| Error::Serialization(e.to_string()))?,
         ),
         None => None,
     };
 
-    let wifi_start = Instant::now();
-    let ble_start = Instant::now();
-
     let (wifi, ble) = tokio::join!(
         // run simultaneously
         wifi::fetch_wifi_stats(),
         bluetooth::fetch_ble_devices()
     );
 
-    let wifi_duration = wifi_start.elapsed();
-    let ble_duration = ble_start.elapsed();
-    tracing::debug!("WiFi scan duration: {:?}", wifi_duration);
-    tracing::debug!("BLE scan duration: {:?}", ble_duration);
-
     let unix_epoch = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
@@ -58,34 +64,67 @@ pub async fn assemble_geo_payload(
     Ok(payload)
 }
 
-/// Submit geolocation payload to the geosubmit API
-pub async fn submit_geo_payload(payload: items) -> Result<()> {
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(5);
-    let http_client = reqwest::Client::builder()
-        .user_agent(APP_USER_AGENT)
-        .timeout(std::time::Duration::from_secs(10))
-        .build()
-        .map_err(|e| Error::Transport(e.to_string()))?;
-
-    let client: ClientWithMiddleware = ClientBuilder::new(http_client.clone())
-        .with(TracingMiddleware::default())
-        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-        .build();
-
-    let req = http_client
-        .post(GEOSUBMIT_ENDPOINT)
-        .json(&payload)
-        .build()
-        .map_err(|e| Error::Transport(e.to_string()))?;
-
-    let res = client
-        .execute(req)
-        .await
-        .map_err(|e| Error::Transport(e.to_string()))?;
+/// Submit a batch of geolocation payloads to a provider's geosubmit API
+pub async fn submit_geo_payload(
+    http: &HttpClient,
+    provider: &Provider,
+    payload: Vec<items>,
+) -> Result<()> {
+    let document = GeoSubmission { items: payload };
+    document.validate()?;
+
+    if let Some(archive) = &provider.archive {
+        archive.append(&document)?;
+        tracing::info!(
+            "[{}] Archived {} item(s) to {}",
+            provider.name,
+            document.items.len(),
+            provider.endpoint
+        );
+        return Ok(());
+    }
+
+    let body = serde_json::to_vec(&document)?;
+
+    let mut res = if provider.gzip_enabled() {
+        let res = post_body(http, provider, &body, true).await?;
+
+        // a provider that can't handle gzip answers 415; retry uncompressed and
+        // remember if that works. The retry is a request like any other, so it
+        // waits for a rate-limit token too.
+        if res.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE {
+            tracing::warn!(
+                "[{}] Compressed upload rejected with {}; retrying uncompressed",
+                provider.name,
+                res.status()
+            );
+            provider.wait_ready().await;
+            let retry = post_body(http, provider, &body, false).await?;
+            if retry.status().is_success() {
+                provider.disable_gzip();
+            }
+            retry
+        } else {
+            res
+        }
+    } else {
+        post_body(http, provider, &body, false).await?
+    };
 
     let status = res.status();
+    let retry_after = res
+        .headers_mut()
+        .remove(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok().and_then(parse_retry_after));
     let body = res.text().await.unwrap_or_default();
 
+    if is_rate_limit_status(status) {
+        return Err(Error::RateLimited {
+            status: status.as_u16(),
+            retry_after,
+        });
+    }
+
     if !status.is_success() {
         return Err(Error::HttpStatus {
             status: status.as_u16(),
@@ -98,3 +137,115 @@ pub async fn submit_geo_payload(payload: items) -> Result<()> {
 
     Ok(())
 }
+
+async fn post_body(
+    http: &HttpClient,
+    provider: &Provider,
+    body: &[u8],
+    gzip: bool,
+) -> Result<reqwest::Response> {
+    let mut builder = http
+        .client()
+        .post(&provider.endpoint)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+
+    builder = if gzip {
+        builder
+            .header(reqwest::header::CONTENT_ENCODING, "gzip")
+            .body(gzip_bytes(body)?)
+    } else {
+        builder.body(body.to_vec())
+    };
+
+    Ok(builder.send().await?)
+}
+
+fn gzip_bytes(body: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HttpSettings, ProviderSettings};
+    use crate::test_support::{self, payload, position};
+    use axum::Router;
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use tokio::sync::mpsc;
+
+    /// A provider on loopback that refuses gzip, reporting each request's encoding
+    async fn gzip_refusing_provider() -> (Provider, mpsc::UnboundedReceiver<Option<String>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let router = Router::new()
+            .route(
+                "/v2/geosubmit",
+                post(
+                    |State(sender): State<mpsc::UnboundedSender<Option<String>>>,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        let encoding = headers
+                            .get(reqwest::header::CONTENT_ENCODING)
+                            .map(|value| value.to_str().unwrap().to_string());
+                        sender.send(encoding.clone()).unwrap();
+                        if encoding.is_some() {
+                            return StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                        }
+                        serde_json::from_slice::<GeoSubmission>(&body).unwrap();
+                        StatusCode::OK
+                    },
+                ),
+            )
+            .with_state(sender);
+        let address = test_support::serve(router).await;
+        let endpoint = format!("http://{}/v2/geosubmit", address);
+        let provider = Provider::new("test", &endpoint, true, &ProviderSettings::default());
+        (provider, receiver)
+    }
+
+    #[tokio::test]
+    async fn retries_uncompressed_after_415_and_stays_uncompressed() {
+        let (provider, mut requests) = gzip_refusing_provider().await;
+        let http = HttpClient::from_settings(&HttpSettings::default()).unwrap();
+
+        let items = || vec![payload(1, position(52.0, 13.0), &[1, 2])];
+        submit_geo_payload(&http, &provider, items()).await.unwrap();
+        assert_eq!(requests.recv().await.unwrap().as_deref(), Some("gzip"));
+        assert_eq!(requests.recv().await.unwrap(), None);
+        assert!(!provider.gzip_enabled());
+
+        submit_geo_payload(&http, &provider, items()).await.unwrap();
+        assert_eq!(requests.recv().await.unwrap(), None);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn parses_retry_after_dates() {
+        let later = SystemTime::now() + Duration::from_secs(300);
+        let delay = parse_retry_after(&httpdate::fmt_http_date(later)).unwrap();
+        // HTTP dates only have whole seconds
+        assert!(delay > Duration::from_secs(298) && delay <= Duration::from_secs(300));
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_retry_after() {
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-5"), None);
+        assert_eq!(parse_retry_after(""), None);
+    }
+}
@@ -0,0 +1,371 @@
+//! Locations of access points and beacons learned from our own submissions
+//!
+//! Every accepted submission refines a signal-weighted estimate of where each
+//! emitter is. The index then answers Ichnaea-style geolocate queries with a
+//! weighted centroid of the known emitters a device can hear.
+
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::config::{
+    EMITTER_MAX_RADIUS_METERS, EMITTER_MIN_RADIUS_METERS, LEARN_MAX_POSITION_ACCURACY_METERS,
+    LOCAL_GEOLOCATE_MIN_EMITTERS,
+};
+
+use super::geolocate::{GeolocateRequest, GeolocateResponse, LatLng};
+use super::payload::{haversine_meters, items};
+
+/// Signal strength assumed when an observation doesn't report one, in dBm
+const DEFAULT_SIGNAL_DBM: f64 = -80.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EmitterKind {
+    Wifi,
+    Bluetooth,
+}
+
+/// Running aggregate of every sighting of one emitter
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmitterAggregate {
+    pub weight: f64,
+    pub weighted_latitude: f64,
+    /// Longitudes are unwrapped around the first sighting, so averages work across the antimeridian
+    pub weighted_longitude: f64,
+    pub reference_longitude: f64,
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+    pub observations: u64,
+    /// Milliseconds since the Unix epoch
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+impl EmitterAggregate {
+    fn new(latitude: f64, longitude: f64, timestamp: i64) -> Self {
+        EmitterAggregate {
+            weight: 0.0,
+            weighted_latitude: 0.0,
+            weighted_longitude: 0.0,
+            reference_longitude: longitude,
+            min_latitude: latitude,
+            max_latitude: latitude,
+            min_longitude: longitude,
+            max_longitude: longitude,
+            observations: 0,
+            first_seen: timestamp,
+            last_seen: timestamp,
+        }
+    }
+
+    fn add(&mut self, latitude: f64, longitude: f64, weight: f64, timestamp: i64) {
+        let longitude = unwrap_longitude(longitude, self.reference_longitude);
+
+        self.weight += weight;
+        self.weighted_latitude += latitude * weight;
+        self.weighted_longitude += longitude * weight;
+        self.min_latitude = self.min_latitude.min(latitude);
+        self.max_latitude = self.max_latitude.max(latitude);
+        self.min_longitude = self.min_longitude.min(longitude);
+        self.max_longitude = self.max_longitude.max(longitude);
+        self.observations += 1;
+        self.first_seen = self.first_seen.min(timestamp);
+        self.last_seen = self.last_seen.max(timestamp);
+    }
+
+    /// Estimated `(latitude, longitude)` of the emitter
+    pub fn location(&self) -> (f64, f64) {
+        let longitude = self.weighted_longitude / self.weight;
+        (
+            self.weighted_latitude / self.weight,
+            (longitude + 180.0).rem_euclid(360.0) - 180.0,
+        )
+    }
+
+    /// Radius of the area the emitter has been heard from, in meters
+    pub fn radius(&self) -> f64 {
+        let diagonal = haversine_meters(
+            self.min_latitude,
+            self.min_longitude,
+            self.max_latitude,
+            self.max_longitude,
+        );
+        (diagonal / 2.0).max(EMITTER_MIN_RADIUS_METERS)
+    }
+}
+
+/// Longitude shifted by whole turns to within 180° of `reference`
+fn unwrap_longitude(longitude: f64, reference: f64) -> f64 {
+    reference + (longitude - reference + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// Relative weight of an observation; a 20 dB stronger signal counts ten times as much
+fn signal_weight(dbm: Option<f64>) -> f64 {
+    10f64.powf(dbm.unwrap_or(DEFAULT_SIGNAL_DBM) / 20.0)
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EmitterStats {
+    pub wifi: usize,
+    pub bluetooth: usize,
+}
+
+/// A learned emitter, as stored in the history database
+pub type LearnedEmitter = (EmitterKind, BDAddr, EmitterAggregate);
+
+#[derive(Default)]
+pub struct EmitterIndex {
+    emitters: Mutex<HashMap<(EmitterKind, BDAddr), EmitterAggregate>>,
+}
+
+impl EmitterIndex {
+    pub fn new() -> Self {
+        EmitterIndex::default()
+    }
+
+    /// Start from emitters learned in earlier runs
+    pub fn from_learned(learned: Vec<LearnedEmitter>) -> Self {
+        EmitterIndex {
+            emitters: Mutex::new(
+                learned
+                    .into_iter()
+                    .map(|(kind, address, aggregate)| ((kind, address), aggregate))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Refine the emitter locations from an accepted submission, returning the
+    /// emitters that changed
+    pub fn learn(&self, payload: &items) -> Vec<LearnedEmitter> {
+        let position = &payload.position;
+        if position.accuracy > LEARN_MAX_POSITION_ACCURACY_METERS {
+            return Vec::new();
+        }
+        let timestamp = payload.timestamp as i64;
+
+        let sightings = payload
+            .wifiAccessPoints
+            .iter()
+            .map(|ap| (EmitterKind::Wifi, ap.bssid, Some(ap.rssi as f64)))
+            .chain(payload.bluetoothBeacons.iter().map(|beacon| {
+                (
+                    EmitterKind::Bluetooth,
+                    beacon.mac_address,
+                    beacon.rssi.map(f64::from),
+                )
+            }));
+
+        let mut emitters = self.emitters.lock().unwrap();
+        let mut updated = HashMap::new();
+        for (kind, address, dbm) in sightings {
+            let emitter = emitters.entry((kind, address)).or_insert_with(|| {
+                EmitterAggregate::new(position.latitude, position.longitude, timestamp)
+            });
+            emitter.add(
+                position.latitude,
+                position.longitude,
+                signal_weight(dbm),
+                timestamp,
+            );
+            updated.insert((kind, address), emitter.clone());
+        }
+
+        updated
+            .into_iter()
+            .map(|((kind, address), aggregate)| (kind, address, aggregate))
+            .collect()
+    }
+
+    /// Drop emitters not seen since `cutoff`, in milliseconds since the Unix epoch
+    pub fn forget_last_seen_before(&self, cutoff: i64) {
+        self.emitters
+            .lock()
+            .unwrap()
+            .retain(|_, emitter| emitter.last_seen >= cutoff);
+    }
+
+    /// Estimate a position from the known emitters in a geolocate request
+    pub fn locate(&self, request: &GeolocateRequest) -> Option<GeolocateResponse> {
+        let heard = request
+            .wifiAccessPoints
+            .iter()
+            .map(|ap| {
+                (
+                    EmitterKind::Wifi,
+                    ap.macAddress,
+                    ap.signalStrength.map(f64::from),
+                )
+            })
+            .chain(request.bluetoothBeacons.iter().map(|beacon| {
+                (
+                    EmitterKind::Bluetooth,
+                    beacon.macAddress,
+                    beacon.signalStrength.map(f64::from),
+                )
+            }));
+
+        let emitters = self.emitters.lock().unwrap();
+        let known = heard
+            .filter_map(|(kind, address, dbm)| {
+                let emitter = emitters.get(&(kind, address))?;
+                let radius = emitter.radius();
+                // Emitters heard over a wide area have probably moved
+                (radius <= EMITTER_MAX_RADIUS_METERS).then(|| {
+                    let (latitude, longitude) = emitter.location();
+                    (latitude, longitude, radius, signal_weight(dbm))
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(emitters);
+
+        if known.len() < LOCAL_GEOLOCATE_MIN_EMITTERS {
+            return None;
+        }
+
+        let reference = known[0].1;
+        let total_weight = known.iter().map(|e| e.3).sum::<f64>();
+        let latitude = known.iter().map(|e| e.0 * e.3).sum::<f64>() / total_weight;
+        let longitude = known
+            .iter()
+            .map(|e| unwrap_longitude(e.1, reference) * e.3)
+            .sum::<f64>()
+            / total_weight;
+        let longitude = (longitude + 180.0).rem_euclid(360.0) - 180.0;
+
+        // Far enough to cover every emitter used, and the area it's heard from
+        let accuracy = known
+            .iter()
+            .map(|e| haversine_meters(latitude, longitude, e.0, e.1) + e.2)
+            .fold(0.0, f64::max);
+
+        Some(GeolocateResponse {
+            location: LatLng {
+                lat: latitude,
+                lng: longitude,
+            },
+            accuracy,
+        })
+    }
+
+    pub fn stats(&self) -> EmitterStats {
+        let emitters = self.emitters.lock().unwrap();
+        let wifi = emitters
+            .keys()
+            .filter(|(kind, _)| *kind == EmitterKind::Wifi)
+            .count();
+        EmitterStats {
+            wifi,
+            bluetooth: emitters.len() - wifi,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geosubmit::Position;
+    use crate::test_support::{access_point, payload, position};
+
+    /// Learn the access points in `access_points` at a position, each heard at `dbm`
+    fn learn_at(index: &EmitterIndex, position: Position, access_points: &[u8], dbm: i32) {
+        let mut payload = payload(1, position, access_points);
+        for ap in &mut payload.wifiAccessPoints {
+            ap.rssi = dbm;
+        }
+        index.learn(&payload);
+    }
+
+    fn request(access_points: &[u8]) -> GeolocateRequest {
+        let access_points = access_points
+            .iter()
+            .copied()
+            .map(access_point)
+            .collect::<Vec<_>>();
+        GeolocateRequest::from_observations(&access_points, &[], Vec::new())
+    }
+
+    fn location(index: &EmitterIndex, address: u8) -> (f64, f64) {
+        index.emitters.lock().unwrap()[&(EmitterKind::Wifi, access_point(address).bssid)].location()
+    }
+
+    #[test]
+    fn learns_a_signal_weighted_centroid() {
+        let index = EmitterIndex::new();
+        // 20 dB stronger counts ten times as much
+        learn_at(&index, position(52.0, 13.0), &[1], -60);
+        learn_at(&index, position(52.0011, 13.0011), &[1], -80);
+
+        let (latitude, longitude) = location(&index, 1);
+        assert!((latitude - 52.0001).abs() < 1e-9);
+        assert!((longitude - 13.0001).abs() < 1e-9);
+        assert_eq!(index.stats().wifi, 1);
+    }
+
+    #[test]
+    fn learns_only_from_accurate_positions() {
+        let index = EmitterIndex::new();
+        let vague = Position {
+            accuracy: LEARN_MAX_POSITION_ACCURACY_METERS + 1.0,
+            ..position(52.0, 13.0)
+        };
+        assert!(index.learn(&payload(1, vague, &[1])).is_empty());
+        assert_eq!(index.stats().wifi, 0);
+    }
+
+    #[test]
+    fn locates_from_enough_known_emitters() {
+        let index = EmitterIndex::new();
+        learn_at(&index, position(52.0, 13.0), &[1], -60);
+        learn_at(&index, position(52.001, 13.0), &[2], -60);
+
+        // too few known, however many are heard
+        assert_eq!(LOCAL_GEOLOCATE_MIN_EMITTERS, 2);
+        assert!(index.locate(&request(&[1, 3, 4])).is_none());
+
+        let estimate = index.locate(&request(&[1, 2, 3])).unwrap();
+        assert!((estimate.location.lat - 52.0005).abs() < 1e-9);
+        assert!((estimate.location.lng - 13.0).abs() < 1e-9);
+        // half the distance between them, plus the radius each is heard over
+        let expected = haversine_meters(52.0, 13.0, 52.0005, 13.0) + EMITTER_MIN_RADIUS_METERS;
+        assert!((estimate.accuracy - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ignores_emitters_that_have_moved() {
+        let index = EmitterIndex::new();
+        learn_at(&index, position(52.0, 13.0), &[1, 2], -60);
+        // about 1.1 km away: access point 1 moved with its owner
+        learn_at(&index, position(52.01, 13.0), &[1], -60);
+        learn_at(&index, position(52.0, 13.0), &[3], -60);
+
+        let radius =
+            index.emitters.lock().unwrap()[&(EmitterKind::Wifi, access_point(1).bssid)].radius();
+        assert!(radius > EMITTER_MAX_RADIUS_METERS);
+        assert!(index.locate(&request(&[1, 2])).is_none());
+        assert!(index.locate(&request(&[1, 2, 3])).is_some());
+    }
+
+    #[test]
+    fn averages_across_the_antimeridian() {
+        let index = EmitterIndex::new();
+        learn_at(&index, position(0.0, 179.9999), &[1], -60);
+        learn_at(&index, position(0.0, -179.9999), &[1], -60);
+        learn_at(&index, position(0.0, -179.9997), &[2], -60);
+
+        let (_, longitude) = location(&index, 1);
+        assert!((longitude.abs() - 180.0).abs() < 1e-9);
+        // about 22 m wide, not a whole turn of the globe
+        let radius =
+            index.emitters.lock().unwrap()[&(EmitterKind::Wifi, access_point(1).bssid)].radius();
+        assert_eq!(radius, EMITTER_MIN_RADIUS_METERS);
+
+        let estimate = index.locate(&request(&[1, 2])).unwrap();
+        assert!((estimate.location.lng - -179.99985).abs() < 1e-9);
+        assert!(estimate.accuracy < 50.0);
+    }
+}
@@ -0,0 +1,212 @@
+//! Decide whether an assembled payload is worth submitting
+//!
+//! Payloads are skipped when they carry too few observations, or when they
+//! repeat the previous submission: same place, mostly the same BSSIDs, shortly after.
+
+use btleplug::api::BDAddr;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::SubmissionSettings;
+
+use super::payload::{Position, items};
+
+/// Why a payload was skipped
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Fewer observations than the configured minimums
+    TooFewObservations,
+    /// Practically the same as the previous submission
+    Duplicate,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::TooFewObservations => write!(f, "too few observations"),
+            SkipReason::Duplicate => write!(f, "duplicate of the previous submission"),
+        }
+    }
+}
+
+/// Counts of skipped payloads, as reported by `/status`
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct FilterStats {
+    pub too_few_observations: u64,
+    pub duplicates: u64,
+}
+
+pub struct SubmissionFilter {
+    settings: SubmissionSettings,
+    last_accepted: Mutex<Option<Fingerprint>>,
+    too_few_observations: AtomicU64,
+    duplicates: AtomicU64,
+}
+
+/// What we remember about the last accepted payload. `check` hands one out for
+/// each payload that passes, to give back to `remember` once it's been queued.
+pub struct Fingerprint {
+    /// Payload timestamp, in milliseconds since the Unix epoch, so replayed
+    /// imports are compared by when they were observed
+    observed_at: u128,
+    position: Position,
+    bssids: HashSet<BDAddr>,
+}
+
+impl SubmissionFilter {
+    pub fn new(settings: SubmissionSettings) -> Self {
+        SubmissionFilter {
+            settings,
+            last_accepted: Mutex::new(None),
+            too_few_observations: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+        }
+    }
+
+    /// Check a payload against the minimums and the last accepted submission
+    pub fn check(&self, payload: &items) -> Result<Fingerprint, SkipReason> {
+        if !self.meets_minimums(payload) {
+            self.too_few_observations.fetch_add(1, Ordering::Relaxed);
+            return Err(SkipReason::TooFewObservations);
+        }
+
+        let fingerprint = Fingerprint {
+            observed_at: payload.timestamp,
+            position: payload.position.clone(),
+            bssids: payload.wifiAccessPoints.iter().map(|ap| ap.bssid).collect(),
+        };
+
+        if let Some(last) = self.last_accepted.lock().unwrap().as_ref()
+            && self.is_duplicate(last, &fingerprint)
+        {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            return Err(SkipReason::Duplicate);
+        }
+
+        Ok(fingerprint)
+    }
+
+    /// Remember a checked payload as the latest submission, once it's been queued,
+    /// so one that fails later doesn't get the next one skipped as its duplicate
+    pub fn remember(&self, fingerprint: Fingerprint) {
+        *self.last_accepted.lock().unwrap() = Some(fingerprint);
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        FilterStats {
+            too_few_observations: self.too_few_observations.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the payload meets any of the minimums set above 0. With none set,
+    /// every payload does.
+    fn meets_minimums(&self, payload: &items) -> bool {
+        let cell_towers = payload.CellTowers.as_ref().map_or(0, Vec::len);
+        let mut requirements = [
+            (
+                payload.wifiAccessPoints.len(),
+                self.settings.min_wifi_access_points,
+            ),
+            (cell_towers, self.settings.min_cell_towers),
+            (
+                payload.bluetoothBeacons.len(),
+                self.settings.min_bluetooth_beacons,
+            ),
+        ]
+        .into_iter()
+        .filter(|&(_, minimum)| minimum > 0)
+        .peekable();
+
+        requirements.peek().is_none() || requirements.any(|(count, minimum)| count >= minimum)
+    }
+
+    fn is_duplicate(&self, last: &Fingerprint, next: &Fingerprint) -> bool {
+        let window = u128::from(self.settings.dedup_window_secs) * 1000;
+        if next.observed_at.abs_diff(last.observed_at) > window {
+            return false;
+        }
+
+        if last.position.distance_to(&next.position) > self.settings.dedup_distance_meters {
+            return false;
+        }
+
+        bssid_overlap(&last.bssids, &next.bssids) >= self.settings.dedup_bssid_overlap
+    }
+}
+
+/// Jaccard index of two BSSID sets; two empty sets count as identical
+fn bssid_overlap(a: &HashSet<BDAddr>, b: &HashSet<BDAddr>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+
+    a.intersection(b).count() as f64 / union as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{payload, position};
+
+    fn filter(wifi: usize, cells: usize, bluetooth: usize) -> SubmissionFilter {
+        SubmissionFilter::new(SubmissionSettings {
+            min_wifi_access_points: wifi,
+            min_cell_towers: cells,
+            min_bluetooth_beacons: bluetooth,
+            ..SubmissionSettings::default()
+        })
+    }
+
+    #[test]
+    fn any_minimum_set_qualifies() {
+        let filter = filter(2, 1, 0);
+        let here = position(52.0, 13.0);
+
+        assert_eq!(
+            filter.check(&payload(0, here.clone(), &[1])).err(),
+            Some(SkipReason::TooFewObservations)
+        );
+        assert!(filter.check(&payload(0, here, &[1, 2])).is_ok());
+    }
+
+    #[test]
+    fn minimums_of_zero_are_no_requirement() {
+        let filter = filter(0, 0, 0);
+        assert!(filter.check(&payload(0, position(52.0, 13.0), &[])).is_ok());
+    }
+
+    #[test]
+    fn only_remembered_payloads_count_as_duplicated() {
+        let filter = filter(1, 0, 0);
+        let here = position(52.0, 13.0);
+        let first = payload(0, here.clone(), &[1, 2, 3]);
+        let again = payload(60_000, here, &[1, 2, 3]);
+
+        // checked but never queued, e.g. because the queue write failed
+        assert!(filter.check(&first).is_ok());
+        let fingerprint = filter.check(&again).unwrap();
+
+        filter.remember(fingerprint);
+        assert_eq!(filter.check(&first).err(), Some(SkipReason::Duplicate));
+        assert_eq!(filter.stats().duplicates, 1);
+    }
+
+    #[test]
+    fn moving_or_new_access_points_are_not_duplicates() {
+        let filter = filter(1, 0, 0);
+        let first = payload(0, position(52.0, 13.0), &[1, 2, 3]);
+        filter.remember(filter.check(&first).unwrap());
+
+        let moved = payload(60_000, position(52.01, 13.0), &[1, 2, 3]);
+        assert!(filter.check(&moved).is_ok());
+        let new_access_points = payload(60_000, position(52.0, 13.0), &[4, 5, 6]);
+        assert!(filter.check(&new_access_points).is_ok());
+        let later = payload(3_600_000, position(52.0, 13.0), &[1, 2, 3]);
+        assert!(filter.check(&later).is_ok());
+    }
+}
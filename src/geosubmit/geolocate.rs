@@ -0,0 +1,337 @@
+//! Ichnaea-compatible `/v1/geolocate` client, used to check our contributions
+//!
+//! A self-check scans the current surroundings, asks the provider where it thinks
+//! we are, and — given a reference position such as the phone's GPS — reports
+//! how far off the provider's answer is.
+
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::scanner::{BleDevice, WifiBssid, bluetooth, wifi};
+
+use super::client::parse_retry_after;
+use super::http::{HttpClient, is_rate_limit_status};
+use super::payload::{CellTower, Position, haversine_meters};
+
+/// Geolocate request body. Network names are never sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[allow(non_snake_case)]
+pub struct GeolocateRequest {
+    #[serde(default)]
+    pub considerIp: bool,
+    #[serde(default)]
+    pub wifiAccessPoints: Vec<GeolocateWifi>,
+    #[serde(default)]
+    pub bluetoothBeacons: Vec<GeolocateBeacon>,
+    #[serde(default)]
+    pub cellTowers: Vec<CellTower>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct GeolocateWifi {
+    pub macAddress: BDAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signalStrength: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct GeolocateBeacon {
+    pub macAddress: BDAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signalStrength: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeolocateResponse {
+    pub location: LatLng,
+    /// Radius of the estimate, in meters
+    pub accuracy: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LatLng {
+    pub lat: f64,
+    pub lng: f64,
+}
+
+/// Outcome of a self-check
+#[derive(Serialize, Debug, Clone)]
+pub struct SelfCheck {
+    pub endpoint: String,
+    pub wifi_access_points: usize,
+    pub bluetooth_beacons: usize,
+    pub cell_towers: usize,
+    /// `None` if the provider couldn't locate these observations
+    pub estimate: Option<GeolocateResponse>,
+    pub reference: Option<Position>,
+    /// Distance between the estimate and the reference position, in meters
+    pub error_meters: Option<f64>,
+    /// Whether the reference position lies within the estimate's accuracy radius
+    pub within_accuracy: Option<bool>,
+}
+
+impl GeolocateRequest {
+    pub fn from_observations(
+        wifi: &[WifiBssid],
+        ble: &[BleDevice],
+        cell_towers: Vec<CellTower>,
+    ) -> Self {
+        GeolocateRequest {
+            considerIp: false,
+            wifiAccessPoints: wifi
+                .iter()
+                .map(|ap| GeolocateWifi {
+                    macAddress: ap.bssid,
+                    signalStrength: Some(ap.rssi),
+                    age: ap.age,
+                    channel: ap.channel,
+                    frequency: Some(ap.frequency),
+                })
+                .collect(),
+            bluetoothBeacons: ble
+                .iter()
+                .map(|beacon| GeolocateBeacon {
+                    macAddress: beacon.mac_address,
+                    signalStrength: beacon.rssi,
+                    age: None,
+                })
+                .collect(),
+            cellTowers: cell_towers,
+        }
+    }
+}
+
+/// Ask a provider to locate a set of observations; `None` if it can't
+pub async fn geolocate(
+    http: &HttpClient,
+    endpoint: &str,
+    request: &GeolocateRequest,
+) -> Result<Option<GeolocateResponse>> {
+    let res = http
+        .client()
+        .post(endpoint)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(request)?)
+        .send()
+        .await?;
+    let status = res.status();
+
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    if is_rate_limit_status(status) {
+        return Err(Error::RateLimited {
+            status: status.as_u16(),
+            retry_after: res
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok().and_then(parse_retry_after)),
+        });
+    }
+
+    if !status.is_success() {
+        return Err(Error::HttpStatus {
+            status: status.as_u16(),
+            body: res.text().await.unwrap_or_default(),
+        });
+    }
+
+    let body = res.bytes().await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Scan, geolocate the results, and compare against a reference position if given
+pub async fn self_check(
+    http: &HttpClient,
+    endpoint: &str,
+    reference: Option<Position>,
+    cell_towers: Vec<CellTower>,
+) -> Result<SelfCheck> {
+    let (wifi, ble) = tokio::join!(wifi::fetch_wifi_stats(), bluetooth::fetch_ble_devices());
+
+    let request = GeolocateRequest::from_observations(&wifi, &ble, cell_towers);
+    check(http, endpoint, &request, reference).await
+}
+
+/// Geolocate a set of observations, and compare against a reference position if given
+pub async fn check(
+    http: &HttpClient,
+    endpoint: &str,
+    request: &GeolocateRequest,
+    reference: Option<Position>,
+) -> Result<SelfCheck> {
+    let estimate = geolocate(http, endpoint, request).await?;
+
+    let error_meters = estimate
+        .as_ref()
+        .zip(reference.as_ref())
+        .map(|(e, r)| haversine_meters(e.location.lat, e.location.lng, r.latitude, r.longitude));
+    let within_accuracy = estimate
+        .as_ref()
+        .zip(error_meters)
+        .map(|(e, error)| error <= e.accuracy);
+
+    match (&estimate, error_meters) {
+        (Some(e), Some(error)) => tracing::info!(
+            "[Geolocate] Estimate {:.6},{:.6} ±{:.0} m is {:.0} m from the reference",
+            e.location.lat,
+            e.location.lng,
+            e.accuracy,
+            error
+        ),
+        (Some(e), None) => tracing::info!(
+            "[Geolocate] Estimate {:.6},{:.6} ±{:.0} m",
+            e.location.lat,
+            e.location.lng,
+            e.accuracy
+        ),
+        (None, _) => tracing::info!("[Geolocate] Provider couldn't locate the current scan"),
+    }
+
+    Ok(SelfCheck {
+        endpoint: endpoint.to_string(),
+        wifi_access_points: request.wifiAccessPoints.len(),
+        bluetooth_beacons: request.bluetoothBeacons.len(),
+        cell_towers: request.cellTowers.len(),
+        estimate,
+        reference,
+        error_meters,
+        within_accuracy,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpSettings;
+    use crate::error::ErrorCategory;
+    use crate::test_support::{self, access_point, position};
+    use axum::Router;
+    use axum::extract::Json;
+    use axum::http::{StatusCode, header};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::post;
+    use std::sync::{Arc, Mutex};
+
+    /// A provider answering `/v1/geolocate` with `response`, keeping the last request
+    async fn provider(response: Response) -> (String, Arc<Mutex<Option<serde_json::Value>>>) {
+        let received = Arc::new(Mutex::new(None));
+        let response = Arc::new(Mutex::new(Some(response)));
+        let seen = Arc::clone(&received);
+        let router = Router::new().route(
+            "/v1/geolocate",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                *seen.lock().unwrap() = Some(body);
+                response.lock().unwrap().take().unwrap()
+            }),
+        );
+        let address = test_support::serve(router).await;
+        (format!("http://{}/v1/geolocate", address), received)
+    }
+
+    fn http() -> HttpClient {
+        HttpClient::from_settings(&HttpSettings {
+            max_retries: 0,
+            ..HttpSettings::default()
+        })
+        .unwrap()
+    }
+
+    fn request() -> GeolocateRequest {
+        GeolocateRequest::from_observations(&[access_point(1), access_point(2)], &[], Vec::new())
+    }
+
+    fn estimate(lat: f64, lng: f64, accuracy: f64) -> Response {
+        Json(serde_json::json!({ "location": { "lat": lat, "lng": lng }, "accuracy": accuracy }))
+            .into_response()
+    }
+
+    #[tokio::test]
+    async fn compares_the_estimate_with_the_reference() {
+        let (endpoint, received) = provider(estimate(52.001, 13.0, 150.0)).await;
+
+        let reference = position(52.0, 13.0);
+        let outcome = check(&http(), &endpoint, &request(), Some(reference))
+            .await
+            .unwrap();
+
+        let error = outcome.error_meters.unwrap();
+        // a thousandth of a degree of latitude is about 111 m
+        assert!((error - 111.2).abs() < 0.5, "{}", error);
+        assert_eq!(error, haversine_meters(52.001, 13.0, 52.0, 13.0));
+        assert_eq!(outcome.within_accuracy, Some(true));
+        assert_eq!(outcome.wifi_access_points, 2);
+
+        let sent = received.lock().unwrap().take().unwrap();
+        assert_eq!(sent["considerIp"], false);
+        assert_eq!(sent["wifiAccessPoints"].as_array().unwrap().len(), 2);
+        assert!(sent["wifiAccessPoints"][0].get("ssid").is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_a_reference_outside_the_accuracy_radius() {
+        let (endpoint, _) = provider(estimate(52.01, 13.0, 150.0)).await;
+
+        let outcome = check(&http(), &endpoint, &request(), Some(position(52.0, 13.0)))
+            .await
+            .unwrap();
+        assert!(outcome.error_meters.unwrap() > 1000.0);
+        assert_eq!(outcome.within_accuracy, Some(false));
+    }
+
+    #[tokio::test]
+    async fn leaves_out_the_comparison_without_a_reference() {
+        let (endpoint, _) = provider(estimate(52.0, 13.0, 150.0)).await;
+
+        let outcome = check(&http(), &endpoint, &request(), None).await.unwrap();
+        assert!(outcome.estimate.is_some());
+        assert_eq!(outcome.error_meters, None);
+        assert_eq!(outcome.within_accuracy, None);
+    }
+
+    #[tokio::test]
+    async fn not_found_means_no_estimate() {
+        let not_found = (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({ "error": { "code": 404, "message": "Not found" } })),
+        )
+            .into_response();
+        let (endpoint, _) = provider(not_found).await;
+
+        let outcome = check(&http(), &endpoint, &request(), Some(position(52.0, 13.0)))
+            .await
+            .unwrap();
+        assert!(outcome.estimate.is_none());
+        assert_eq!(outcome.error_meters, None);
+        assert_eq!(outcome.within_accuracy, None);
+    }
+
+    #[tokio::test]
+    async fn passes_on_rate_limits_and_failures() {
+        let rate_limited =
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "30")]).into_response();
+        let (endpoint, _) = provider(rate_limited).await;
+        let error = geolocate(&http(), &endpoint, &request()).await.unwrap_err();
+        assert_eq!(error.category(), ErrorCategory::RateLimited);
+        assert_eq!(
+            error.retry_after(),
+            Some(std::time::Duration::from_secs(30))
+        );
+
+        let (endpoint, _) = provider(StatusCode::BAD_GATEWAY.into_response()).await;
+        let error = geolocate(&http(), &endpoint, &request()).await.unwrap_err();
+        assert_eq!(error.category(), ErrorCategory::Upstream);
+    }
+}
@@ -0,0 +1,296 @@
+//! Shared HTTP client for talking to geolocation providers
+//!
+//! Built once from the `http` settings and held in the application state, so
+//! connection pools, proxy configuration and trust roots are reused across requests.
+
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use reqwest_retry::{
+    RetryTransientMiddleware, Retryable, RetryableStrategy, default_on_request_failure,
+    default_on_request_success, policies::ExponentialBackoff,
+};
+use reqwest_tracing::TracingMiddleware;
+use std::fs;
+use std::time::Duration;
+
+use crate::config::{APP_USER_AGENT, HttpSettings};
+use crate::error::{Error, Result};
+
+/// Retry transient failures, except 429/503: those carry a `Retry-After`
+/// that the provider's circuit breaker honours instead.
+struct ProviderRetryStrategy;
+
+impl RetryableStrategy for ProviderRetryStrategy {
+    fn handle(
+        &self,
+        res: &std::result::Result<reqwest::Response, reqwest_middleware::Error>,
+    ) -> Option<Retryable> {
+        match res {
+            Ok(response) if is_rate_limit_status(response.status()) => Some(Retryable::Fatal),
+            Ok(response) => default_on_request_success(response),
+            Err(error) => default_on_request_failure(error),
+        }
+    }
+}
+
+pub fn is_rate_limit_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+}
+
+#[derive(Clone)]
+pub struct HttpClient {
+    client: ClientWithMiddleware,
+}
+
+impl HttpClient {
+    pub fn from_settings(settings: &HttpSettings) -> Result<Self> {
+        // the same rustls backend the HTTPS server uses; a no-op once installed
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+        let mut builder = reqwest::Client::builder()
+            .user_agent(APP_USER_AGENT)
+            .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+            .timeout(Duration::from_secs(settings.timeout_secs));
+
+        if let Some(proxy_url) = &settings.proxy {
+            // socks5h:// resolves names through the proxy, which is what Tor needs
+            let proxy = reqwest::Proxy::all(proxy_url)
+                .map_err(|e| Error::Config(format!("Invalid proxy {}: {}", proxy_url, e)))?;
+            builder = builder.proxy(proxy);
+            tracing::info!("Routing provider requests through proxy {}", proxy_url);
+        }
+
+        for path in &settings.ca_certificates {
+            let pem = fs::read(path).map_err(|e| {
+                Error::Config(format!(
+                    "Can't read CA certificate {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+            let certificates = reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| {
+                Error::Config(format!("Invalid CA certificate {}: {}", path.display(), e))
+            })?;
+
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+            tracing::info!(
+                "Trusting additional CA certificates from {}",
+                path.display()
+            );
+        }
+
+        if Duration::from_millis(settings.retry_min_backoff_ms)
+            > Duration::from_secs(settings.retry_max_backoff_secs)
+        {
+            return Err(Error::Config(format!(
+                "retry_min_backoff_ms ({} ms) is longer than retry_max_backoff_secs ({} s)",
+                settings.retry_min_backoff_ms, settings.retry_max_backoff_secs
+            )));
+        }
+        let retry_policy = ExponentialBackoff::builder()
+            .retry_bounds(
+                Duration::from_millis(settings.retry_min_backoff_ms),
+                Duration::from_secs(settings.retry_max_backoff_secs),
+            )
+            .build_with_max_retries(settings.max_retries);
+
+        let client = ClientBuilder::new(builder.build()?)
+            .with(TracingMiddleware::default())
+            .with(RetryTransientMiddleware::new_with_policy_and_strategy(
+                retry_policy,
+                ProviderRetryStrategy,
+            ))
+            .build();
+
+        Ok(HttpClient { client })
+    }
+
+    pub fn client(&self) -> &ClientWithMiddleware {
+        &self.client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCategory;
+    use crate::test_support;
+    use axum::Router;
+    use axum::extract::{Request, State};
+    use axum::http::StatusCode;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn settings() -> HttpSettings {
+        HttpSettings {
+            retry_min_backoff_ms: 10,
+            retry_max_backoff_secs: 1,
+            ..HttpSettings::default()
+        }
+    }
+
+    /// A server answering every request with `status`, counting them
+    async fn answering(status: StatusCode) -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let router = Router::new()
+            .fallback(move |State(hits): State<Arc<AtomicUsize>>| async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                status
+            })
+            .with_state(Arc::clone(&hits));
+        let address = test_support::serve(router).await;
+        (format!("http://{}/v2/geosubmit", address), hits)
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_within_bounds() {
+        let (url, hits) = answering(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let http = HttpClient::from_settings(&HttpSettings {
+            max_retries: 3,
+            ..settings()
+        })
+        .unwrap();
+
+        let started = Instant::now();
+        let response = http.client().post(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        // three waits, each capped at retry_max_backoff_secs
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn leaves_rate_limits_and_rejections_to_the_caller() {
+        let http = HttpClient::from_settings(&settings()).unwrap();
+
+        for status in [
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::BAD_REQUEST,
+        ] {
+            let (url, hits) = answering(status).await;
+            let response = http.client().post(&url).send().await.unwrap();
+            assert_eq!(response.status(), status);
+            assert_eq!(hits.load(Ordering::SeqCst), 1, "{}", status);
+        }
+    }
+
+    #[test]
+    fn rejects_inverted_retry_bounds() {
+        let error = HttpClient::from_settings(&HttpSettings {
+            retry_min_backoff_ms: 5_000,
+            retry_max_backoff_secs: 1,
+            ..HttpSettings::default()
+        })
+        .err()
+        .unwrap();
+        assert_eq!(error.category(), ErrorCategory::Config);
+    }
+
+    #[tokio::test]
+    async fn sends_requests_through_the_proxy() {
+        // a forward proxy sees the absolute URL of the provider in the request line
+        let proxy =
+            Router::new().fallback(|request: Request| async move { request.uri().to_string() });
+        let proxy_address = test_support::serve(proxy).await;
+
+        let http = HttpClient::from_settings(&HttpSettings {
+            proxy: Some(format!("http://{}", proxy_address)),
+            ..settings()
+        })
+        .unwrap();
+        let response = http
+            .client()
+            .get("http://provider.invalid/v2/geosubmit")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.text().await.unwrap(),
+            "http://provider.invalid/v2/geosubmit"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_proxies() {
+        let error = HttpClient::from_settings(&HttpSettings {
+            proxy: Some("not a proxy".to_string()),
+            ..HttpSettings::default()
+        })
+        .err()
+        .unwrap();
+        assert_eq!(error.category(), ErrorCategory::Config);
+    }
+
+    /// An HTTPS server with a self-signed certificate, answering `200 OK` once
+    /// per connection; returns its port and certificate
+    async fn self_signed_server() -> (u16, String) {
+        let identity = test_support::self_signed();
+        let acceptor =
+            tokio_rustls::TlsAcceptor::from(Arc::new(test_support::server_config(&identity)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                    )
+                    .await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        (port, identity.cert.pem())
+    }
+
+    #[tokio::test]
+    async fn trusts_configured_ca_certificates() {
+        let (port, pem) = self_signed_server().await;
+        let url = format!("https://localhost:{}/", port);
+
+        let untrusting = HttpClient::from_settings(&settings()).unwrap();
+        let error: Error = untrusting
+            .client()
+            .get(&url)
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        assert_eq!(error.category(), ErrorCategory::Tls);
+
+        let dir = test_support::temp_dir("ca");
+        let ca_path = dir.join("provider.pem");
+        fs::write(&ca_path, pem).unwrap();
+        let trusting = HttpClient::from_settings(&HttpSettings {
+            ca_certificates: vec![ca_path],
+            ..settings()
+        })
+        .unwrap();
+        let response = trusting.client().get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_unreadable_ca_certificates() {
+        let error = HttpClient::from_settings(&HttpSettings {
+            ca_certificates: vec!["/nonexistent/ca.pem".into()],
+            ..HttpSettings::default()
+        })
+        .err()
+        .unwrap();
+        assert_eq!(error.category(), ErrorCategory::Config);
+    }
+}
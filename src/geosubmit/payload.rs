@@ -2,6 +2,14 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Error, Result};
+
+/// Top-level geosubmit v2 request body
+#[derive(Serialize, Debug, Deserialize, Clone)]
+pub struct GeoSubmission {
+    pub items: Vec<items>,
+}
+
 #[derive(Serialize, Debug, Deserialize, Clone)]
 #[allow(nonstandard_style)]
 pub struct items {
@@ -21,9 +29,70 @@ pub struct Position {
     pub accuracy: f64,
     pub altitude: f64,
     pub altitudeAccuracy: f64,
-    pub heading: f64,
-    pub speed: f64,
-    pub source: String,
+    /// Direction of travel in degrees clockwise from north
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<f64>,
+    /// Speed in meters per second
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f64>,
+    #[serde(default)]
+    pub source: LocationSource,
+}
+
+/// How a position was determined, as in Ichnaea's `source` field
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationSource {
+    /// A single satellite fix, from the phone or a receiver on this machine
+    #[default]
+    Gps,
+    /// Entered by hand, such as a stationary node's configured position
+    Manual,
+    /// Combined from several sources, or a phone's own network-assisted estimate
+    Fused,
+}
+
+impl LocationSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LocationSource::Gps => "gps",
+            LocationSource::Manual => "manual",
+            LocationSource::Fused => "fused",
+        }
+    }
+}
+
+impl std::fmt::Display for LocationSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for LocationSource {
+    type Err = Error;
+
+    /// One of Ichnaea's values, in any case; anything else is refused rather than guessed at
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "gps" => Ok(LocationSource::Gps),
+            "manual" => Ok(LocationSource::Manual),
+            "fused" => Ok(LocationSource::Fused),
+            _ => Err(Error::Validation(format!(
+                "unknown position source '{}'; expected gps, manual or fused",
+                s
+            ))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LocationSource {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
 }
 
 #[derive(Serialize, Debug, Deserialize, Clone)]
@@ -49,6 +118,84 @@ pub enum RadioType {
     Lte,
 }
 
+impl GeoSubmission {
+    /// Check the document is something a geosubmit provider would accept
+    pub fn validate(&self) -> Result<()> {
+        if self.items.is_empty() {
+            return Err(Error::Validation("submission has no items".into()));
+        }
+
+        self.items.iter().try_for_each(items::validate)
+    }
+}
+
+impl items {
+    /// Check a single item has a plausible position and at least one observation
+    pub fn validate(&self) -> Result<()> {
+        self.position.validate()?;
+
+        let cell_towers = self.CellTowers.as_ref().map_or(0, Vec::len);
+        if self.wifiAccessPoints.is_empty() && self.bluetoothBeacons.is_empty() && cell_towers == 0
+        {
+            return Err(Error::Validation(
+                "item has no WiFi, Bluetooth or cell observations".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Mean Earth radius, in meters
+pub const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+impl Position {
+    /// Great-circle (haversine) distance to another position, in meters
+    pub fn distance_to(&self, other: &Position) -> f64 {
+        haversine_meters(
+            self.latitude,
+            self.longitude,
+            other.latitude,
+            other.longitude,
+        )
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(Error::Validation(format!(
+                "latitude {} out of range",
+                self.latitude
+            )));
+        }
+
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(Error::Validation(format!(
+                "longitude {} out of range",
+                self.longitude
+            )));
+        }
+
+        if !self.accuracy.is_finite() || self.accuracy < 0.0 {
+            return Err(Error::Validation(format!(
+                "accuracy {} is not a non-negative distance",
+                self.accuracy
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Great-circle distance between two coordinates in degrees, in meters
+pub fn haversine_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
 impl CellTower {
     /// Set radio type from string
     pub fn set_radio_type(&mut self, radio: &str) {
@@ -60,3 +207,27 @@ impl CellTower {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_ichnaea_sources() {
+        assert_eq!(
+            "GPS".parse::<LocationSource>().unwrap(),
+            LocationSource::Gps
+        );
+        assert_eq!(
+            serde_json::from_str::<LocationSource>("\"fused\"").unwrap(),
+            LocationSource::Fused
+        );
+        for name in ["network", "wifi", ""] {
+            assert!(name.parse::<LocationSource>().is_err(), "{}", name);
+        }
+
+        let position = r#"{"latitude": 52.0, "longitude": 13.0, "accuracy": 5.0,
+            "altitude": 0.0, "altitudeAccuracy": 0.0, "source": "network"}"#;
+        assert!(serde_json::from_str::<Position>(position).is_err());
+    }
+}
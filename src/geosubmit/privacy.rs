@@ -0,0 +1,137 @@
+//! Privacy policy applied to everything that leaves the machine
+//!
+//! With the policy enabled, queued payloads are held back for a random delay and
+//! sent shuffled in with others, so the time and source address of an upload no
+//! longer pinpoint where the observations were made. Held-back payloads are only
+//! released at the end of a flush window, all together. Optional fields can be
+//! stripped independently of the delay.
+
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::now_millis;
+use crate::config::{PRIVACY_FLUSH_INTERVAL_SECS, PRIVACY_MAX_DELAY_SECS, PRIVACY_MIN_DELAY_SECS};
+
+use super::payload::items;
+
+/// Describes exactly what is uploaded, and when
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PrivacyPolicy {
+    /// Hold each payload back for a random delay and shuffle batches before upload
+    pub delay_submissions: bool,
+    /// Bounds of the random delay, in seconds
+    pub min_delay_secs: u64,
+    pub max_delay_secs: u64,
+    /// Delayed payloads are released together at the end of each window this long
+    pub flush_interval_secs: u64,
+    /// Drop WiFi network names
+    pub strip_ssid: bool,
+    /// Drop Bluetooth device names
+    pub strip_ble_name: bool,
+    /// Drop the direction of travel
+    pub strip_heading: bool,
+    /// Drop the speed of travel
+    pub strip_speed: bool,
+}
+
+impl Default for PrivacyPolicy {
+    fn default() -> Self {
+        PrivacyPolicy {
+            delay_submissions: false,
+            min_delay_secs: PRIVACY_MIN_DELAY_SECS,
+            max_delay_secs: PRIVACY_MAX_DELAY_SECS,
+            flush_interval_secs: PRIVACY_FLUSH_INTERVAL_SECS,
+            strip_ssid: false,
+            strip_ble_name: false,
+            strip_heading: false,
+            strip_speed: false,
+        }
+    }
+}
+
+impl PrivacyPolicy {
+    /// When a payload queued now may be uploaded, in milliseconds since the Unix
+    /// epoch: after a random delay, at the end of the flush window it ends in
+    pub fn release_time(&self) -> i64 {
+        let now = now_millis();
+        if !self.delay_submissions {
+            return now;
+        }
+
+        let max = self.max_delay_secs.max(self.min_delay_secs);
+        let delay = rand::rng().random_range(self.min_delay_secs..=max) as i64 * 1000;
+        self.window_end(now + delay)
+    }
+
+    /// The end of the flush window `time` falls in. Windows are aligned to the
+    /// Unix epoch, so every payload shares them.
+    fn window_end(&self, time: i64) -> i64 {
+        let window = self.flush_interval_secs.max(1) as i64 * 1000;
+        (time + window - 1).div_euclid(window) * window
+    }
+
+    /// Shuffle what's due so upload order doesn't reveal collection order
+    pub fn shuffle<T>(&self, batch: &mut [T]) {
+        if self.delay_submissions {
+            batch.shuffle(&mut rand::rng());
+        }
+    }
+
+    /// Remove the fields this policy doesn't allow to leave the machine
+    pub fn apply(&self, payload: &mut items) {
+        if self.strip_ssid {
+            for access_point in payload.wifiAccessPoints.iter_mut() {
+                access_point.ssid = None;
+            }
+        }
+
+        if self.strip_ble_name {
+            for beacon in payload.bluetoothBeacons.iter_mut() {
+                beacon.name = None;
+            }
+        }
+
+        if self.strip_heading {
+            payload.position.heading = None;
+        }
+
+        if self.strip_speed {
+            payload.position.speed = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_end_at_a_window_boundary() {
+        let policy = PrivacyPolicy {
+            delay_submissions: true,
+            flush_interval_secs: 60,
+            ..PrivacyPolicy::default()
+        };
+        let minute = 60_000;
+
+        // queued at different times, but released together
+        for (now, delay) in [
+            (10 * minute + 1, 30_000),
+            (10 * minute + 20_000, 5_000),
+            (10 * minute, 60_000),
+        ] {
+            assert_eq!(
+                policy.window_end(now + delay),
+                11 * minute,
+                "{} + {}",
+                now,
+                delay
+            );
+        }
+        // already on a boundary
+        assert_eq!(policy.window_end(11 * minute), 11 * minute);
+        assert_eq!(policy.window_end(10 * minute + 61_000), 12 * minute);
+    }
+}
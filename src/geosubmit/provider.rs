@@ -0,0 +1,388 @@
+//! Per-provider submission limits: a token-bucket rate limiter and a circuit breaker
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::{
+    DRY_RUN_PROVIDER_NAME, GEOSUBMIT_ENDPOINT, GEOSUBMIT_PROVIDER_NAME, ProviderSettings,
+};
+
+use crate::clock::now_millis;
+use crate::error::Error;
+
+use super::archive::JsonlArchive;
+
+/// A geolocation service we submit to, along with its submission limits
+pub struct Provider {
+    pub name: String,
+    pub endpoint: String,
+    /// Set for the dry-run target: documents are archived locally instead of uploaded
+    pub archive: Option<JsonlArchive>,
+    gzip: AtomicBool,
+    limiter: Mutex<TokenBucket>,
+    breaker: Mutex<CircuitBreaker>,
+    deliveries: Mutex<DeliveryLog>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Submissions flow normally
+    Closed,
+    /// Submissions are paused until the cooldown (or `Retry-After`) elapses
+    Open,
+    /// The cooldown elapsed; the next submission is a trial
+    HalfOpen,
+}
+
+/// Provider state, as reported by `/status`
+#[derive(Serialize, Debug, Clone)]
+pub struct ProviderStatus {
+    pub name: String,
+    pub endpoint: String,
+    pub gzip: bool,
+    pub breaker: BreakerState,
+    pub consecutive_failures: u32,
+    /// Seconds until submissions resume, while the breaker is open
+    pub resumes_in_secs: Option<u64>,
+    pub tokens_available: u32,
+    /// When a batch was last delivered, in milliseconds since the Unix epoch
+    pub last_success: Option<i64>,
+    pub last_failure: Option<i64>,
+    pub last_error: Option<String>,
+    /// Submissions delivered since startup
+    pub delivered: u64,
+    /// Failed delivery attempts since startup
+    pub failures: u64,
+}
+
+/// Outcomes of delivery attempts, for `/status`
+#[derive(Default)]
+struct DeliveryLog {
+    last_success: Option<i64>,
+    last_failure: Option<i64>,
+    last_error: Option<String>,
+    delivered: u64,
+    failures: u64,
+}
+
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+struct CircuitBreaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl Provider {
+    pub fn new(name: &str, endpoint: &str, gzip: bool, settings: &ProviderSettings) -> Self {
+        Provider {
+            name: name.to_string(),
+            endpoint: endpoint.to_string(),
+            archive: None,
+            gzip: AtomicBool::new(gzip),
+            limiter: Mutex::new(TokenBucket::new(
+                settings.rate_limit_burst.max(1),
+                settings.rate_limit_per_minute.max(1) as f64 / 60.0,
+            )),
+            breaker: Mutex::new(CircuitBreaker {
+                consecutive_failures: 0,
+                open_until: None,
+                failure_threshold: settings.breaker_failure_threshold.max(1),
+                cooldown: Duration::from_secs(settings.breaker_cooldown_secs),
+            }),
+            deliveries: Mutex::new(DeliveryLog::default()),
+        }
+    }
+
+    /// The default provider, BeaconDB
+    pub fn beacondb(gzip: bool, settings: &ProviderSettings) -> Self {
+        Provider::new(GEOSUBMIT_PROVIDER_NAME, GEOSUBMIT_ENDPOINT, gzip, settings)
+    }
+
+    /// A provider that writes each document to a local JSON Lines archive
+    pub fn dry_run(archive: JsonlArchive) -> Self {
+        let endpoint = format!("file://{}", archive.active_path().display());
+
+        Provider {
+            archive: Some(archive),
+            ..Provider::new(
+                DRY_RUN_PROVIDER_NAME,
+                &endpoint,
+                false,
+                &ProviderSettings::default(),
+            )
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.archive.is_some()
+    }
+
+    pub fn gzip_enabled(&self) -> bool {
+        self.gzip.load(Ordering::Relaxed)
+    }
+
+    /// Fall back to uncompressed uploads after the provider rejected gzip
+    pub fn disable_gzip(&self) {
+        if self.gzip.swap(false, Ordering::Relaxed) {
+            tracing::info!("[{}] Disabled gzip uploads for this session", self.name);
+        }
+    }
+
+    /// Wait until the breaker allows a request and a rate-limit token is available
+    pub async fn wait_ready(&self) {
+        if self.is_dry_run() {
+            return;
+        }
+
+        loop {
+            let now = Instant::now();
+
+            let paused_for = self.breaker.lock().unwrap().open_until.and_then(|until| {
+                let remaining = until.saturating_duration_since(now);
+                (!remaining.is_zero()).then_some(remaining)
+            });
+            if let Some(delay) = paused_for {
+                tracing::info!(
+                    "[{}] Circuit open; pausing submissions for {:?}",
+                    self.name,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+
+            let wait = self.limiter.lock().unwrap().take(now);
+            match wait {
+                None => return,
+                Some(delay) => {
+                    tracing::debug!("[{}] Rate limited locally for {:?}", self.name, delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.consecutive_failures = 0;
+        breaker.open_until = None;
+    }
+
+    /// Record a delivered batch of `submissions`
+    pub fn record_delivery(&self, submissions: usize) {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        deliveries.last_success = Some(now_millis());
+        deliveries.delivered += submissions as u64;
+    }
+
+    /// Record a failed submission. A `Retry-After` from the provider pauses
+    /// submissions immediately; otherwise the breaker opens after repeated failures.
+    pub fn record_failure(&self, error: &Error) {
+        {
+            let mut deliveries = self.deliveries.lock().unwrap();
+            deliveries.last_failure = Some(now_millis());
+            deliveries.last_error = Some(error.to_string());
+            deliveries.failures += 1;
+        }
+
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.consecutive_failures = breaker.consecutive_failures.saturating_add(1);
+
+        let pause = match error.retry_after() {
+            Some(delay) => Some(delay),
+            None if breaker.consecutive_failures >= breaker.failure_threshold => {
+                Some(breaker.cooldown)
+            }
+            None => None,
+        };
+
+        if let Some(pause) = pause {
+            tracing::warn!(
+                "[{}] Pausing submissions for {:?} after {} consecutive failure(s)",
+                self.name,
+                pause,
+                breaker.consecutive_failures
+            );
+            breaker.open_until = Some(Instant::now() + pause);
+        }
+    }
+
+    pub fn status(&self) -> ProviderStatus {
+        let now = Instant::now();
+        let (state, consecutive_failures, resumes_in) = {
+            let breaker = self.breaker.lock().unwrap();
+            let resumes_in = breaker
+                .open_until
+                .map(|until| until.saturating_duration_since(now))
+                .filter(|remaining| !remaining.is_zero());
+            let state = match (resumes_in, breaker.open_until) {
+                (Some(_), _) => BreakerState::Open,
+                (None, Some(_)) => BreakerState::HalfOpen,
+                (None, None) => BreakerState::Closed,
+            };
+            (state, breaker.consecutive_failures, resumes_in)
+        };
+
+        let tokens_available = {
+            let mut limiter = self.limiter.lock().unwrap();
+            limiter.refill(now);
+            limiter.tokens.floor() as u32
+        };
+
+        let deliveries = self.deliveries.lock().unwrap();
+        ProviderStatus {
+            name: self.name.clone(),
+            endpoint: self.endpoint.clone(),
+            gzip: self.gzip_enabled(),
+            breaker: state,
+            consecutive_failures,
+            resumes_in_secs: resumes_in.map(|d| d.as_secs_f64().ceil() as u64),
+            tokens_available,
+            last_success: deliveries.last_success,
+            last_failure: deliveries.last_failure,
+            last_error: deliveries.last_error.clone(),
+            delivered: deliveries.delivered,
+            failures: deliveries.failures,
+        }
+    }
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, refill_per_sec: f64) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refill_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take a token, or return how long until one is available
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            let missing = 1.0 - self.tokens;
+            Some(Duration::from_secs_f64(missing / self.refill_per_sec))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(settings: ProviderSettings) -> Provider {
+        Provider::new("test", "http://127.0.0.1:9/v2/geosubmit", false, &settings)
+    }
+
+    fn server_error() -> Error {
+        Error::HttpStatus {
+            status: 503,
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3, 0.5);
+        bucket.last_refill = start;
+
+        for _ in 0..3 {
+            assert_eq!(bucket.take(start), None);
+        }
+        assert_eq!(bucket.take(start), Some(Duration::from_secs(2)));
+        assert_eq!(
+            bucket.take(start + Duration::from_secs(1)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(bucket.take(start + Duration::from_secs(2)), None);
+
+        // never more than the burst, however long it sat idle
+        bucket.refill(start + Duration::from_secs(3600));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn breaker_opens_after_the_threshold_and_closes_on_success() {
+        let provider = provider(ProviderSettings {
+            breaker_failure_threshold: 3,
+            breaker_cooldown_secs: 600,
+            ..ProviderSettings::default()
+        });
+        assert_eq!(provider.status().breaker, BreakerState::Closed);
+
+        provider.record_failure(&server_error());
+        provider.record_failure(&server_error());
+        let status = provider.status();
+        assert_eq!(status.breaker, BreakerState::Closed);
+        assert_eq!(status.consecutive_failures, 2);
+
+        provider.record_failure(&server_error());
+        let status = provider.status();
+        assert_eq!(status.breaker, BreakerState::Open);
+        assert_eq!(status.resumes_in_secs, Some(600));
+        assert_eq!(status.failures, 3);
+
+        provider.record_success();
+        let status = provider.status();
+        assert_eq!(status.breaker, BreakerState::Closed);
+        assert_eq!(status.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn breaker_half_opens_once_the_cooldown_elapses() {
+        let provider = provider(ProviderSettings {
+            breaker_failure_threshold: 1,
+            breaker_cooldown_secs: 0,
+            ..ProviderSettings::default()
+        });
+
+        provider.record_failure(&server_error());
+        assert_eq!(provider.status().breaker, BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn retry_after_pauses_right_away() {
+        let provider = provider(ProviderSettings::default());
+
+        provider.record_failure(&Error::RateLimited {
+            status: 429,
+            retry_after: Some(Duration::from_secs(120)),
+        });
+        let status = provider.status();
+        assert_eq!(status.breaker, BreakerState::Open);
+        assert_eq!(status.resumes_in_secs, Some(120));
+    }
+
+    #[test]
+    fn takes_limits_from_the_settings() {
+        let provider = provider(ProviderSettings {
+            rate_limit_burst: 2,
+            ..ProviderSettings::default()
+        });
+        assert_eq!(provider.status().tokens_available, 2);
+    }
+}
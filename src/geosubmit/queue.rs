@@ -0,0 +1,795 @@
+//! Durable on-disk queue of assembled geosubmit payloads
+//!
+//! Every payload is written to its own file before any upload is attempted,
+//! so submissions survive restarts and offline periods. Entries that can't be
+//! parsed, or that the provider rejects as invalid, are moved to a quarantine
+//! directory instead of being retried, as are entries that have failed to deliver
+//! too many times or for too long.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::clock::now_millis;
+use crate::config::{
+    QUEUE_POLL_SECS, QUEUE_RETRY_BASE_SECS, QUEUE_RETRY_MAX_SECS, SubmissionSettings,
+};
+use crate::error::{Error, ErrorCategory, Result};
+use crate::metrics::metrics;
+
+use super::http::HttpClient;
+use super::payload::items;
+use super::privacy::PrivacyPolicy;
+use super::provider::Provider;
+
+const ENTRY_EXTENSION: &str = "json";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// A payload waiting to be uploaded
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedSubmission {
+    pub id: String,
+    /// Milliseconds since Unix epoch
+    pub enqueued_at: i64,
+    /// Not uploaded before this time, in milliseconds since Unix epoch
+    #[serde(default)]
+    pub not_before: i64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub payload: items,
+}
+
+/// Summary of a queued entry, without the payload itself
+#[derive(Serialize, Debug, Clone)]
+pub struct QueueEntrySummary {
+    pub id: String,
+    pub enqueued_at: i64,
+    pub not_before: i64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub wifi_access_points: usize,
+    pub bluetooth_beacons: usize,
+}
+
+impl From<&QueuedSubmission> for QueueEntrySummary {
+    fn from(entry: &QueuedSubmission) -> Self {
+        QueueEntrySummary {
+            id: entry.id.clone(),
+            enqueued_at: entry.enqueued_at,
+            not_before: entry.not_before,
+            attempts: entry.attempts,
+            last_error: entry.last_error.clone(),
+            wifi_access_points: entry.payload.wifiAccessPoints.len(),
+            bluetooth_beacons: entry.payload.bluetoothBeacons.len(),
+        }
+    }
+}
+
+/// Snapshot of the queue, as reported by the HTTP API
+#[derive(Serialize, Debug, Clone)]
+pub struct QueueStatus {
+    pub pending: usize,
+    pub quarantined: usize,
+    pub entries: Vec<QueueEntrySummary>,
+}
+
+/// Files are only read when an entry is due for upload: everything else is answered
+/// from an in-memory index, built once when the queue is opened. Reads and writes
+/// block on the disk, so callers on the async runtime should use the blocking pool.
+pub struct SubmissionQueue {
+    dir: PathBuf,
+    quarantine_dir: PathBuf,
+    /// Entries by id, which is also age order
+    index: Mutex<BTreeMap<String, QueueEntrySummary>>,
+    quarantined: AtomicUsize,
+    max_attempts: u32,
+    max_age_millis: i64,
+    sequence: AtomicU64,
+    notify: Notify,
+}
+
+impl SubmissionQueue {
+    /// Open (or create) a queue rooted at `dir`, indexing the entries already in it
+    pub fn open(dir: PathBuf, settings: &SubmissionSettings) -> Result<Self> {
+        let quarantine_dir = dir.join("quarantine");
+        fs::create_dir_all(&dir)?;
+        fs::create_dir_all(&quarantine_dir)?;
+
+        let queue = SubmissionQueue {
+            quarantined: AtomicUsize::new(fs::read_dir(&quarantine_dir)?.count()),
+            dir,
+            quarantine_dir,
+            index: Mutex::new(BTreeMap::new()),
+            max_attempts: settings.max_attempts,
+            max_age_millis: (settings.max_age_days as i64).saturating_mul(24 * 60 * 60 * 1000),
+            sequence: AtomicU64::new(0),
+            notify: Notify::new(),
+        };
+
+        // a leftover temp file means we crashed mid-write
+        for path in queue.files_with_extension(TEMP_EXTENSION)? {
+            queue.quarantine(&path, "incomplete write");
+        }
+
+        for path in queue.files_with_extension(ENTRY_EXTENSION)? {
+            match read_entry(&path) {
+                Ok(entry) => {
+                    queue.index().insert(entry.id.clone(), (&entry).into());
+                }
+                Err(e) => queue.quarantine(&path, &e.to_string()),
+            }
+        }
+
+        Ok(queue)
+    }
+
+    /// Persist a payload and wake the worker
+    pub fn enqueue(&self, payload: items) -> Result<QueuedSubmission> {
+        self.enqueue_until(payload, 0)
+    }
+
+    /// Persist a payload that mustn't be uploaded before `not_before`, in
+    /// milliseconds since the Unix epoch
+    pub fn enqueue_until(&self, payload: items, not_before: i64) -> Result<QueuedSubmission> {
+        let enqueued_at = now_millis();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let entry = QueuedSubmission {
+            id: format!("{:013}-{:06}", enqueued_at, sequence),
+            enqueued_at,
+            not_before: not_before.max(enqueued_at),
+            attempts: 0,
+            last_error: None,
+            payload,
+        };
+
+        self.write(&entry)?;
+        self.notify.notify_one();
+
+        tracing::info!("[Queue] Enqueued submission {}", entry.id);
+        Ok(entry)
+    }
+
+    /// Load up to `limit` entries, oldest first, quarantining any that are corrupt
+    pub fn pending(&self, limit: usize) -> Result<Vec<QueuedSubmission>> {
+        self.load(limit, |_| true)
+    }
+
+    /// Like `pending`, but only entries whose hold-back delay has passed
+    pub fn due(&self, limit: usize) -> Result<Vec<QueuedSubmission>> {
+        let now = now_millis();
+        self.load(limit, |entry| entry.not_before <= now)
+    }
+
+    /// How long until the earliest held-back entry may be uploaded, if any is waiting
+    pub fn next_due_in(&self) -> Option<Duration> {
+        let now = now_millis();
+        self.index()
+            .values()
+            .map(|entry| entry.not_before)
+            .min()
+            .map(|not_before| Duration::from_millis(not_before.saturating_sub(now).max(0) as u64))
+    }
+
+    fn load(
+        &self,
+        limit: usize,
+        include: impl Fn(&QueueEntrySummary) -> bool,
+    ) -> Result<Vec<QueuedSubmission>> {
+        let ids = self
+            .index()
+            .values()
+            .filter(|entry| include(entry))
+            .take(limit)
+            .map(|entry| entry.id.clone())
+            .collect::<Vec<_>>();
+
+        let mut entries = Vec::new();
+        for id in ids {
+            let path = self.entry_path(&id);
+            match read_entry(&path) {
+                Ok(entry) => entries.push(entry),
+                // delivered or quarantined since the index was read
+                Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    self.index().remove(&id);
+                    self.quarantine(&path, &e.to_string());
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Remove an entry after it has been delivered
+    pub fn remove(&self, id: &str) -> Result<()> {
+        self.index().remove(id);
+        match fs::remove_file(self.entry_path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a failed delivery attempt against an entry, quarantining it once it has
+    /// used up its attempts or grown too old to keep retrying
+    pub fn record_failure(&self, entry: &mut QueuedSubmission, error: &str) -> Result<()> {
+        entry.attempts += 1;
+        entry.last_error = Some(error.to_string());
+
+        if self.max_attempts > 0 && entry.attempts >= self.max_attempts {
+            let reason = format!("gave up after {} attempts: {}", entry.attempts, error);
+            self.reject(entry, &reason);
+            Ok(())
+        } else if self.max_age_millis > 0 && now_millis() - entry.enqueued_at >= self.max_age_millis
+        {
+            self.reject(
+                entry,
+                &format!("gave up on an expired submission: {}", error),
+            );
+            Ok(())
+        } else {
+            self.write(entry)
+        }
+    }
+
+    /// Move an entry the provider permanently rejected into quarantine
+    pub fn reject(&self, entry: &QueuedSubmission, reason: &str) {
+        let mut entry = entry.clone();
+        entry.last_error = Some(reason.to_string());
+
+        // persist the reason alongside the entry before moving it aside
+        if let Err(e) = self.write(&entry) {
+            tracing::error!("[Queue] Failed to update {}: {}", entry.id, e);
+        }
+        self.index().remove(&entry.id);
+        self.quarantine(&self.entry_path(&entry.id), reason);
+    }
+
+    /// Number of entries waiting to be uploaded
+    pub fn len(&self) -> usize {
+        self.index().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of entries moved aside because they couldn't be read or were rejected
+    pub fn quarantined(&self) -> usize {
+        self.quarantined.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let entries = self.index().values().cloned().collect::<Vec<_>>();
+
+        QueueStatus {
+            pending: entries.len(),
+            quarantined: self.quarantined(),
+            entries,
+        }
+    }
+
+    /// Wait until something is enqueued
+    pub async fn notified(&self) {
+        self.notify.notified().await
+    }
+
+    fn index(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, QueueEntrySummary>> {
+        self.index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn entry_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id).with_extension(ENTRY_EXTENSION)
+    }
+
+    /// Write via a temp file and rename, so a crash never leaves a half-written entry
+    fn write(&self, entry: &QueuedSubmission) -> Result<()> {
+        let path = self.entry_path(&entry.id);
+        let temp_path = path.with_extension(TEMP_EXTENSION);
+
+        let file = fs::File::create(&temp_path)?;
+        serde_json::to_writer(&file, entry)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+
+        self.index().insert(entry.id.clone(), entry.into());
+        Ok(())
+    }
+
+    fn files_with_extension(&self, extension: &str) -> Result<Vec<PathBuf>> {
+        let mut files = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == extension))
+            .collect::<Vec<_>>();
+
+        // ids start with the enqueue time, so name order is age order
+        files.sort();
+        Ok(files)
+    }
+
+    fn quarantine(&self, path: &Path, reason: &str) {
+        let Some(file_name) = path.file_name() else {
+            return;
+        };
+
+        tracing::warn!(
+            "[Queue] Quarantining {}: {}",
+            file_name.to_string_lossy(),
+            reason
+        );
+
+        match fs::rename(path, self.quarantine_dir.join(file_name)) {
+            Ok(()) => {
+                self.quarantined.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                tracing::error!("[Queue] Failed to quarantine {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+fn read_entry(path: &Path) -> Result<QueuedSubmission> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Run queue operations on the blocking pool, since they read and sync entry files
+async fn blocking<T: Send + 'static>(
+    queue: &Arc<SubmissionQueue>,
+    operation: impl FnOnce(&SubmissionQueue) -> T + Send + 'static,
+) -> Result<T> {
+    let queue = Arc::clone(queue);
+    tokio::task::spawn_blocking(move || operation(&queue))
+        .await
+        .map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Upload queued payloads in batches, backing off while the provider is unreachable
+pub async fn run_queue_worker(
+    queue: Arc<SubmissionQueue>,
+    http: HttpClient,
+    provider: Arc<Provider>,
+    settings: SubmissionSettings,
+    privacy: PrivacyPolicy,
+) {
+    let mut consecutive_failures: u32 = 0;
+    // after a batch is rejected as invalid, send its entries one at a time to find the culprit
+    let mut isolate_remaining: usize = 0;
+
+    loop {
+        let batch_limit = if isolate_remaining > 0 {
+            1
+        } else {
+            settings.batch_max_items.max(1)
+        };
+
+        // with delayed submissions, shuffle everything released in the window, not one batch
+        let load_limit = if privacy.delay_submissions {
+            usize::MAX
+        } else {
+            batch_limit
+        };
+        let mut batch = match blocking(&queue, move |queue| queue.due(load_limit)).await {
+            Ok(Ok(mut due)) => {
+                privacy.shuffle(&mut due);
+                due.truncate(batch_limit);
+                limit_batch_size(due, settings.batch_max_bytes)
+            }
+            Ok(Err(e)) | Err(e) => {
+                tracing::error!("[Queue] Failed to read queue: {}", e);
+                Vec::new()
+            }
+        };
+
+        if batch.is_empty() {
+            isolate_remaining = 0;
+            // wake when the next held-back entry is due, not up to a poll interval later
+            let poll = Duration::from_secs(QUEUE_POLL_SECS);
+            let wait = queue.next_due_in().map_or(poll, |due_in| due_in.min(poll));
+            tokio::select! {
+                _ = queue.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+            continue;
+        }
+
+        provider.wait_ready().await;
+
+        let payloads = batch
+            .iter()
+            .map(|entry| {
+                let mut payload = entry.payload.clone();
+                privacy.apply(&mut payload);
+                payload
+            })
+            .collect();
+        let result = super::client::submit_geo_payload(&http, &provider, payloads).await;
+        isolate_remaining = isolate_remaining.saturating_sub(1);
+
+        match result {
+            Ok(()) => {
+                provider.record_success();
+                provider.record_delivery(batch.len());
+                metrics().record_submissions(&provider.name, "delivered", batch.len());
+                consecutive_failures = 0;
+                let delivered = batch.len();
+                let removed = blocking(&queue, move |queue| {
+                    for entry in &batch {
+                        if let Err(e) = queue.remove(&entry.id) {
+                            tracing::error!("[Queue] Failed to remove {}: {}", entry.id, e);
+                        }
+                    }
+                })
+                .await;
+                if let Err(e) = removed {
+                    tracing::error!("[Queue] Failed to remove delivered submissions: {}", e);
+                }
+                tracing::info!("[Queue] Delivered {} queued submission(s)", delivered);
+            }
+            // the provider is fine, but didn't like what we sent
+            Err(e) if e.category() == ErrorCategory::Validation => {
+                provider.record_success();
+                consecutive_failures = 0;
+
+                if let [entry] = batch.as_slice() {
+                    metrics().record_submissions(&provider.name, "rejected", 1);
+                    let (entry, reason) = (entry.clone(), e.to_string());
+                    if let Err(e) =
+                        blocking(&queue, move |queue| queue.reject(&entry, &reason)).await
+                    {
+                        tracing::error!(
+                            "[Queue] Failed to quarantine a rejected submission: {}",
+                            e
+                        );
+                    }
+                } else {
+                    tracing::warn!(
+                        "[Queue] Batch of {} rejected ({}); retrying entries individually",
+                        batch.len(),
+                        e
+                    );
+                    isolate_remaining = batch.len();
+                }
+            }
+            // too large for the provider, though not invalid: send the entries one at a time
+            Err(Error::HttpStatus { status: 413, .. }) if batch.len() > 1 => {
+                provider.record_success();
+                consecutive_failures = 0;
+                tracing::warn!(
+                    "[Queue] Batch of {} too large for the provider; retrying entries individually",
+                    batch.len()
+                );
+                isolate_remaining = batch.len();
+            }
+            Err(e) => {
+                consecutive_failures = consecutive_failures.saturating_add(1);
+                let retry_after = e.retry_after();
+                provider.record_failure(&e);
+                metrics().record_submissions(&provider.name, "failed", batch.len());
+
+                let error = e.to_string();
+                let failed = batch.len();
+                let recorded = blocking(&queue, {
+                    let error = error.clone();
+                    move |queue| {
+                        for entry in batch.iter_mut() {
+                            if let Err(e) = queue.record_failure(entry, &error) {
+                                tracing::error!("[Queue] Failed to update {}: {}", entry.id, e);
+                            }
+                        }
+                    }
+                })
+                .await;
+                if let Err(e) = recorded {
+                    tracing::error!("[Queue] Failed to record the failed delivery: {}", e);
+                }
+
+                let delay = if retry_after.is_some() {
+                    // the provider told us when to come back; wait_ready() handles that
+                    Duration::ZERO
+                } else if e.is_retryable() {
+                    retry_delay(consecutive_failures)
+                } else {
+                    // e.g. a TLS or endpoint misconfiguration: keep the data, but don't hammer
+                    Duration::from_secs(QUEUE_RETRY_MAX_SECS)
+                };
+                tracing::warn!(
+                    "[Queue] Delivery failed ({:?}: {}); retrying {} submission(s) in {:?}",
+                    e.category(),
+                    error,
+                    failed,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Trim a batch so its serialized items fit within `max_bytes`, keeping at least one
+fn limit_batch_size(batch: Vec<QueuedSubmission>, max_bytes: usize) -> Vec<QueuedSubmission> {
+    let mut total_bytes = 0;
+
+    batch
+        .into_iter()
+        .enumerate()
+        .take_while(|(index, entry)| {
+            total_bytes += serde_json::to_vec(&entry.payload)
+                .map(|bytes| bytes.len())
+                .unwrap_or(0);
+            *index == 0 || total_bytes <= max_bytes
+        })
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+/// Exponential backoff: base * 2^(failures - 1), capped
+fn retry_delay(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    let secs = QUEUE_RETRY_BASE_SECS.saturating_mul(1 << exponent);
+    Duration::from_secs(secs.min(QUEUE_RETRY_MAX_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HttpSettings, ProviderSettings};
+    use crate::test_support::{self, payload, position};
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use tokio::sync::mpsc;
+
+    /// A provider on loopback, passing each uploaded document on
+    async fn provider() -> (Arc<Provider>, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let router = Router::new()
+            .route(
+                "/v2/geosubmit",
+                post(
+                    |State(sender): State<mpsc::UnboundedSender<serde_json::Value>>,
+                     Json(document): Json<serde_json::Value>| async move {
+                        sender.send(document).unwrap();
+                    },
+                ),
+            )
+            .with_state(sender);
+        let address = test_support::serve(router).await;
+        let endpoint = format!("http://{}/v2/geosubmit", address);
+        (
+            Arc::new(Provider::new(
+                "test",
+                &endpoint,
+                false,
+                &ProviderSettings::default(),
+            )),
+            receiver,
+        )
+    }
+
+    fn timestamps(document: &serde_json::Value) -> Vec<u64> {
+        document["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["timestamp"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn releases_delayed_entries_together_shuffled() {
+        let queue = Arc::new(
+            SubmissionQueue::open(
+                test_support::temp_dir("queue"),
+                &SubmissionSettings::default(),
+            )
+            .unwrap(),
+        );
+        let privacy = PrivacyPolicy {
+            delay_submissions: true,
+            min_delay_secs: 0,
+            max_delay_secs: 0,
+            flush_interval_secs: 1,
+            ..PrivacyPolicy::default()
+        };
+
+        // start just after a window opens, so every entry lands in the same one
+        let into_window = crate::clock::now_millis().rem_euclid(1000) as u64;
+        tokio::time::sleep(Duration::from_millis(1010 - into_window)).await;
+        let mut not_before = Vec::new();
+        for timestamp in 1..=8 {
+            let entry = queue
+                .enqueue_until(
+                    payload(timestamp, position(52.0, 13.0), &[1]),
+                    privacy.release_time(),
+                )
+                .unwrap();
+            not_before.push(entry.not_before);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(not_before.iter().all(|time| *time == not_before[0]));
+
+        let (provider, mut uploads) = provider().await;
+        let http = HttpClient::from_settings(&HttpSettings::default()).unwrap();
+        let worker = tokio::spawn(run_queue_worker(
+            Arc::clone(&queue),
+            http,
+            provider,
+            SubmissionSettings::default(),
+            privacy,
+        ));
+
+        let document = tokio::time::timeout(Duration::from_secs(5), uploads.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        worker.abort();
+
+        let uploaded = timestamps(&document);
+        let mut sorted = uploaded.clone();
+        sorted.sort();
+        assert_eq!(sorted, (1..=8).collect::<Vec<_>>());
+        // one in 8! that a shuffle keeps the queued order
+        assert_ne!(uploaded, sorted);
+    }
+
+    fn open(dir: &Path, settings: &SubmissionSettings) -> SubmissionQueue {
+        SubmissionQueue::open(dir.to_path_buf(), settings).unwrap()
+    }
+
+    #[test]
+    fn recovers_entries_after_a_restart() {
+        let dir = test_support::temp_dir("queue");
+        let settings = SubmissionSettings::default();
+        let queue = open(&dir, &settings);
+        let first = queue
+            .enqueue(payload(1, position(52.0, 13.0), &[1, 2]))
+            .unwrap();
+        let held = queue
+            .enqueue_until(
+                payload(2, position(52.0, 13.0), &[3]),
+                now_millis() + 60_000,
+            )
+            .unwrap();
+        assert_eq!(queue.len(), 2);
+        let status = queue.status();
+        assert_eq!(status.entries[0].id, first.id);
+        assert_eq!(status.entries[0].wifi_access_points, 2);
+        drop(queue);
+
+        let queue = open(&dir, &settings);
+        assert_eq!(queue.len(), 2);
+        let due = queue.due(usize::MAX).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].id, first.id);
+        assert!(queue.next_due_in().unwrap() <= Duration::from_secs(60));
+
+        queue.remove(&first.id).unwrap();
+        let queue = open(&dir, &settings);
+        let pending = queue.pending(usize::MAX).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, held.id);
+    }
+
+    #[test]
+    fn quarantines_corrupt_and_half_written_entries() {
+        let dir = test_support::temp_dir("queue");
+        fs::write(dir.join("0000000000001-000000.json"), "{\"id\":").unwrap();
+        fs::write(dir.join("0000000000002-000000.tmp"), "{}").unwrap();
+
+        let queue = open(&dir, &SubmissionSettings::default());
+        assert!(queue.is_empty());
+        assert_eq!(queue.quarantined(), 2);
+        assert!(dir.join("quarantine/0000000000001-000000.json").exists());
+
+        // still counted after a restart
+        assert_eq!(open(&dir, &SubmissionSettings::default()).quarantined(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_the_attempt_limit() {
+        let dir = test_support::temp_dir("queue");
+        let settings = SubmissionSettings {
+            max_attempts: 3,
+            ..SubmissionSettings::default()
+        };
+        let queue = open(&dir, &settings);
+        let mut entry = queue
+            .enqueue(payload(1, position(52.0, 13.0), &[1]))
+            .unwrap();
+
+        for _ in 0..2 {
+            queue
+                .record_failure(&mut entry, "connection refused")
+                .unwrap();
+        }
+        assert_eq!(queue.status().entries[0].attempts, 2);
+        assert_eq!(open(&dir, &settings).pending(1).unwrap()[0].attempts, 2);
+
+        queue
+            .record_failure(&mut entry, "connection refused")
+            .unwrap();
+        assert!(queue.is_empty());
+        assert_eq!(queue.quarantined(), 1);
+        let quarantined = read_entry(
+            &dir.join("quarantine")
+                .join(&entry.id)
+                .with_extension("json"),
+        )
+        .unwrap();
+        assert_eq!(
+            quarantined.last_error.as_deref(),
+            Some("gave up after 3 attempts: connection refused")
+        );
+    }
+
+    #[test]
+    fn gives_up_on_expired_entries() {
+        let dir = test_support::temp_dir("queue");
+        let settings = SubmissionSettings {
+            max_age_days: 1,
+            ..SubmissionSettings::default()
+        };
+        let queue = open(&dir, &settings);
+        let mut fresh = queue
+            .enqueue(payload(1, position(52.0, 13.0), &[1]))
+            .unwrap();
+        let mut expired = fresh.clone();
+        expired.id = "0000000000001-000000".to_string();
+        expired.enqueued_at -= 24 * 60 * 60 * 1000;
+        queue.write(&expired).unwrap();
+
+        queue.record_failure(&mut fresh, "timed out").unwrap();
+        queue.record_failure(&mut expired, "timed out").unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.status().entries[0].id, fresh.id);
+        assert_eq!(queue.quarantined(), 1);
+    }
+
+    #[test]
+    fn splits_batches_at_the_size_limit() {
+        let queue = open(
+            &test_support::temp_dir("queue"),
+            &SubmissionSettings::default(),
+        );
+        for timestamp in 1..=5 {
+            queue
+                .enqueue(payload(timestamp, position(52.0, 13.0), &[1, 2, 3]))
+                .unwrap();
+        }
+        let due = queue.due(usize::MAX).unwrap();
+        let size = serde_json::to_vec(&due[0].payload).unwrap().len();
+
+        let batch = limit_batch_size(due.clone(), size * 5 / 2);
+        let ids = batch.iter().map(|entry| &entry.id).collect::<Vec<_>>();
+        assert_eq!(ids, [&due[0].id, &due[1].id]);
+        assert_eq!(limit_batch_size(due.clone(), size * 5).len(), 5);
+        // one entry always goes out, even if it's over the limit on its own
+        assert_eq!(limit_batch_size(due, 1).len(), 1);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_a_cap() {
+        assert_eq!(retry_delay(1), Duration::from_secs(QUEUE_RETRY_BASE_SECS));
+        assert_eq!(
+            retry_delay(2),
+            Duration::from_secs(2 * QUEUE_RETRY_BASE_SECS)
+        );
+        assert_eq!(
+            retry_delay(4),
+            Duration::from_secs(8 * QUEUE_RETRY_BASE_SECS)
+        );
+        assert_eq!(retry_delay(40), Duration::from_secs(QUEUE_RETRY_MAX_SECS));
+        assert_eq!(
+            retry_delay(u32::MAX),
+            Duration::from_secs(QUEUE_RETRY_MAX_SECS)
+        );
+    }
+}
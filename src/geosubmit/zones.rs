@@ -0,0 +1,365 @@
+//! Privacy exclusion zones: places where nothing is submitted
+//!
+//! Observations made inside a zone are never queued. Every access point and
+//! beacon seen from inside a zone is remembered, and stripped from submissions
+//! made anywhere else, so a home network isn't mapped from the street either.
+
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::Result;
+use crate::scanner::{BleDevice, WifiBssid};
+
+use super::payload::{Position, haversine_meters, items};
+
+/// Tolerance for treating a point as on a polygon edge, in degrees (~1 cm)
+const EDGE_EPSILON_DEGREES: f64 = 1e-7;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExclusionZone {
+    Circle {
+        #[serde(default)]
+        name: Option<String>,
+        latitude: f64,
+        longitude: f64,
+        radius_meters: f64,
+    },
+    /// Vertices as `[latitude, longitude]` pairs; the ring closes itself.
+    /// A polygon may cross the antimeridian but must span less than 180° of longitude.
+    Polygon {
+        #[serde(default)]
+        name: Option<String>,
+        points: Vec<[f64; 2]>,
+    },
+}
+
+impl ExclusionZone {
+    /// Whether a position is inside the zone; points on the boundary count as inside
+    pub fn contains(&self, position: &Position) -> bool {
+        match self {
+            ExclusionZone::Circle {
+                latitude,
+                longitude,
+                radius_meters,
+                ..
+            } => {
+                haversine_meters(*latitude, *longitude, position.latitude, position.longitude)
+                    <= *radius_meters
+            }
+            ExclusionZone::Polygon { points, .. } => {
+                polygon_contains(points, position.latitude, position.longitude)
+            }
+        }
+    }
+}
+
+/// Ray casting, with longitudes unwrapped around the first vertex so that
+/// polygons crossing the antimeridian work
+fn polygon_contains(points: &[[f64; 2]], latitude: f64, longitude: f64) -> bool {
+    if points.len() < 3 {
+        return false;
+    }
+
+    let reference = points[0][1];
+    let ring = points
+        .iter()
+        .map(|[lat, lon]| (*lat, reference + wrap_longitude(lon - reference)))
+        .collect::<Vec<_>>();
+    let (y, x) = (latitude, reference + wrap_longitude(longitude - reference));
+
+    let mut inside = false;
+    for (i, &(y1, x1)) in ring.iter().enumerate() {
+        let (y2, x2) = ring[(i + 1) % ring.len()];
+
+        if on_segment((y, x), (y1, x1), (y2, x2)) {
+            return true;
+        }
+
+        if (y1 > y) != (y2 > y) {
+            let crossing_x = x1 + (y - y1) * (x2 - x1) / (y2 - y1);
+            if x < crossing_x {
+                inside = !inside;
+            }
+        }
+    }
+
+    inside
+}
+
+fn on_segment(point: (f64, f64), a: (f64, f64), b: (f64, f64)) -> bool {
+    let cross = (b.0 - a.0) * (point.1 - a.1) - (b.1 - a.1) * (point.0 - a.0);
+    let length = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+    if length == 0.0 {
+        return (point.0 - a.0).abs() <= EDGE_EPSILON_DEGREES
+            && (point.1 - a.1).abs() <= EDGE_EPSILON_DEGREES;
+    }
+
+    cross.abs() / length <= EDGE_EPSILON_DEGREES
+        && point.0 >= a.0.min(b.0) - EDGE_EPSILON_DEGREES
+        && point.0 <= a.0.max(b.0) + EDGE_EPSILON_DEGREES
+        && point.1 >= a.1.min(b.1) - EDGE_EPSILON_DEGREES
+        && point.1 <= a.1.max(b.1) + EDGE_EPSILON_DEGREES
+}
+
+/// Normalise a longitude difference into [-180, 180)
+fn wrap_longitude(delta: f64) -> f64 {
+    (delta + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// What the zone check did to a payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneVerdict {
+    /// Outside every zone; `removed` observations were of emitters seen inside one
+    Allowed { removed: usize },
+    /// Inside a zone: the payload must be dropped
+    Suppressed,
+}
+
+/// Counts of what the zones kept back, as reported by `/status`
+#[derive(Serialize, Debug, Clone)]
+pub struct ZoneStats {
+    pub zones: usize,
+    pub suppressed_submissions: u64,
+    pub suppressed_observations: u64,
+    pub excluded_emitters: usize,
+}
+
+pub struct ZoneGuard {
+    zones: Vec<ExclusionZone>,
+    excluded_path: PathBuf,
+    excluded: Mutex<HashSet<BDAddr>>,
+    suppressed_submissions: AtomicU64,
+    suppressed_observations: AtomicU64,
+}
+
+impl ZoneGuard {
+    /// Load the set of emitters previously seen inside a zone from `excluded_path`
+    pub fn open(zones: Vec<ExclusionZone>, excluded_path: PathBuf) -> Result<Self> {
+        let excluded = match fs::read(&excluded_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(ZoneGuard {
+            zones,
+            excluded_path,
+            excluded: Mutex::new(excluded),
+            suppressed_submissions: AtomicU64::new(0),
+            suppressed_observations: AtomicU64::new(0),
+        })
+    }
+
+    pub fn is_inside_any(&self, position: &Position) -> bool {
+        self.zones.iter().any(|zone| zone.contains(position))
+    }
+
+    /// Check a payload against the zones, stripping any excluded emitters from it.
+    /// A dry run still excludes emitters seen inside a zone from later payloads,
+    /// but doesn't save them.
+    pub fn screen(&self, payload: &mut items, dry_run: bool) -> Result<ZoneVerdict> {
+        let cell_towers = payload.CellTowers.as_ref().map_or(0, Vec::len);
+        self.screen_scan(
+            Some(&payload.position),
+            &mut payload.wifiAccessPoints,
+            &mut payload.bluetoothBeacons,
+            cell_towers,
+            dry_run,
+        )
+    }
+
+    /// `screen` for observations that may not have a position yet. Without one,
+    /// only the excluded emitters are stripped.
+    pub fn screen_scan(
+        &self,
+        position: Option<&Position>,
+        access_points: &mut Vec<WifiBssid>,
+        beacons: &mut Vec<BleDevice>,
+        cell_towers: usize,
+        dry_run: bool,
+    ) -> Result<ZoneVerdict> {
+        let mut excluded = self.excluded.lock().unwrap();
+
+        if position.is_some_and(|position| self.is_inside_any(position)) {
+            let before = excluded.len();
+            excluded.extend(access_points.iter().map(|ap| ap.bssid));
+            excluded.extend(beacons.iter().map(|b| b.mac_address));
+            if excluded.len() != before && !dry_run {
+                self.save(&excluded)?;
+            }
+
+            let observations = access_points.len() + beacons.len() + cell_towers;
+            self.suppressed_submissions.fetch_add(1, Ordering::Relaxed);
+            self.suppressed_observations
+                .fetch_add(observations as u64, Ordering::Relaxed);
+
+            return Ok(ZoneVerdict::Suppressed);
+        }
+
+        let before = access_points.len() + beacons.len();
+        access_points.retain(|ap| !excluded.contains(&ap.bssid));
+        beacons.retain(|b| !excluded.contains(&b.mac_address));
+        let removed = before - access_points.len() - beacons.len();
+
+        self.suppressed_observations
+            .fetch_add(removed as u64, Ordering::Relaxed);
+        Ok(ZoneVerdict::Allowed { removed })
+    }
+
+    /// Write via a temp file and rename, so a crash never leaves a half-written set
+    fn save(&self, excluded: &HashSet<BDAddr>) -> Result<()> {
+        let temp_path = self.excluded_path.with_extension("json.tmp");
+
+        let file = fs::File::create(&temp_path)?;
+        serde_json::to_writer(&file, excluded)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.excluded_path)?;
+
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ZoneStats {
+        ZoneStats {
+            zones: self.zones.len(),
+            suppressed_submissions: self.suppressed_submissions.load(Ordering::Relaxed),
+            suppressed_observations: self.suppressed_observations.load(Ordering::Relaxed),
+            excluded_emitters: self.excluded.lock().unwrap().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, access_point, payload, position};
+
+    fn circle(latitude: f64, longitude: f64, radius_meters: f64) -> ExclusionZone {
+        ExclusionZone::Circle {
+            name: None,
+            latitude,
+            longitude,
+            radius_meters,
+        }
+    }
+
+    fn polygon(points: &[[f64; 2]]) -> ExclusionZone {
+        ExclusionZone::Polygon {
+            name: None,
+            points: points.to_vec(),
+        }
+    }
+
+    #[test]
+    fn circle_includes_its_edge() {
+        let edge = position(52.001, 13.0);
+        let radius = haversine_meters(52.0, 13.0, edge.latitude, edge.longitude);
+
+        assert!(circle(52.0, 13.0, radius).contains(&edge));
+        assert!(!circle(52.0, 13.0, radius - 0.01).contains(&edge));
+        assert!(circle(52.0, 13.0, radius).contains(&position(52.0, 13.0)));
+    }
+
+    #[test]
+    fn polygon_includes_its_vertices_and_edges() {
+        let square = polygon(&[[52.0, 13.0], [52.0, 13.1], [52.1, 13.1], [52.1, 13.0]]);
+
+        for [latitude, longitude] in [[52.0, 13.0], [52.0, 13.1], [52.1, 13.1], [52.1, 13.0]] {
+            assert!(square.contains(&position(latitude, longitude)));
+        }
+        // midpoints of the bottom, right, top and left edges
+        for [latitude, longitude] in [[52.0, 13.05], [52.05, 13.1], [52.1, 13.05], [52.05, 13.0]] {
+            assert!(square.contains(&position(latitude, longitude)));
+        }
+
+        assert!(square.contains(&position(52.05, 13.05)));
+        assert!(!square.contains(&position(51.9999, 13.05)));
+        assert!(!square.contains(&position(52.05, 13.1001)));
+        assert!(!square.contains(&position(52.1001, 13.1001)));
+    }
+
+    #[test]
+    fn polygon_includes_edges_of_concave_rings() {
+        // an L shape: the notch's corner is a reflex vertex
+        let shape = polygon(&[
+            [0.0, 0.0],
+            [0.0, 2.0],
+            [1.0, 2.0],
+            [1.0, 1.0],
+            [2.0, 1.0],
+            [2.0, 0.0],
+        ]);
+
+        assert!(shape.contains(&position(1.0, 1.0)));
+        assert!(shape.contains(&position(1.0, 1.5)));
+        assert!(shape.contains(&position(0.5, 1.5)));
+        assert!(!shape.contains(&position(1.5, 1.5)));
+    }
+
+    #[test]
+    fn polygon_may_cross_the_antimeridian() {
+        let pacific = polygon(&[
+            [-10.0, 170.0],
+            [-10.0, -170.0],
+            [10.0, -170.0],
+            [10.0, 170.0],
+        ]);
+
+        for longitude in [175.0, 180.0, -180.0, -175.0, 170.0, -170.0] {
+            assert!(pacific.contains(&position(0.0, longitude)), "{}", longitude);
+        }
+        for longitude in [165.0, -165.0, 0.0] {
+            assert!(
+                !pacific.contains(&position(0.0, longitude)),
+                "{}",
+                longitude
+            );
+        }
+        assert!(!pacific.contains(&position(11.0, 180.0)));
+    }
+
+    #[test]
+    fn degenerate_polygons_contain_nothing() {
+        assert!(!polygon(&[[0.0, 0.0], [1.0, 1.0]]).contains(&position(0.0, 0.0)));
+    }
+
+    #[test]
+    fn remembers_emitters_seen_inside_and_strips_them_elsewhere() {
+        let dir = test_support::temp_dir("zones");
+        let path = dir.join("excluded_emitters.json");
+        let zones = vec![circle(52.0, 13.0, 100.0)];
+        let guard = ZoneGuard::open(zones.clone(), path.clone()).unwrap();
+
+        let mut home = payload(0, position(52.0, 13.0), &[1, 2]);
+        assert_eq!(
+            guard.screen(&mut home, false).unwrap(),
+            ZoneVerdict::Suppressed
+        );
+        assert!(path.exists());
+        assert!(!path.with_extension("json.tmp").exists());
+
+        // reloaded from disk, as after a restart
+        let guard = ZoneGuard::open(zones, path).unwrap();
+        let mut street = payload(0, position(52.01, 13.0), &[1, 3]);
+        assert_eq!(
+            guard.screen(&mut street, false).unwrap(),
+            ZoneVerdict::Allowed { removed: 1 }
+        );
+        assert_eq!(
+            street
+                .wifiAccessPoints
+                .iter()
+                .map(|ap| ap.bssid)
+                .collect::<Vec<_>>(),
+            vec![access_point(3).bssid]
+        );
+        assert_eq!(guard.stats().excluded_emitters, 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
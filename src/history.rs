@@ -0,0 +1,767 @@
+//! Local SQLite history of everything we've observed
+//!
+//! Each accepted submission is stored with its position and every observation,
+//! alongside the learned per-emitter aggregates. The schema is versioned with
+//! `PRAGMA user_version` and migrated forward on open.
+
+use btleplug::api::BDAddr;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, Transaction, params};
+use serde::{Serialize, de::DeserializeOwned};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::clock::now_millis;
+use crate::config::HistorySettings;
+use crate::error::{Error, Result};
+use crate::geosubmit::emitters::{EmitterAggregate, LearnedEmitter};
+use crate::geosubmit::{CellTower, Position, items};
+use crate::scanner::{BleDevice, WifiBssid};
+
+/// Schema migrations; migration `i` upgrades the database from version `i` to `i + 1`
+const MIGRATIONS: &[&str] = &[r#"
+CREATE TABLE submissions (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL,
+    accuracy REAL NOT NULL,
+    altitude REAL NOT NULL,
+    altitude_accuracy REAL NOT NULL,
+    heading REAL,
+    speed REAL,
+    source TEXT NOT NULL
+);
+CREATE INDEX submissions_timestamp ON submissions (timestamp);
+
+CREATE TABLE wifi_observations (
+    submission_id INTEGER NOT NULL REFERENCES submissions (id) ON DELETE CASCADE,
+    bssid TEXT NOT NULL,
+    ssid TEXT,
+    frequency INTEGER NOT NULL,
+    channel INTEGER,
+    radio_type TEXT NOT NULL,
+    signal_strength INTEGER NOT NULL,
+    age INTEGER
+);
+CREATE INDEX wifi_observations_submission ON wifi_observations (submission_id);
+CREATE INDEX wifi_observations_bssid ON wifi_observations (bssid);
+
+CREATE TABLE bluetooth_observations (
+    submission_id INTEGER NOT NULL REFERENCES submissions (id) ON DELETE CASCADE,
+    mac_address TEXT NOT NULL,
+    name TEXT,
+    signal_strength INTEGER
+);
+CREATE INDEX bluetooth_observations_submission ON bluetooth_observations (submission_id);
+CREATE INDEX bluetooth_observations_mac_address ON bluetooth_observations (mac_address);
+
+CREATE TABLE cell_observations (
+    submission_id INTEGER NOT NULL REFERENCES submissions (id) ON DELETE CASCADE,
+    radio_type TEXT,
+    mobile_country_code INTEGER NOT NULL,
+    mobile_network_code INTEGER NOT NULL,
+    location_area_code INTEGER NOT NULL,
+    cell_id INTEGER NOT NULL,
+    age INTEGER,
+    asu INTEGER
+);
+CREATE INDEX cell_observations_submission ON cell_observations (submission_id);
+
+CREATE TABLE emitters (
+    kind TEXT NOT NULL,
+    address TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    observations INTEGER NOT NULL,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL,
+    radius REAL NOT NULL,
+    weight REAL NOT NULL,
+    weighted_latitude REAL NOT NULL,
+    weighted_longitude REAL NOT NULL,
+    reference_longitude REAL NOT NULL,
+    min_latitude REAL NOT NULL,
+    max_latitude REAL NOT NULL,
+    min_longitude REAL NOT NULL,
+    max_longitude REAL NOT NULL,
+    PRIMARY KEY (kind, address)
+);
+CREATE INDEX emitters_last_seen ON emitters (last_seen);
+"#];
+
+/// Row counts reported by `/status`
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryStats {
+    pub schema_version: usize,
+    pub submissions: u64,
+    pub wifi_observations: u64,
+    pub bluetooth_observations: u64,
+    pub cell_observations: u64,
+    pub emitters: u64,
+    /// Milliseconds since the Unix epoch
+    pub oldest_submission: Option<i64>,
+    pub newest_submission: Option<i64>,
+}
+
+/// What a retention pass removed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneStats {
+    pub submissions: usize,
+    pub emitters: usize,
+    /// Emitters last seen before this time were removed, in milliseconds since the Unix epoch
+    pub cutoff: Option<i64>,
+}
+
+/// A submission read back from the history, with all of its observations
+#[derive(Debug, Clone)]
+pub struct StoredSubmission {
+    pub id: i64,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: i64,
+    pub position: Position,
+    pub wifi: Vec<WifiBssid>,
+    pub bluetooth: Vec<BleDevice>,
+    pub cells: Vec<CellTower>,
+}
+
+/// Which part of the history to read
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    /// Inclusive start, in milliseconds since the Unix epoch
+    pub since: Option<i64>,
+    /// Exclusive end, in milliseconds since the Unix epoch
+    pub until: Option<i64>,
+    pub bbox: Option<BoundingBox>,
+}
+
+/// Area bounded by two meridians and two parallels; `west` may be greater than
+/// `east` for a box crossing the antimeridian
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        let within_longitude = if self.west <= self.east {
+            (self.west..=self.east).contains(&longitude)
+        } else {
+            longitude >= self.west || longitude <= self.east
+        };
+        (self.south..=self.north).contains(&latitude) && within_longitude
+    }
+}
+
+impl std::str::FromStr for BoundingBox {
+    type Err = Error;
+
+    /// Parse `west,south,east,north` in degrees
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::Validation(format!("invalid bounding box '{}'", s)))?;
+        let [west, south, east, north] = values[..] else {
+            return Err(Error::Validation(format!(
+                "bounding box '{}' must be west,south,east,north",
+                s
+            )));
+        };
+
+        if south > north
+            || !(-90.0..=90.0).contains(&south)
+            || !(-90.0..=90.0).contains(&north)
+            || !(-180.0..=180.0).contains(&west)
+            || !(-180.0..=180.0).contains(&east)
+        {
+            return Err(Error::Validation(format!(
+                "bounding box '{}' is out of range",
+                s
+            )));
+        }
+
+        Ok(BoundingBox {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+}
+
+impl HistoryFilter {
+    fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        self.bbox
+            .is_none_or(|bbox| bbox.contains(latitude, longitude))
+    }
+}
+
+pub struct History {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl History {
+    /// Open (or create) the database at `path` and bring its schema up to date
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn)?;
+
+        Ok(History {
+            path: path.to_path_buf(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Store a submission with all of its observations, and the emitters it updated
+    pub fn record(&self, payload: &items, emitters: &[LearnedEmitter]) -> Result<i64> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let position = &payload.position;
+        tx.execute(
+            "INSERT INTO submissions (timestamp, latitude, longitude, accuracy, altitude,
+                altitude_accuracy, heading, speed, source)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                payload.timestamp as i64,
+                position.latitude,
+                position.longitude,
+                position.accuracy,
+                position.altitude,
+                position.altitudeAccuracy,
+                position.heading,
+                position.speed,
+                position.source.as_str(),
+            ],
+        )?;
+        let submission_id = tx.last_insert_rowid();
+
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO wifi_observations (submission_id, bssid, ssid, frequency, channel,
+                    radio_type, signal_strength, age)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for ap in &payload.wifiAccessPoints {
+                insert.execute(params![
+                    submission_id,
+                    ap.bssid.to_string(),
+                    ap.ssid,
+                    ap.frequency,
+                    ap.channel,
+                    to_text(&ap.phy)?,
+                    ap.rssi,
+                    ap.age.map(|age| age as i64),
+                ])?;
+            }
+
+            let mut insert = tx.prepare(
+                "INSERT INTO bluetooth_observations (submission_id, mac_address, name,
+                    signal_strength)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for beacon in &payload.bluetoothBeacons {
+                insert.execute(params![
+                    submission_id,
+                    beacon.mac_address.to_string(),
+                    beacon.name,
+                    beacon.rssi,
+                ])?;
+            }
+
+            let mut insert = tx.prepare(
+                "INSERT INTO cell_observations (submission_id, radio_type, mobile_country_code,
+                    mobile_network_code, location_area_code, cell_id, age, asu)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for tower in payload.CellTowers.iter().flatten() {
+                insert.execute(params![
+                    submission_id,
+                    tower.radioType.as_ref().map(to_text).transpose()?,
+                    tower.mobileCountryCode,
+                    tower.mobileNetworkCode,
+                    tower.locationAreaCode,
+                    tower.cellId,
+                    tower.age,
+                    tower.asu,
+                ])?;
+            }
+        }
+
+        upsert_emitters(&tx, emitters)?;
+        tx.commit()?;
+
+        Ok(submission_id)
+    }
+
+    /// Every learned emitter, for rebuilding the in-memory index
+    pub fn emitters(&self) -> Result<Vec<LearnedEmitter>> {
+        let conn = self.conn.lock().unwrap();
+        let mut emitters = Vec::new();
+        query_emitters(&conn, &HistoryFilter::default(), &mut |emitter| {
+            emitters.push(emitter);
+            Ok(())
+        })?;
+        Ok(emitters)
+    }
+
+    /// Visit the stored submissions matching `filter`, oldest first.
+    /// Reads use their own connection, so submissions keep being recorded meanwhile.
+    pub fn for_each_submission(
+        &self,
+        filter: &HistoryFilter,
+        mut f: impl FnMut(StoredSubmission) -> Result<()>,
+    ) -> Result<()> {
+        let conn = self.reader()?;
+        let mut select = conn.prepare(
+            "SELECT id, timestamp, latitude, longitude, accuracy, altitude, altitude_accuracy,
+                heading, speed, source
+             FROM submissions
+             WHERE (?1 IS NULL OR timestamp >= ?1) AND (?2 IS NULL OR timestamp < ?2)
+             ORDER BY timestamp, id",
+        )?;
+        let mut select_wifi = conn.prepare(
+            "SELECT bssid, ssid, frequency, channel, radio_type, signal_strength, age
+             FROM wifi_observations WHERE submission_id = ?1",
+        )?;
+        let mut select_bluetooth = conn.prepare(
+            "SELECT mac_address, name, signal_strength
+             FROM bluetooth_observations WHERE submission_id = ?1",
+        )?;
+        let mut select_cells = conn.prepare(
+            "SELECT radio_type, mobile_country_code, mobile_network_code, location_area_code,
+                cell_id, age, asu
+             FROM cell_observations WHERE submission_id = ?1",
+        )?;
+
+        let mut rows = select.query(params![filter.since, filter.until])?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let position = Position {
+                latitude: row.get(2)?,
+                longitude: row.get(3)?,
+                accuracy: row.get(4)?,
+                altitude: row.get(5)?,
+                altitudeAccuracy: row.get(6)?,
+                heading: row.get(7)?,
+                speed: row.get(8)?,
+                source: row.get::<_, String>(9)?.parse()?,
+            };
+            if !filter.contains(position.latitude, position.longitude) {
+                continue;
+            }
+
+            let mut wifi = Vec::new();
+            let mut wifi_rows = select_wifi.query(params![id])?;
+            while let Some(row) = wifi_rows.next()? {
+                wifi.push(WifiBssid {
+                    bssid: parse_address(row.get(0)?)?,
+                    ssid: row.get(1)?,
+                    frequency: row.get(2)?,
+                    channel: row.get(3)?,
+                    phy: from_text(&row.get::<_, String>(4)?)?,
+                    rssi: row.get(5)?,
+                    age: row.get::<_, Option<i64>>(6)?.map(|age| age as u64),
+                });
+            }
+
+            let mut bluetooth = Vec::new();
+            let mut bluetooth_rows = select_bluetooth.query(params![id])?;
+            while let Some(row) = bluetooth_rows.next()? {
+                bluetooth.push(BleDevice {
+                    mac_address: parse_address(row.get(0)?)?,
+                    name: row.get(1)?,
+                    rssi: row.get(2)?,
+                });
+            }
+
+            let mut cells = Vec::new();
+            let mut cell_rows = select_cells.query(params![id])?;
+            while let Some(row) = cell_rows.next()? {
+                cells.push(CellTower {
+                    radioType: row
+                        .get::<_, Option<String>>(0)?
+                        .map(|text| from_text(&text))
+                        .transpose()?,
+                    mobileCountryCode: row.get(1)?,
+                    mobileNetworkCode: row.get(2)?,
+                    locationAreaCode: row.get(3)?,
+                    cellId: row.get(4)?,
+                    age: row.get(5)?,
+                    asu: row.get(6)?,
+                });
+            }
+
+            f(StoredSubmission {
+                id,
+                timestamp: row.get(1)?,
+                position,
+                wifi,
+                bluetooth,
+                cells,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Visit the learned emitters seen during the filter's time range, inside its bounding box
+    pub fn for_each_emitter(
+        &self,
+        filter: &HistoryFilter,
+        mut f: impl FnMut(LearnedEmitter) -> Result<()>,
+    ) -> Result<()> {
+        let conn = self.reader()?;
+        query_emitters(&conn, filter, &mut f)
+    }
+
+    fn reader(&self) -> Result<Connection> {
+        Ok(Connection::open_with_flags(
+            &self.path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?)
+    }
+
+    /// Apply the retention settings, deleting old submissions and emitters
+    pub fn prune(&self, settings: &HistorySettings) -> Result<PruneStats> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut stats = PruneStats::default();
+
+        if settings.retention_days > 0 {
+            let retention = (settings.retention_days as i64).saturating_mul(24 * 60 * 60 * 1000);
+            let cutoff = now_millis().saturating_sub(retention);
+
+            stats.submissions += tx.execute(
+                "DELETE FROM submissions WHERE timestamp < ?1",
+                params![cutoff],
+            )?;
+            stats.emitters +=
+                tx.execute("DELETE FROM emitters WHERE last_seen < ?1", params![cutoff])?;
+            stats.cutoff = Some(cutoff);
+        }
+
+        if settings.max_submissions > 0 {
+            stats.submissions += tx.execute(
+                "DELETE FROM submissions WHERE id NOT IN
+                    (SELECT id FROM submissions ORDER BY timestamp DESC, id DESC LIMIT ?1)",
+                params![settings.max_submissions as i64],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(stats)
+    }
+
+    pub fn stats(&self) -> Result<HistoryStats> {
+        let conn = self.conn.lock().unwrap();
+        let count = |table: &str| -> Result<u64> {
+            let count: i64 =
+                conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                    row.get(0)
+                })?;
+            Ok(count as u64)
+        };
+        let (oldest_submission, newest_submission) = conn.query_row(
+            "SELECT MIN(timestamp), MAX(timestamp) FROM submissions",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        Ok(HistoryStats {
+            schema_version: schema_version(&conn)?,
+            submissions: count("submissions")?,
+            wifi_observations: count("wifi_observations")?,
+            bluetooth_observations: count("bluetooth_observations")?,
+            cell_observations: count("cell_observations")?,
+            emitters: count("emitters")?,
+            oldest_submission,
+            newest_submission,
+        })
+    }
+}
+
+fn schema_version(conn: &Connection) -> Result<usize> {
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .optional()?
+        .unwrap_or(0);
+    Ok(version as usize)
+}
+
+/// Run every migration newer than the database's `user_version`, each in its own transaction
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let current = schema_version(conn)?;
+    if current > MIGRATIONS.len() {
+        return Err(Error::Config(format!(
+            "history database schema version {} is newer than this build supports ({})",
+            current,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version as i64 + 1)?;
+        tx.commit()?;
+        tracing::info!(
+            "[History] Migrated database to schema version {}",
+            version + 1
+        );
+    }
+
+    Ok(())
+}
+
+fn query_emitters(
+    conn: &Connection,
+    filter: &HistoryFilter,
+    f: &mut dyn FnMut(LearnedEmitter) -> Result<()>,
+) -> Result<()> {
+    let mut select = conn.prepare(
+        "SELECT kind, address, first_seen, last_seen, observations, weight,
+            weighted_latitude, weighted_longitude, reference_longitude,
+            min_latitude, max_latitude, min_longitude, max_longitude
+         FROM emitters
+         WHERE (?1 IS NULL OR last_seen >= ?1) AND (?2 IS NULL OR first_seen < ?2)
+         ORDER BY kind, address",
+    )?;
+
+    let mut rows = select.query(params![filter.since, filter.until])?;
+    while let Some(row) = rows.next()? {
+        let aggregate = EmitterAggregate {
+            first_seen: row.get(2)?,
+            last_seen: row.get(3)?,
+            observations: row.get::<_, i64>(4)? as u64,
+            weight: row.get(5)?,
+            weighted_latitude: row.get(6)?,
+            weighted_longitude: row.get(7)?,
+            reference_longitude: row.get(8)?,
+            min_latitude: row.get(9)?,
+            max_latitude: row.get(10)?,
+            min_longitude: row.get(11)?,
+            max_longitude: row.get(12)?,
+        };
+        let (latitude, longitude) = aggregate.location();
+        if !filter.contains(latitude, longitude) {
+            continue;
+        }
+
+        f((
+            from_text(&row.get::<_, String>(0)?)?,
+            parse_address(row.get(1)?)?,
+            aggregate,
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn parse_address(address: String) -> Result<BDAddr> {
+    address
+        .parse::<BDAddr>()
+        .map_err(|e| Error::Other(format!("Invalid address {} in history: {}", address, e)))
+}
+
+fn upsert_emitters(tx: &Transaction, emitters: &[LearnedEmitter]) -> Result<()> {
+    let mut upsert = tx.prepare(
+        "INSERT OR REPLACE INTO emitters (kind, address, first_seen, last_seen, observations,
+            latitude, longitude, radius, weight, weighted_latitude, weighted_longitude,
+            reference_longitude, min_latitude, max_latitude, min_longitude, max_longitude)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+    )?;
+
+    for (kind, address, aggregate) in emitters {
+        let (latitude, longitude) = aggregate.location();
+        upsert.execute(params![
+            to_text(kind)?,
+            address.to_string(),
+            aggregate.first_seen,
+            aggregate.last_seen,
+            aggregate.observations as i64,
+            latitude,
+            longitude,
+            aggregate.radius(),
+            aggregate.weight,
+            aggregate.weighted_latitude,
+            aggregate.weighted_longitude,
+            aggregate.reference_longitude,
+            aggregate.min_latitude,
+            aggregate.max_latitude,
+            aggregate.min_longitude,
+            aggregate.max_longitude,
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Store a unit enum as the same string it serializes to in JSON
+fn to_text<T: Serialize>(value: &T) -> Result<String> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(text) => Ok(text),
+        other => Ok(other.to_string()),
+    }
+}
+
+fn from_text<T: DeserializeOwned>(text: &str) -> Result<T> {
+    Ok(serde_json::from_value(serde_json::Value::String(
+        text.to_string(),
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geosubmit::EmitterIndex;
+    use crate::geosubmit::emitters::EmitterKind;
+    use crate::test_support::{self, payload, position};
+
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+
+    fn open() -> (History, PathBuf) {
+        let path = test_support::temp_dir("history").join("history.sqlite3");
+        (History::open(&path).unwrap(), path)
+    }
+
+    /// Record a submission seeing `access_points`, learning from it as the daemon does
+    fn record(history: &History, index: &EmitterIndex, timestamp: i64, access_points: &[u8]) {
+        let payload = payload(timestamp as u128, position(52.0, 13.0), access_points);
+        history.record(&payload, &index.learn(&payload)).unwrap();
+    }
+
+    #[test]
+    fn migrates_an_empty_database_once() {
+        let (history, path) = open();
+        let stats = history.stats().unwrap();
+        assert_eq!(stats.schema_version, MIGRATIONS.len());
+        assert_eq!(stats.submissions, 0);
+        assert_eq!(stats.oldest_submission, None);
+        drop(history);
+
+        // already up to date
+        assert_eq!(
+            History::open(&path)
+                .unwrap()
+                .stats()
+                .unwrap()
+                .schema_version,
+            MIGRATIONS.len()
+        );
+
+        // written by a newer build
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1)
+            .unwrap();
+        assert!(matches!(History::open(&path), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn prunes_by_age_then_by_count() {
+        let (history, _) = open();
+        let index = EmitterIndex::new();
+        let now = now_millis();
+        record(&history, &index, now - 40 * DAY, &[1, 2]);
+        record(&history, &index, now - 10 * DAY, &[3]);
+        record(&history, &index, now - DAY, &[3]);
+        record(&history, &index, now, &[3, 4]);
+
+        let pruned = history
+            .prune(&HistorySettings {
+                retention_days: 30,
+                max_submissions: 2,
+                ..HistorySettings::default()
+            })
+            .unwrap();
+        assert_eq!(pruned.submissions, 2);
+        // only the emitters last seen in the expired submission
+        assert_eq!(pruned.emitters, 2);
+        assert!(pruned.cutoff.unwrap().abs_diff(now - 30 * DAY) < 60_000);
+
+        let stats = history.stats().unwrap();
+        assert_eq!(stats.submissions, 2);
+        assert_eq!(stats.oldest_submission, Some(now - DAY));
+        assert_eq!(stats.newest_submission, Some(now));
+        // observations go with their submissions
+        assert_eq!(stats.wifi_observations, 3);
+        assert_eq!(stats.emitters, 2);
+    }
+
+    #[test]
+    fn keeps_everything_without_limits() {
+        let (history, _) = open();
+        let index = EmitterIndex::new();
+        record(&history, &index, 1, &[1]);
+
+        let pruned = history
+            .prune(&HistorySettings {
+                retention_days: 0,
+                max_submissions: 0,
+                ..HistorySettings::default()
+            })
+            .unwrap();
+        assert_eq!(pruned.submissions, 0);
+        assert_eq!(pruned.cutoff, None);
+        assert_eq!(history.stats().unwrap().submissions, 1);
+    }
+
+    #[test]
+    fn reloads_learned_emitters() {
+        let (history, path) = open();
+        let index = EmitterIndex::new();
+        let now = now_millis();
+        record(&history, &index, now - DAY, &[1, 2]);
+        record(&history, &index, now, &[1]);
+        drop(history);
+
+        let mut emitters = History::open(&path).unwrap().emitters().unwrap();
+        emitters.sort_by_key(|(_, address, _)| *address);
+        let summary = emitters
+            .iter()
+            .map(|(kind, address, aggregate)| {
+                (
+                    *kind,
+                    address.to_string(),
+                    aggregate.observations,
+                    aggregate.first_seen,
+                    aggregate.last_seen,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (
+                    EmitterKind::Wifi,
+                    "02:00:00:00:00:01".to_string(),
+                    2,
+                    now - DAY,
+                    now
+                ),
+                (
+                    EmitterKind::Wifi,
+                    "02:00:00:00:00:02".to_string(),
+                    1,
+                    now - DAY,
+                    now - DAY
+                ),
+            ]
+        );
+
+        let (latitude, longitude) = emitters[0].2.location();
+        assert!((latitude - 52.0).abs() < 1e-9 && (longitude - 13.0).abs() < 1e-9);
+    }
+}
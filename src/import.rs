@@ -0,0 +1,558 @@
+//! Import historical wardriving data from WiGLE CSV and Tower Collector CSV exports
+//!
+//! Rows are parsed into the same records the scanners produce, then grouped into
+//! geosubmit `items` by time and place, ready to go through the normal
+//! submission path.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::clock::parse_time;
+use crate::config::{IMPORT_GROUP_DISTANCE_METERS, IMPORT_GROUP_WINDOW_SECS};
+use crate::error::{Error, Result};
+use crate::geosubmit::{CellTower, LocationSource, Position, RadioType, items};
+use crate::scanner::wifi::PhyType;
+use crate::scanner::{BleDevice, WifiBssid};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    WigleCsv,
+    TowerCollectorCsv,
+}
+
+#[derive(Debug, Clone)]
+pub enum Observation {
+    Wifi(WifiBssid),
+    Bluetooth(BleDevice),
+    Cell(CellTower),
+}
+
+/// One row of an export: something observed at a place and time
+#[derive(Debug, Clone)]
+pub struct ImportedObservation {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: i64,
+    pub position: Position,
+    pub observation: Observation,
+}
+
+/// Everything read from one file
+#[derive(Debug)]
+pub struct ParsedFile {
+    pub format: ImportFormat,
+    pub rows: usize,
+    /// Rows without a usable position, time or identifier, or of unsupported radios
+    pub skipped_rows: usize,
+    pub observations: Vec<ImportedObservation>,
+}
+
+/// What an import found, and what became of it
+#[derive(Serialize, Debug, Default)]
+pub struct ImportSummary {
+    /// False for a dry run, where nothing was queued
+    pub committed: bool,
+    pub files: Vec<FileSummary>,
+    pub wifi_observations: usize,
+    pub bluetooth_observations: usize,
+    pub cell_observations: usize,
+    /// Milliseconds since the Unix epoch
+    pub first_observed: Option<i64>,
+    pub last_observed: Option<i64>,
+    /// Submissions the observations were grouped into
+    pub submissions: usize,
+    /// Submissions that passed the filters (and were queued, unless this is a dry run)
+    pub accepted: usize,
+    pub inside_exclusion_zones: usize,
+    pub too_few_observations: usize,
+    pub duplicates: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileSummary {
+    pub path: String,
+    pub format: ImportFormat,
+    pub rows: usize,
+    pub skipped_rows: usize,
+}
+
+impl ImportSummary {
+    pub fn add_file(&mut self, path: &Path, parsed: &ParsedFile) {
+        self.files.push(FileSummary {
+            path: path.display().to_string(),
+            format: parsed.format,
+            rows: parsed.rows,
+            skipped_rows: parsed.skipped_rows,
+        });
+
+        for o in &parsed.observations {
+            match o.observation {
+                Observation::Wifi(_) => self.wifi_observations += 1,
+                Observation::Bluetooth(_) => self.bluetooth_observations += 1,
+                Observation::Cell(_) => self.cell_observations += 1,
+            }
+            self.first_observed = Some(
+                self.first_observed
+                    .map_or(o.timestamp, |t| t.min(o.timestamp)),
+            );
+            self.last_observed = Some(
+                self.last_observed
+                    .map_or(o.timestamp, |t| t.max(o.timestamp)),
+            );
+        }
+    }
+}
+
+/// Read a WiGLE or Tower Collector CSV file, detecting which it is from its first line
+pub fn read_file(path: &Path) -> Result<ParsedFile> {
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+
+    let first = lines
+        .next()
+        .ok_or_else(|| Error::Validation(format!("{} is empty", path.display())))?;
+
+    let (format, header) = if first.starts_with("WigleWifi") {
+        let header = lines
+            .next()
+            .ok_or_else(|| Error::Validation(format!("{} has no header line", path.display())))?;
+        (ImportFormat::WigleCsv, header)
+    } else if split_csv_line(first)
+        .iter()
+        .any(|column| column.eq_ignore_ascii_case("mcc"))
+    {
+        (ImportFormat::TowerCollectorCsv, first)
+    } else {
+        return Err(Error::Validation(format!(
+            "{} is neither a WiGLE nor a Tower Collector CSV export",
+            path.display()
+        )));
+    };
+
+    let columns = Columns::new(header);
+    let mut parsed = ParsedFile {
+        format,
+        rows: 0,
+        skipped_rows: 0,
+        observations: Vec::new(),
+    };
+
+    for line in lines {
+        parsed.rows += 1;
+        let fields = split_csv_line(line);
+        let row = Row {
+            columns: &columns,
+            fields: &fields,
+        };
+        let observation = match format {
+            ImportFormat::WigleCsv => parse_wigle_row(&row),
+            ImportFormat::TowerCollectorCsv => parse_tower_collector_row(&row),
+        };
+
+        match observation {
+            Some(observation) => parsed.observations.push(observation),
+            None => parsed.skipped_rows += 1,
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Group observations made close together in time and space into submissions.
+/// Each submission takes the most accurate position among its rows.
+pub fn group_observations(mut observations: Vec<ImportedObservation>) -> Vec<items> {
+    observations.sort_by_key(|o| o.timestamp);
+    let window = IMPORT_GROUP_WINDOW_SECS as i64 * 1000;
+
+    // Files may interleave, so any group still open in the time window can take a row
+    let mut groups: Vec<Vec<ImportedObservation>> = Vec::new();
+    for observation in observations {
+        let open = groups
+            .iter_mut()
+            .rev()
+            .take_while(|group| observation.timestamp - group[0].timestamp <= window)
+            .find(|group| {
+                group[0].position.distance_to(&observation.position) <= IMPORT_GROUP_DISTANCE_METERS
+            });
+
+        match open {
+            Some(group) => group.push(observation),
+            None => groups.push(vec![observation]),
+        }
+    }
+
+    groups.into_iter().map(into_item).collect()
+}
+
+fn into_item(group: Vec<ImportedObservation>) -> items {
+    let timestamp = group[0].timestamp;
+    let position = group
+        .iter()
+        .map(|o| &o.position)
+        .min_by(|a, b| a.accuracy.total_cmp(&b.accuracy))
+        .cloned()
+        .expect("groups are never empty");
+
+    // The same emitter may appear more than once; keep its strongest sighting
+    let mut wifi: HashMap<_, WifiBssid> = HashMap::new();
+    let mut bluetooth: HashMap<_, BleDevice> = HashMap::new();
+    let mut cells: HashMap<_, CellTower> = HashMap::new();
+    for o in group {
+        match o.observation {
+            Observation::Wifi(ap) => {
+                let entry = wifi.entry(ap.bssid).or_insert_with(|| ap.clone());
+                if ap.rssi > entry.rssi {
+                    *entry = ap;
+                }
+            }
+            Observation::Bluetooth(beacon) => {
+                let entry = bluetooth
+                    .entry(beacon.mac_address)
+                    .or_insert_with(|| beacon.clone());
+                if beacon.rssi > entry.rssi {
+                    *entry = beacon;
+                }
+            }
+            Observation::Cell(tower) => {
+                cells
+                    .entry((
+                        tower.mobileCountryCode,
+                        tower.mobileNetworkCode,
+                        tower.locationAreaCode,
+                        tower.cellId,
+                    ))
+                    .or_insert(tower);
+            }
+        }
+    }
+
+    items {
+        timestamp: timestamp as u128,
+        position,
+        bluetoothBeacons: bluetooth.into_values().collect(),
+        wifiAccessPoints: wifi.into_values().collect(),
+        CellTowers: (!cells.is_empty()).then(|| cells.into_values().collect()),
+    }
+}
+
+/// WiGLE CSV rows, as written by WiGLE WiFi Wardriving 1.4 and later
+fn parse_wigle_row(row: &Row) -> Option<ImportedObservation> {
+    let timestamp = parse_time(row.get(&["FirstSeen"])?).ok()?;
+    let position = row.position(
+        &["CurrentLatitude"],
+        &["CurrentLongitude"],
+        &["AccuracyMeters"],
+        &["AltitudeMeters"],
+    )?;
+    let mac = row.get(&["MAC"])?;
+
+    let observation = match row.get(&["Type"])?.to_ascii_uppercase().as_str() {
+        "WIFI" => {
+            let channel = row.parse::<u8>(&["Channel"]);
+            let frequency = row
+                .parse::<u16>(&["Frequency"])
+                .or_else(|| channel.and_then(channel_frequency))?;
+            Observation::Wifi(WifiBssid {
+                ssid: row.get(&["SSID"]).map(str::to_string),
+                bssid: mac.parse().ok()?,
+                age: None,
+                channel,
+                frequency,
+                phy: PhyType::Legacy,
+                rssi: row.parse(&["RSSI"])?,
+            })
+        }
+        "BLE" => Observation::Bluetooth(BleDevice {
+            mac_address: mac.parse().ok()?,
+            rssi: row.parse(&["RSSI"]),
+            name: row.get(&["SSID"]).map(str::to_string),
+        }),
+        // Cells are identified as <MCC><MNC>_<LAC>_<CID>
+        kind @ ("GSM" | "WCDMA" | "UMTS" | "LTE") => {
+            let mut parts = mac.split('_');
+            let operator = parts.next()?;
+            if operator.len() < 5 || !operator.is_ascii() {
+                return None;
+            }
+            Observation::Cell(CellTower {
+                radioType: radio_type(kind),
+                mobileCountryCode: operator[..3].parse().ok()?,
+                mobileNetworkCode: operator[3..].parse().ok()?,
+                locationAreaCode: parts.next()?.parse().ok()?,
+                cellId: parts.next()?.parse().ok()?,
+                age: None,
+                asu: None,
+            })
+        }
+        _ => return None,
+    };
+
+    Some(ImportedObservation {
+        timestamp,
+        position,
+        observation,
+    })
+}
+
+/// Tower Collector CSV rows. Column names vary between versions, so each field
+/// is looked up under the names it has had.
+fn parse_tower_collector_row(row: &Row) -> Option<ImportedObservation> {
+    let timestamp = parse_time(row.get(&["measured_at", "timestamp", "time"])?).ok()?;
+    let position = row.position(
+        &["gps_latitude", "latitude", "lat"],
+        &["gps_longitude", "longitude", "lon"],
+        &["gps_accuracy", "accuracy"],
+        &["gps_altitude", "altitude"],
+    )?;
+
+    let cell_id = row.parse::<i64>(&["cell_id", "cid", "long_cid"])?;
+    let tower = CellTower {
+        radioType: row
+            .get(&["net_type", "network_type", "radio", "type"])
+            .and_then(radio_type),
+        mobileCountryCode: row.parse(&["mcc"])?,
+        mobileNetworkCode: row.parse(&["mnc"])?,
+        locationAreaCode: row.parse(&["lac", "tac"])?,
+        cellId: u32::try_from(cell_id).ok()?,
+        age: None,
+        asu: row.parse(&["asu"]),
+    };
+
+    Some(ImportedObservation {
+        timestamp,
+        position,
+        observation: Observation::Cell(tower),
+    })
+}
+
+fn radio_type(name: &str) -> Option<RadioType> {
+    match name.to_ascii_uppercase().as_str() {
+        "GSM" | "GPRS" | "EDGE" => Some(RadioType::Gsm),
+        "WCDMA" | "UMTS" | "HSPA" | "HSDPA" | "HSUPA" | "HSPA+" => Some(RadioType::Wcdma),
+        "LTE" => Some(RadioType::Lte),
+        _ => None,
+    }
+}
+
+/// Centre frequency of a 2.4 or 5 GHz WiFi channel, in MHz
+fn channel_frequency(channel: u8) -> Option<u16> {
+    match channel {
+        1..=13 => Some(2407 + 5 * u16::from(channel)),
+        14 => Some(2484),
+        32..=177 => Some(5000 + 5 * u16::from(channel)),
+        _ => None,
+    }
+}
+
+/// Column positions by lower-cased header name
+struct Columns(HashMap<String, usize>);
+
+impl Columns {
+    fn new(header: &str) -> Self {
+        Columns(
+            split_csv_line(header)
+                .into_iter()
+                .enumerate()
+                .map(|(i, name)| (name.trim().to_ascii_lowercase(), i))
+                .collect(),
+        )
+    }
+}
+
+struct Row<'a> {
+    columns: &'a Columns,
+    fields: &'a [String],
+}
+
+impl Row<'_> {
+    /// The first non-empty field under any of `names`
+    fn get(&self, names: &[&str]) -> Option<&str> {
+        names.iter().find_map(|name| {
+            let index = *self.columns.0.get(&name.to_ascii_lowercase())?;
+            let value = self.fields.get(index)?.trim();
+            (!value.is_empty()).then_some(value)
+        })
+    }
+
+    fn parse<T: std::str::FromStr>(&self, names: &[&str]) -> Option<T> {
+        self.get(names)?.parse().ok()
+    }
+
+    fn position(
+        &self,
+        latitude: &[&str],
+        longitude: &[&str],
+        accuracy: &[&str],
+        altitude: &[&str],
+    ) -> Option<Position> {
+        let position = Position {
+            latitude: self.parse(latitude)?,
+            longitude: self.parse(longitude)?,
+            accuracy: self.parse(accuracy).unwrap_or(0.0),
+            altitude: self.parse(altitude).unwrap_or(0.0),
+            altitudeAccuracy: 0.0,
+            heading: None,
+            speed: None,
+            source: LocationSource::Gps,
+        };
+
+        // Exports use 0,0 for rows logged before the first fix
+        let no_fix = position.latitude == 0.0 && position.longitude == 0.0;
+        (!no_fix && position.validate().is_ok()).then_some(position)
+    }
+}
+
+/// Split one CSV line, honouring double-quoted fields
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/import")
+            .join(name)
+    }
+
+    #[test]
+    fn reads_wigle_csv() {
+        let parsed = read_file(&fixture("wigle.csv")).unwrap();
+        assert_eq!(parsed.format, ImportFormat::WigleCsv);
+        assert_eq!(parsed.rows, 10);
+        // no fix yet, a broken MAC, Bluetooth classic and an unreadable time
+        assert_eq!(parsed.skipped_rows, 4);
+
+        let Observation::Wifi(ap) = &parsed.observations[0].observation else {
+            panic!("expected an access point");
+        };
+        assert_eq!(ap.ssid.as_deref(), Some("Cafe, \"Free\""));
+        assert_eq!((ap.channel, ap.frequency, ap.rssi), (Some(6), 2437, -60));
+        assert_eq!(
+            parsed.observations[0].timestamp,
+            parse_time("2025-06-01T12:00:00Z").unwrap()
+        );
+        let Observation::Wifi(ap) = &parsed.observations[3].observation else {
+            panic!("expected an access point");
+        };
+        assert_eq!(ap.frequency, 5180);
+        let Observation::Cell(tower) = &parsed.observations[4].observation else {
+            panic!("expected a cell");
+        };
+        assert_eq!(
+            (
+                tower.mobileCountryCode,
+                tower.mobileNetworkCode,
+                tower.locationAreaCode,
+                tower.cellId
+            ),
+            (262, 1, 1234, 5678)
+        );
+    }
+
+    #[test]
+    fn groups_wigle_rows_and_keeps_the_strongest_sighting() {
+        let parsed = read_file(&fixture("wigle.csv")).unwrap();
+        let submissions = group_observations(parsed.observations);
+        assert_eq!(submissions.len(), 2);
+
+        let first = &submissions[0];
+        assert_eq!(
+            first.timestamp as i64,
+            parse_time("2025-06-01T12:00:00Z").unwrap()
+        );
+        // the most accurate position of the group
+        assert_eq!(first.position.accuracy, 4.0);
+        assert_eq!(first.wifiAccessPoints.len(), 2);
+        let cafe = first
+            .wifiAccessPoints
+            .iter()
+            .find(|ap| ap.bssid.to_string() == "02:00:00:00:00:01")
+            .unwrap();
+        assert_eq!(cafe.rssi, -48);
+        assert_eq!(first.bluetoothBeacons.len(), 1);
+        assert_eq!(first.CellTowers.as_ref().unwrap().len(), 1);
+
+        assert_eq!(submissions[1].wifiAccessPoints.len(), 1);
+        assert!(submissions[1].CellTowers.is_none());
+    }
+
+    #[test]
+    fn reads_tower_collector_csv() {
+        let parsed = read_file(&fixture("tower-collector.csv")).unwrap();
+        assert_eq!(parsed.format, ImportFormat::TowerCollectorCsv);
+        assert_eq!(parsed.rows, 6);
+        // a negative cell id, a non-numeric MNC and a latitude out of range
+        assert_eq!(parsed.skipped_rows, 3);
+        assert_eq!(
+            parsed.observations[0].timestamp,
+            parse_time("2025-06-01T12:00:00Z").unwrap()
+        );
+        let Observation::Cell(tower) = &parsed.observations[1].observation else {
+            panic!("expected a cell");
+        };
+        assert!(matches!(tower.radioType, Some(RadioType::Wcdma)));
+        assert_eq!(tower.asu, Some(18));
+
+        // the same cell twice in one group is submitted once
+        let submissions = group_observations(parsed.observations);
+        assert_eq!(submissions.len(), 1);
+        let mut cells = submissions[0]
+            .CellTowers
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|tower| tower.cellId)
+            .collect::<Vec<_>>();
+        cells.sort();
+        assert_eq!(cells, [5678, 5679]);
+        assert_eq!(submissions[0].position.accuracy, 5.0);
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_exports() {
+        let dir = test_support::temp_dir("import");
+        for (name, contents) in [
+            ("empty.csv", "\n\n"),
+            ("no-header.csv", "WigleWifi-1.4,appRelease=2.70\n"),
+            ("other.csv", "name,latitude,longitude\nhome,52.5,13.4\n"),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            assert!(
+                matches!(read_file(&path), Err(Error::Validation(_))),
+                "{}",
+                name
+            );
+        }
+
+        assert!(matches!(
+            read_file(&dir.join("missing.csv")),
+            Err(Error::Io(_))
+        ));
+    }
+}
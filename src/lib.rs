@@ -1,20 +1,66 @@
+pub mod cli;
+pub mod clock;
 pub mod config;
+pub mod devices;
 pub mod error;
+pub mod export;
+pub mod history;
+pub mod import;
+pub mod metrics;
+pub mod stationary;
+
+#[cfg(test)]
+mod test_support;
 
 pub mod scanner {
+    pub mod adapter;
     pub mod bluetooth;
+    pub mod cache;
     pub mod wifi;
 
     pub use self::bluetooth::BleDevice;
+    pub use self::cache::{Scan, ScanCache};
     pub use self::wifi::WifiBssid;
 }
 
 pub mod geosubmit {
+    pub mod archive;
     pub mod client;
+    pub mod emitters;
+    pub mod filter;
+    pub mod geolocate;
+    pub mod http;
     pub mod payload;
+    pub mod privacy;
+    pub mod provider;
+    pub mod queue;
+    pub mod zones;
 
+    pub use self::archive::JsonlArchive;
     pub use self::client::{assemble_geo_payload, submit_geo_payload};
-    pub use self::payload::{CellTower, Position, RadioType, items};
+    pub use self::emitters::EmitterIndex;
+    pub use self::filter::SubmissionFilter;
+    pub use self::geolocate::{SelfCheck, self_check};
+    pub use self::http::HttpClient;
+    pub use self::payload::{CellTower, GeoSubmission, LocationSource, Position, RadioType, items};
+    pub use self::privacy::PrivacyPolicy;
+    pub use self::provider::Provider;
+    pub use self::queue::{SubmissionQueue, run_queue_worker};
+    pub use self::zones::{ExclusionZone, ZoneGuard};
+}
+
+pub mod position {
+    pub mod arbiter;
+    pub mod fixed;
+    pub mod gpsd;
+    pub mod nmea;
+    pub mod source;
+
+    pub use self::arbiter::arbitrate;
+    pub use self::fixed::FixedSource;
+    pub use self::gpsd::GpsdSource;
+    pub use self::nmea::NmeaSource;
+    pub use self::source::{Fix, PositionSource, SourceStatus};
 }
 
 pub mod peripheral {
@@ -24,27 +70,59 @@ pub mod peripheral {
 }
 
 pub mod server {
+    pub mod auth;
+    pub mod compat;
     pub mod handlers;
+    pub mod health;
     pub mod mdns_service;
+    pub mod state;
 
     use axum::routing::{get, post};
-    use axum::{Router, body::Body, http::Request};
+    use axum::{Router, body::Body, http::Request, middleware};
+    use hyper::body::Incoming;
     use hyper_util::rt::tokio::TokioIo;
     use rustls::ServerConfig;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
     use std::{net::SocketAddr, sync::Arc};
     use tokio::net::TcpListener;
     use tokio_rustls::TlsAcceptor;
+    use tower::ServiceExt;
     use tower_http::trace::TraceLayer;
     use tracing::Span;
 
     use crate::config::{HTTP_SERVER_PORT, Identity};
+    use crate::devices::DeviceRegistry;
     use crate::error::Result;
 
-    pub fn create_router() -> Router {
-        Router::new()
+    pub use self::state::AppState;
+
+    pub fn create_router(state: Arc<AppState>) -> Router {
+        let mut router = Router::new()
             .route("/submit", post(handlers::process_submit_http))
-            .route("/status", get(handlers::handle_status))
             .route("/request", get(handlers::handle_request))
+            .route("/status", get(handlers::handle_status))
+            .route("/queue", get(handlers::handle_queue))
+            .route("/geolocate/check", post(handlers::handle_geolocate_check))
+            .route("/v1/geolocate", post(handlers::handle_geolocate))
+            .route("/export/{format}", get(handlers::handle_export))
+            .route("/network_json", get(compat::handle_network_json));
+        if state.settings.metrics.enabled && !state.settings.metrics.separate_listener {
+            router = router.route("/metrics", get(crate::metrics::handle_metrics));
+        }
+        let router = router
+            // every route above needs a paired device; pairing itself can't
+            .route_layer(middleware::from_fn_with_state(
+                Arc::clone(&state),
+                auth::require_device,
+            ))
+            .route("/pair", post(auth::handle_pair));
+
+        router
+            .layer(middleware::from_fn(crate::metrics::track_requests))
+            .layer(middleware::from_fn_with_state(
+                Arc::clone(&state),
+                health::record_errors,
+            ))
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(|request: &Request<Body>| {
@@ -71,23 +149,54 @@ pub mod server {
                         tracing::info!("started {} {}", request.method(), request.uri().path());
                     }),
             )
+            .with_state(state)
     }
 
-    pub async fn start_tls(identity: Identity) -> Result<()> {
-        let certs = identity.certs;
-        let key = identity.key;
-
-        let config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .map_err(|e| crate::error::Error::Other(e.to_string()))?;
-
-        let acceptor = TlsAcceptor::from(Arc::new(config));
+    pub async fn start_tls(identity: Identity, state: Arc<AppState>) -> Result<()> {
+        let config = tls_config(
+            identity.certs,
+            identity.key,
+            state.settings.pairing.mutual_tls,
+            &state.devices,
+        )?;
         let listener = TcpListener::bind(("0.0.0.0", HTTP_SERVER_PORT))
             .await
             .map_err(|e| crate::error::Error::Bind(e.to_string()))?;
 
-        let router = create_router();
+        serve_tls(
+            listener,
+            TlsAcceptor::from(Arc::new(config)),
+            create_router(state),
+        )
+        .await
+    }
+
+    /// TLS settings for the server; with `mutual_tls`, clients may present a
+    /// certificate pinned in `devices`
+    fn tls_config(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        mutual_tls: bool,
+        devices: &Arc<DeviceRegistry>,
+    ) -> Result<ServerConfig> {
+        let builder = ServerConfig::builder();
+        let builder = if mutual_tls {
+            let algorithms = builder.crypto_provider().signature_verification_algorithms;
+            builder.with_client_cert_verifier(Arc::new(auth::PinnedClientCerts::new(
+                Arc::clone(devices),
+                algorithms,
+            )))
+        } else {
+            builder.with_no_client_auth()
+        };
+        builder
+            .with_single_cert(certs, key)
+            .map_err(|e| crate::error::Error::Other(e.to_string()))
+    }
+
+    /// Serve `router` to every TLS connection on `listener`, adding the client
+    /// certificate of the connection to each request
+    async fn serve_tls(listener: TcpListener, acceptor: TlsAcceptor, router: Router) -> Result<()> {
         loop {
             let (stream, _) = listener
                 .accept()
@@ -100,6 +209,19 @@ pub mod server {
             tokio::spawn(async move {
                 match acceptor.accept(stream).await {
                     Ok(tls_stream) => {
+                        let client_certificate = tls_stream
+                            .get_ref()
+                            .1
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .map(|cert| auth::ClientCertificate(cert.clone().into_owned()));
+                        let router = router.map_request(move |mut request: Request<Incoming>| {
+                            if let Some(certificate) = &client_certificate {
+                                request.extensions_mut().insert(certificate.clone());
+                            }
+                            request
+                        });
+
                         let io = TokioIo::new(tls_stream);
                         let hyper_service = hyper_util::service::TowerToHyperService::new(router);
 
@@ -117,6 +239,6 @@ pub mod server {
     }
 }
 
-pub use error::{Error, Result};
+pub use error::{Error, ErrorCategory, Result};
 pub use geosubmit::{CellTower, Position, items};
 pub use scanner::{BleDevice, WifiBssid};
@@ -4,7 +4,9 @@
 //! location data to the Ichnaea geolocation service.
 
 use local_ip_address::local_ip;
-use service_berry::{config, peripheral, server};
+use service_berry::cli::{self, Command};
+use service_berry::{config, devices, geosubmit, metrics, peripheral, scanner, server, stationary};
+use std::sync::Arc;
 use users::get_current_username;
 
 #[tokio::main]
@@ -12,6 +14,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging
     tracing_subscriber::fmt::init();
 
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Serve => {}
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Command::Geolocate { reference } => {
+            let settings = config::load_settings(&config::config_dir())?;
+            return cli::geolocate(&settings, reference).await;
+        }
+        Command::Export {
+            format,
+            output,
+            filter,
+        } => return cli::export(format, output, &filter),
+        Command::Import { files, commit } => {
+            let settings = config::load_settings(&config::config_dir())?;
+            return cli::import(settings, &files, commit);
+        }
+        Command::Devices(command) => {
+            let settings = config::load_settings(&config::config_dir())?;
+            return cli::devices(&settings, command);
+        }
+    }
+
     // get system info
     let instance_name = hostname::get() // computer name
         .unwrap_or_else(|_| config::DEFAULT_HOSTNAME.into())
@@ -28,7 +63,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Generate TLS certificates
     let config_directory = config::config_dir();
+    let settings = config::load_settings(&config_directory)?;
     let identity = config::load_identity(instance_name.clone(), config_directory)?;
+    let _data_dir_lock = config::lock_data_dir()?;
 
     // Register mDNS service
     let _mdns = server::mdns_service::register_mdns_service(
@@ -37,29 +74,107 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         version,
         &identity.certs_hash,
         &username,
+        settings.pairing.required,
     )
     .map_err(|e| format!("Failed to register mDNS: {}", e))?;
 
+    let state = Arc::new(server::AppState::new(settings)?);
+    state
+        .health
+        .set_certificate(&identity.certs_hash, identity.not_after());
+
+    // Nothing can submit until a phone is paired, so offer a PIN right away
+    if state.settings.pairing.required && state.devices.devices()?.is_empty() {
+        let ttl = std::time::Duration::from_secs(state.settings.pairing.pin_ttl_secs);
+        let pin = state.devices.open_pin(ttl)?;
+        let uri = devices::pairing_uri(
+            &lan_ip.to_string(),
+            config::HTTP_SERVER_PORT,
+            &identity.certs_hash,
+            &pin,
+        );
+        devices::print_pin(&pin, ttl, &uri, state.settings.pairing.show_qr);
+    }
+
+    // Check the Bluetooth adapter once; scans keep /status up to date after that
+    tokio::spawn(scanner::adapter::probe_bluetooth());
+
+    // Start the submission queue worker
+    tokio::spawn(geosubmit::run_queue_worker(
+        Arc::clone(&state.queue),
+        state.http.clone(),
+        Arc::clone(&state.provider),
+        state.settings.submission.clone(),
+        state.settings.privacy.clone(),
+    ));
+
+    // Start local position sources
+    for source in &state.position_sources {
+        Arc::clone(source).spawn();
+    }
+
+    // Scan on a schedule when this is a stationary node
+    if let Some(node) = &state.stationary {
+        tokio::spawn(stationary::run(Arc::clone(node), Arc::clone(&state)));
+    }
+
+    // Apply history retention now and periodically
+    let retention_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        let interval = std::time::Duration::from_secs(config::HISTORY_PRUNE_INTERVAL_SECS);
+        loop {
+            if let Err(e) = retention_state.prune_history() {
+                tracing::error!("[History] Failed to apply retention: {}", e);
+                retention_state.health.record_error("history", &e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<server::handlers::PartialPayload>();
 
     // Start the BLE peripheral
+    let peripheral_health = Arc::clone(&state.health);
     tokio::spawn(async move {
-        peripheral::ble_peripheral(tx).await;
+        peripheral::ble_peripheral(tx, peripheral_health).await;
     });
 
     // Start the Worker
+    let worker_state = Arc::clone(&state);
     tokio::spawn(async move {
         while let Some(payload) = rx.recv().await {
             tracing::info!("Worker received payload from BLE: {:?}", payload);
-            // This is where you call your submission logic
-            if let Err(e) = server::handlers::process_submit(payload).await {
+            if let Err(e) = server::handlers::process_submit(&worker_state, payload).await {
                 tracing::error!("Failed to process BLE submission: {:?}", e);
+                worker_state.health.record_error("ble", &e);
             }
         }
     });
 
+    // Serve the Scriptable client over plain HTTP if asked to
+    if state.settings.compat.plain_http {
+        let compat_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let port = compat_state.settings.compat.port;
+            if let Err(e) = server::compat::start_plain_http(port, compat_state).await {
+                tracing::error!("[Compat] Plain HTTP listener failed: {}", e);
+            }
+        });
+    }
+
+    // Serve metrics on their own port if asked to
+    if state.settings.metrics.enabled && state.settings.metrics.separate_listener {
+        let metrics_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let port = metrics_state.settings.metrics.port;
+            if let Err(e) = metrics::start_listener(port, metrics_state).await {
+                tracing::error!("[Metrics] Listener failed: {}", e);
+            }
+        });
+    }
+
     // Start HTTP server
-    server::start_tls(identity).await?;
+    server::start_tls(identity, state).await?;
 
     Ok(())
 }
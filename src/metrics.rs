@@ -0,0 +1,297 @@
+//! Prometheus metrics, served as text on `/metrics`
+//!
+//! Scans and BLE writes happen far from `AppState`, so the metrics live in one
+//! process-wide registry. Gauges that mirror state elsewhere, like the queue
+//! depth, are filled in when scraped.
+
+use axum::Router;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::header;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
+use tokio::net::TcpListener;
+
+use crate::error::{Error, Result};
+use crate::server::{AppState, auth};
+
+const NAMESPACE: &str = "serviceberry";
+
+const SCAN_DURATION_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 12.0, 15.0, 20.0, 30.0, 60.0];
+const SCAN_OBSERVATION_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0];
+const HTTP_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0,
+];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The process-wide metrics
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Why bytes written over BLE didn't make it to a submission
+#[derive(Debug, Clone, Copy)]
+pub enum BleError {
+    /// A complete line that isn't a valid payload
+    InvalidPayload,
+    /// The reassembly buffer grew too large without a complete payload
+    Overflow,
+    /// The peripheral couldn't be created or start advertising
+    Advertising,
+}
+
+impl BleError {
+    fn as_str(self) -> &'static str {
+        match self {
+            BleError::InvalidPayload => "invalid_payload",
+            BleError::Overflow => "overflow",
+            BleError::Advertising => "advertising",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    scans: IntCounterVec,
+    scan_duration: HistogramVec,
+    scan_observations: HistogramVec,
+    submissions: IntCounterVec,
+    queue_pending: IntGauge,
+    queue_quarantined: IntGauge,
+    http_requests: HistogramVec,
+    ble_writes: IntCounter,
+    ble_payloads: IntCounter,
+    ble_errors: IntCounterVec,
+    start_time: IntGauge,
+    build_info: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let metrics = Metrics {
+            scans: IntCounterVec::new(opts("scans_total", "Scans performed, by radio"), &["radio"])
+                .unwrap(),
+            scan_duration: HistogramVec::new(
+                histogram_opts(
+                    "scan_duration_seconds",
+                    "How long scans took, by radio",
+                    SCAN_DURATION_BUCKETS,
+                ),
+                &["radio"],
+            )
+            .unwrap(),
+            scan_observations: HistogramVec::new(
+                histogram_opts(
+                    "scan_observations",
+                    "Access points or beacons seen per scan, by radio",
+                    SCAN_OBSERVATION_BUCKETS,
+                ),
+                &["radio"],
+            )
+            .unwrap(),
+            submissions: IntCounterVec::new(
+                opts(
+                    "submissions_total",
+                    "Queued submissions sent to a provider, by outcome",
+                ),
+                &["provider", "outcome"],
+            )
+            .unwrap(),
+            queue_pending: IntGauge::with_opts(opts(
+                "queue_pending",
+                "Submissions waiting in the queue",
+            ))
+            .unwrap(),
+            queue_quarantined: IntGauge::with_opts(opts(
+                "queue_quarantined",
+                "Submissions set aside as undeliverable",
+            ))
+            .unwrap(),
+            http_requests: HistogramVec::new(
+                histogram_opts(
+                    "http_request_duration_seconds",
+                    "HTTP request latency, by method, route and status",
+                    HTTP_DURATION_BUCKETS,
+                ),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            ble_writes: IntCounter::with_opts(opts(
+                "ble_writes_total",
+                "GATT writes received from phones",
+            ))
+            .unwrap(),
+            ble_payloads: IntCounter::with_opts(opts(
+                "ble_payloads_total",
+                "Payloads reassembled from GATT writes",
+            ))
+            .unwrap(),
+            ble_errors: IntCounterVec::new(
+                opts("ble_errors_total", "BLE peripheral errors, by kind"),
+                &["kind"],
+            )
+            .unwrap(),
+            start_time: IntGauge::with_opts(opts(
+                "start_time_seconds",
+                "When the daemon started, in seconds since the Unix epoch",
+            ))
+            .unwrap(),
+            build_info: IntGaugeVec::new(
+                opts("build_info", "Always 1, labelled with the running version"),
+                &["version"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        for collector in [
+            Box::new(metrics.scans.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.scan_duration.clone()),
+            Box::new(metrics.scan_observations.clone()),
+            Box::new(metrics.submissions.clone()),
+            Box::new(metrics.queue_pending.clone()),
+            Box::new(metrics.queue_quarantined.clone()),
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.ble_writes.clone()),
+            Box::new(metrics.ble_payloads.clone()),
+            Box::new(metrics.ble_errors.clone()),
+            Box::new(metrics.start_time.clone()),
+            Box::new(metrics.build_info.clone()),
+        ] {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        metrics
+            .build_info
+            .with_label_values(&[env!("CARGO_PKG_VERSION")])
+            .set(1);
+
+        metrics
+    }
+
+    /// Time a scan of `radio` and count what it found
+    pub async fn time_scan<T>(&self, radio: &str, scan: impl Future<Output = Vec<T>>) -> Vec<T> {
+        let started = Instant::now();
+        let observations = scan.await;
+        let elapsed = started.elapsed();
+        tracing::debug!("[Metrics] {} scan took {:?}", radio, elapsed);
+
+        self.scans.with_label_values(&[radio]).inc();
+        self.scan_duration
+            .with_label_values(&[radio])
+            .observe(elapsed.as_secs_f64());
+        self.scan_observations
+            .with_label_values(&[radio])
+            .observe(observations.len() as f64);
+        observations
+    }
+
+    /// Count `count` submissions sent to `provider` that ended in `outcome`
+    pub fn record_submissions(&self, provider: &str, outcome: &str, count: usize) {
+        self.submissions
+            .with_label_values(&[provider, outcome])
+            .inc_by(count as u64);
+    }
+
+    pub fn record_ble_write(&self) {
+        self.ble_writes.inc();
+    }
+
+    pub fn record_ble_payload(&self) {
+        self.ble_payloads.inc();
+    }
+
+    pub fn record_ble_error(&self, error: BleError) {
+        self.ble_errors.with_label_values(&[error.as_str()]).inc();
+    }
+
+    /// Everything in the Prometheus text format, refreshing gauges from `state`
+    pub fn render(&self, state: &AppState) -> Result<String> {
+        self.queue_pending.set(state.queue.len() as i64);
+        self.queue_quarantined.set(state.queue.quarantined() as i64);
+        self.start_time.set(state.health.started_at() / 1000);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| Error::Other(e.to_string()))?;
+        String::from_utf8(buffer).map_err(|e| Error::Other(e.to_string()))
+    }
+}
+
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace(NAMESPACE)
+}
+
+fn histogram_opts(name: &str, help: &str, buckets: &[f64]) -> HistogramOpts {
+    HistogramOpts::new(name, help)
+        .namespace(NAMESPACE)
+        .buckets(buckets.to_vec())
+}
+
+pub async fn handle_metrics(State(state): State<Arc<AppState>>) -> Result<Response> {
+    let body = metrics().render(&state)?;
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response())
+}
+
+/// Middleware timing each request, labelled with its route rather than its path
+/// so ids in URLs don't make new series
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    metrics()
+        .http_requests
+        .with_label_values(&[method.as_str(), &route, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+/// Serve only `/metrics`, over plain HTTP, on `port`. See
+/// `auth::plain_http_access` for who can reach it.
+pub async fn start_listener(port: u16, state: Arc<AppState>) -> Result<()> {
+    let (address, token) = auth::plain_http_access(
+        &state.settings.pairing,
+        state.settings.metrics.token.as_deref(),
+    );
+    let listener = TcpListener::bind((address, port))
+        .await
+        .map_err(|e| Error::Bind(e.to_string()))?;
+    tracing::info!(
+        "[Metrics] Serving /metrics over HTTP on {}:{}",
+        address,
+        port
+    );
+
+    let mut router = Router::new().route("/metrics", get(handle_metrics));
+    if let Some(token) = token {
+        router = router.route_layer(middleware::from_fn_with_state(
+            token,
+            auth::require_listener_token,
+        ));
+    }
+    let router = router.with_state(state);
+    axum::serve(listener, router)
+        .await
+        .map_err(|e| Error::Bind(e.to_string()))
+}
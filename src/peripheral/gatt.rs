@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{Mutex, mpsc};
-use tracing::info;
+use tracing::{error, info};
 use uuid::Uuid;
 
 use ble_peripheral_rust::PeripheralImpl;
@@ -18,9 +18,11 @@ use ble_peripheral_rust::{
     uuid::ShortUuid,
 };
 
+use crate::metrics::{BleError, metrics};
 use crate::server::handlers::PartialPayload;
+use crate::server::health::{Health, PeripheralState};
 
-pub async fn ble_peripheral(payload_tx: UnboundedSender<PartialPayload>) {
+pub async fn ble_peripheral(payload_tx: UnboundedSender<PartialPayload>, health: Arc<Health>) {
     let service_uuid =
         Uuid::parse_str("12345678-1234-5678-1234-56789abcdef0").expect("invalid service UUID");
     let char_uuid =
@@ -52,21 +54,37 @@ pub async fn ble_peripheral(payload_tx: UnboundedSender<PartialPayload>) {
     };
 
     let (event_tx, mut event_rx) = mpsc::channel::<PeripheralEvent>(256);
-    let mut peripheral = Peripheral::new(event_tx)
-        .await
-        .expect("failed to create peripheral");
-    peripheral
-        .add_service(&service)
-        .await
-        .expect("failed to add service");
+    let mut peripheral = match Peripheral::new(event_tx).await {
+        Ok(peripheral) => peripheral,
+        Err(e) => {
+            error!("[BLE] Failed to create peripheral: {}", e);
+            health.set_peripheral_state(PeripheralState::Failed, Some(e.to_string()));
+            metrics().record_ble_error(BleError::Advertising);
+            return;
+        }
+    };
+    if let Err(e) = peripheral.add_service(&service).await {
+        error!("[BLE] Failed to add service: {}", e);
+        health.set_peripheral_state(PeripheralState::Failed, Some(e.to_string()));
+        metrics().record_ble_error(BleError::Advertising);
+        return;
+    }
 
     let mut write_buffer = Vec::new();
     let char_value_loop = Arc::clone(&char_value);
 
     info!("Advertising as Serviceberry...");
-    let _ = peripheral
+    match peripheral
         .start_advertising("Serviceberry", &[service_uuid])
-        .await;
+        .await
+    {
+        Ok(()) => health.set_peripheral_state(PeripheralState::Advertising, None),
+        Err(e) => {
+            error!("[BLE] Failed to start advertising: {}", e);
+            health.set_peripheral_state(PeripheralState::Failed, Some(e.to_string()));
+            metrics().record_ble_error(BleError::Advertising);
+        }
+    }
 
     while let Some(event) = event_rx.recv().await {
         match event {
@@ -92,38 +110,112 @@ pub async fn ble_peripheral(payload_tx: UnboundedSender<PartialPayload>) {
                     *data = value.clone();
                 }
 
+                metrics().record_ble_write();
                 write_buffer.extend_from_slice(&value);
 
-                if let Ok(text) = String::from_utf8(write_buffer.clone()) {
-                    let mut success = false;
-
-                    if text.contains('\n') {
-                        for line in text.lines() {
-                            if let Ok(payload) = serde_json::from_str::<PartialPayload>(line) {
-                                let _ = payload_tx.send(payload);
-                                success = true;
-                            }
+                for payload in drain_payloads(&mut write_buffer) {
+                    match payload {
+                        Ok(payload) => {
+                            health.record_ble_payload();
+                            metrics().record_ble_payload();
+                            let _ = payload_tx.send(payload);
+                        }
+                        Err(e) => {
+                            tracing::warn!("[BLE] Discarding invalid payload: {}", e);
+                            metrics().record_ble_error(BleError::InvalidPayload);
                         }
-                    } else if let Ok(payload) = serde_json::from_str::<PartialPayload>(&text) {
-                        let _ = payload_tx.send(payload);
-                        success = true;
-                    }
-
-                    if success {
-                        write_buffer.clear();
                     }
                 }
 
                 if write_buffer.len() > 2048 {
+                    tracing::warn!(
+                        "[BLE] Discarding {} bytes without a payload",
+                        write_buffer.len()
+                    );
+                    metrics().record_ble_error(BleError::Overflow);
                     write_buffer.clear();
                 }
             }
 
             _ => {
-                let _ = peripheral
+                if peripheral
                     .start_advertising("Serviceberry", &[service_uuid])
-                    .await;
+                    .await
+                    .is_ok()
+                {
+                    health.set_peripheral_state(PeripheralState::Advertising, None);
+                }
             }
         }
     }
 }
+
+/// Takes every newline-terminated payload out of `buffer`, parsed or not, leaving
+/// only the fragment after the last newline. A fragment that already parses on its
+/// own is taken too, for clients that send one payload per write without a newline.
+fn drain_payloads(buffer: &mut Vec<u8>) -> Vec<serde_json::Result<PartialPayload>> {
+    let mut payloads = Vec::new();
+
+    if let Some(last) = buffer.iter().rposition(|&b| b == b'\n') {
+        let lines: Vec<u8> = buffer.drain(..=last).collect();
+        for line in lines.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if !line.is_empty() {
+                payloads.push(serde_json::from_slice(line));
+            }
+        }
+    }
+
+    if !buffer.is_empty()
+        && let Ok(payload) = serde_json::from_slice(buffer)
+    {
+        payloads.push(Ok(payload));
+        buffer.clear();
+    }
+
+    payloads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_invalid_lines_and_keeps_the_trailing_fragment() {
+        let mut buffer = b"not json\n{\"position\":null}\r\n{\"posi".to_vec();
+
+        let payloads = drain_payloads(&mut buffer);
+        assert_eq!(payloads.len(), 2);
+        assert!(payloads[0].is_err());
+        assert!(payloads[1].is_ok());
+        assert_eq!(buffer, b"{\"posi");
+
+        // the invalid line is gone, and the fragment completes with the next write
+        buffer.extend_from_slice(b"tion\":null}\n");
+        let payloads = drain_payloads(&mut buffer);
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].is_ok());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn invalid_lines_are_dropped_even_when_nothing_parses() {
+        let mut buffer = b"[1, 2]\n{\"cell".to_vec();
+
+        let payloads = drain_payloads(&mut buffer);
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].is_err());
+        assert_eq!(buffer, b"{\"cell");
+        assert!(drain_payloads(&mut buffer).is_empty());
+    }
+
+    #[test]
+    fn accepts_a_whole_payload_without_a_newline() {
+        let mut buffer = b"{\"position\":null}".to_vec();
+
+        let payloads = drain_payloads(&mut buffer);
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].is_ok());
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+//! Choosing a submission's position when several sources have one
+//!
+//! Each fix is judged by its accuracy, worsened by how old it is. The best fix
+//! wins; with fusion enabled, it's combined with any others that agree with it,
+//! weighting each by the inverse of its variance.
+
+use crate::config::FIX_AGE_PENALTY_METERS_PER_SEC;
+use crate::geosubmit::{LocationSource, Position};
+
+use super::source::Fix;
+
+/// Below this, a fix's claimed accuracy isn't trusted
+const MIN_ACCURACY_METERS: f64 = 1.0;
+
+/// Pick the best of `candidates`, fusing it with those that agree when `fuse` is set
+pub fn arbitrate(candidates: Vec<Fix>, fuse: bool) -> Option<Fix> {
+    let best = candidates
+        .iter()
+        .min_by(|a, b| effective_accuracy(a).total_cmp(&effective_accuracy(b)))?
+        .clone();
+    if !fuse {
+        return Some(best);
+    }
+
+    // Fixes whose error circles overlap the best one's
+    let agreeing: Vec<&Fix> = candidates
+        .iter()
+        .filter(|fix| {
+            fix.position.distance_to(&best.position)
+                <= effective_accuracy(fix) + effective_accuracy(&best)
+        })
+        .collect();
+    if agreeing.len() < 2 {
+        return Some(best);
+    }
+
+    let mut total_weight = 0.0;
+    let (mut latitude, mut longitude) = (0.0, 0.0);
+    for fix in &agreeing {
+        let weight = effective_accuracy(fix).powi(-2);
+        total_weight += weight;
+        latitude += weight * fix.position.latitude;
+        // Relative to the best fix, so fixes either side of the antimeridian average correctly
+        longitude += weight * wrap_longitude(fix.position.longitude - best.position.longitude);
+    }
+
+    let position = Position {
+        latitude: latitude / total_weight,
+        longitude: wrap_longitude(best.position.longitude + longitude / total_weight),
+        accuracy: total_weight.sqrt().recip(),
+        heading: agreeing.iter().find_map(|fix| fix.position.heading),
+        speed: agreeing.iter().find_map(|fix| fix.position.speed),
+        source: LocationSource::Fused,
+        ..best.position
+    };
+    let time = agreeing
+        .iter()
+        .map(|fix| fix.time)
+        .max()
+        .unwrap_or(best.time);
+
+    Some(Fix { position, time })
+}
+
+/// Claimed accuracy plus the distance the receiver could have moved since
+fn effective_accuracy(fix: &Fix) -> f64 {
+    fix.position.accuracy.max(MIN_ACCURACY_METERS)
+        + fix.age().as_secs_f64() * FIX_AGE_PENALTY_METERS_PER_SEC
+}
+
+fn wrap_longitude(longitude: f64) -> f64 {
+    (longitude + 180.0).rem_euclid(360.0) - 180.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::now_millis;
+    use crate::position::source::{PositionSource, recent_fixes};
+    use crate::test_support::position;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct Stub(Fix);
+
+    impl PositionSource for Stub {
+        fn name(&self) -> &'static str {
+            "stub"
+        }
+
+        fn spawn(self: Arc<Self>) {}
+
+        fn latest(&self) -> Option<Fix> {
+            Some(self.0.clone())
+        }
+    }
+
+    fn fix(latitude: f64, longitude: f64, accuracy: f64, age_secs: i64) -> Fix {
+        Fix {
+            position: Position {
+                accuracy,
+                ..position(latitude, longitude)
+            },
+            time: now_millis() - age_secs * 1000,
+        }
+    }
+
+    #[test]
+    fn picks_the_most_accurate_fix_without_fusing() {
+        let best = arbitrate(
+            vec![fix(52.0, 13.0, 20.0, 0), fix(52.0001, 13.0, 5.0, 0)],
+            false,
+        )
+        .unwrap();
+        assert_eq!(best.position.latitude, 52.0001);
+        assert_eq!(best.position.accuracy, 5.0);
+        assert_eq!(best.position.source, LocationSource::Gps);
+
+        assert!(arbitrate(Vec::new(), true).is_none());
+    }
+
+    #[test]
+    fn older_fixes_count_as_less_accurate() {
+        // 10 m, but 5 s old: 10 + 5 * 5 = 35 m against a fresh 20 m
+        let best = arbitrate(
+            vec![fix(52.0, 13.0, 10.0, 5), fix(52.0001, 13.0, 20.0, 0)],
+            false,
+        )
+        .unwrap();
+        assert_eq!(best.position.latitude, 52.0001);
+    }
+
+    #[test]
+    fn stale_fixes_are_not_candidates() {
+        let sources: Vec<Arc<dyn PositionSource>> = vec![
+            Arc::new(Stub(fix(52.0, 13.0, 1.0, 30))),
+            Arc::new(Stub(fix(52.0001, 13.0, 50.0, 2))),
+        ];
+
+        let candidates = recent_fixes(&sources, Duration::from_secs(10));
+        assert_eq!(candidates.len(), 1);
+        let best = arbitrate(candidates, true).unwrap();
+        assert_eq!(best.position.latitude, 52.0001);
+    }
+
+    #[test]
+    fn fuses_agreeing_fixes_by_inverse_variance() {
+        let older = fix(52.0, 13.0, 10.0, 0);
+        let fused = arbitrate(vec![older.clone(), fix(52.0001, 13.0001, 5.0, 0)], true).unwrap();
+
+        // weights 1/25 and 1/100: four fifths of the way to the more accurate fix
+        assert!((fused.position.latitude - 52.00008).abs() < 1e-9);
+        assert!((fused.position.longitude - 13.00008).abs() < 1e-9);
+        let accuracy = (1.0 / 25.0 + 1.0 / 100.0_f64).sqrt().recip();
+        assert!((fused.position.accuracy - accuracy).abs() < 1e-9);
+        assert_eq!(fused.position.source, LocationSource::Fused);
+        assert!(fused.time >= older.time);
+    }
+
+    #[test]
+    fn leaves_disagreeing_fixes_out() {
+        // about 1.1 km apart, with 10 m and 5 m error circles
+        let best = arbitrate(
+            vec![fix(52.0, 13.0, 10.0, 0), fix(52.01, 13.0, 5.0, 0)],
+            true,
+        )
+        .unwrap();
+        assert_eq!(best.position.latitude, 52.01);
+        assert_eq!(best.position.source, LocationSource::Gps);
+    }
+
+    #[test]
+    fn fuses_across_the_antimeridian() {
+        let fused = arbitrate(
+            vec![fix(0.0, 179.99995, 10.0, 0), fix(0.0, -179.99995, 10.0, 0)],
+            true,
+        )
+        .unwrap();
+        assert_eq!(fused.position.source, LocationSource::Fused);
+        assert!((fused.position.longitude.abs() - 180.0).abs() < 1e-9);
+    }
+}
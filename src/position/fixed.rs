@@ -0,0 +1,34 @@
+//! A position that never changes, such as a stationary node's configured location
+
+use std::sync::Arc;
+
+use crate::clock::now_millis;
+use crate::geosubmit::Position;
+
+use super::source::{Fix, PositionSource};
+
+pub struct FixedSource {
+    position: Position,
+}
+
+impl FixedSource {
+    pub fn new(position: Position) -> Self {
+        FixedSource { position }
+    }
+}
+
+impl PositionSource for FixedSource {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn spawn(self: Arc<Self>) {}
+
+    /// Always current: the position holds for as long as the node stays put
+    fn latest(&self) -> Option<Fix> {
+        Some(Fix {
+            position: self.position.clone(),
+            time: now_millis(),
+        })
+    }
+}
@@ -0,0 +1,317 @@
+//! gpsd client: positions from a GPS receiver attached to this machine
+//!
+//! Connects to gpsd's JSON protocol, enables watch mode, and turns TPV reports
+//! into fixes. SKY reports supply the satellite count, and an HDOP-based accuracy
+//! when a TPV carries no error estimates.
+
+use serde::Deserialize;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::clock::{now_millis, parse_time};
+use crate::config::{GPS_UERE_METERS, GPSD_RECONNECT_SECS};
+use crate::error::Result;
+use crate::geosubmit::{LocationSource, Position};
+
+use super::source::{Fix, FixSlot, PositionSource, SourceStatus};
+
+const WATCH_COMMAND: &[u8] = b"?WATCH={\"enable\":true,\"json\":true}\n";
+
+/// One line of gpsd output; only the classes we use are decoded
+#[derive(Deserialize, Debug)]
+#[serde(tag = "class")]
+enum Report {
+    #[serde(rename = "TPV")]
+    Tpv(Tpv),
+    #[serde(rename = "SKY")]
+    Sky(Sky),
+    #[serde(other)]
+    Other,
+}
+
+/// Time-position-velocity report
+#[derive(Deserialize, Debug, Default)]
+#[allow(non_snake_case)]
+struct Tpv {
+    /// 0/1: no fix, 2: 2D, 3: 3D
+    #[serde(default)]
+    mode: u8,
+    time: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    /// Altitude above mean sea level; `alt` in gpsd before 3.20
+    altMSL: Option<f64>,
+    alt: Option<f64>,
+    /// Estimated errors in meters, at 95% confidence
+    epx: Option<f64>,
+    epy: Option<f64>,
+    epv: Option<f64>,
+    /// Meters per second
+    speed: Option<f64>,
+    /// Degrees clockwise from true north
+    track: Option<f64>,
+}
+
+/// Satellite report
+#[derive(Deserialize, Debug, Default)]
+#[allow(non_snake_case)]
+struct Sky {
+    hdop: Option<f64>,
+    uSat: Option<u32>,
+    #[serde(default)]
+    satellites: Vec<Satellite>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Satellite {
+    #[serde(default)]
+    used: bool,
+}
+
+pub struct GpsdSource {
+    address: String,
+    fix: FixSlot,
+    connected: AtomicBool,
+    /// From the latest SKY report
+    sky: Mutex<Option<(Option<f64>, u32)>>,
+    reconnect_delay: Duration,
+}
+
+impl GpsdSource {
+    /// `address` is gpsd's `host:port`, usually `127.0.0.1:2947`
+    pub fn new(address: String) -> Self {
+        GpsdSource {
+            address,
+            fix: FixSlot::default(),
+            connected: AtomicBool::new(false),
+            sky: Mutex::new(None),
+            reconnect_delay: Duration::from_secs(GPSD_RECONNECT_SECS),
+        }
+    }
+
+    /// Stay connected to gpsd, reconnecting whenever the connection drops
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            if let Err(e) = self.session().await {
+                tracing::warn!("[gpsd] {}: {}", self.address, e);
+            }
+            self.connected.store(false, Ordering::Relaxed);
+            tokio::time::sleep(self.reconnect_delay).await;
+        }
+    }
+
+    async fn session(&self) -> Result<()> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(WATCH_COMMAND).await?;
+
+        self.connected.store(true, Ordering::Relaxed);
+        tracing::info!("[gpsd] Connected to {}", self.address);
+
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            self.handle_report(&line);
+        }
+
+        tracing::warn!("[gpsd] {} closed the connection", self.address);
+        Ok(())
+    }
+
+    /// Decode one line of gpsd output, updating the fix or satellite state
+    pub fn handle_report(&self, line: &str) {
+        let report = match serde_json::from_str::<Report>(line) {
+            Ok(report) => report,
+            Err(e) => {
+                tracing::debug!("[gpsd] Ignoring unreadable report: {}", e);
+                return;
+            }
+        };
+
+        match report {
+            Report::Tpv(tpv) => {
+                if let Some(fix) = self.fix_from(tpv) {
+                    self.fix.set(fix);
+                }
+            }
+            Report::Sky(sky) => {
+                let used = sky
+                    .uSat
+                    .unwrap_or_else(|| sky.satellites.iter().filter(|s| s.used).count() as u32);
+                *self.sky.lock().unwrap() = Some((sky.hdop, used));
+            }
+            Report::Other => {}
+        }
+    }
+
+    fn fix_from(&self, tpv: Tpv) -> Option<Fix> {
+        if tpv.mode < 2 {
+            return None;
+        }
+
+        // Horizontal error is the combined east and north error; without those,
+        // estimate it from the satellite geometry
+        let accuracy = match (tpv.epx, tpv.epy) {
+            (Some(epx), Some(epy)) => epx.hypot(epy),
+            _ => {
+                let hdop = self.sky.lock().unwrap().and_then(|(hdop, _)| hdop)?;
+                hdop * GPS_UERE_METERS
+            }
+        };
+
+        let three_d = tpv.mode >= 3;
+        let position = Position {
+            latitude: tpv.lat?,
+            longitude: tpv.lon?,
+            accuracy,
+            altitude: if three_d {
+                tpv.altMSL.or(tpv.alt).unwrap_or(0.0)
+            } else {
+                0.0
+            },
+            altitudeAccuracy: if three_d { tpv.epv.unwrap_or(0.0) } else { 0.0 },
+            heading: tpv.track,
+            speed: tpv.speed,
+            source: LocationSource::Gps,
+        };
+        position.validate().ok()?;
+
+        let time = tpv
+            .time
+            .as_deref()
+            .and_then(|time| parse_time(time).ok())
+            .unwrap_or_else(now_millis);
+
+        Some(Fix { position, time })
+    }
+}
+
+impl PositionSource for GpsdSource {
+    fn name(&self) -> &'static str {
+        "gpsd"
+    }
+
+    fn spawn(self: Arc<Self>) {
+        tokio::spawn(self.run());
+    }
+
+    fn latest(&self) -> Option<Fix> {
+        self.fix.get()
+    }
+
+    fn status(&self) -> SourceStatus {
+        let last_fix = self.latest();
+        SourceStatus {
+            name: self.name(),
+            connected: self.connected.load(Ordering::Relaxed),
+            last_fix_age_secs: last_fix.as_ref().map(|fix| fix.age().as_secs()),
+            last_fix,
+            satellites_used: self.sky.lock().unwrap().map(|(_, used)| used),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    /// A gpsd 3.25 session with a u-blox receiver and a 3D fix
+    const SESSION_3D: &str = include_str!("../../tests/fixtures/gpsd/3d-fix.jsonl");
+    /// A gpsd 3.17 session with an NMEA receiver that reports no error estimates
+    const SESSION_2D: &str = include_str!("../../tests/fixtures/gpsd/2d-fix-without-errors.jsonl");
+
+    /// A gpsd that replays one session per connection, closing all but the last
+    async fn fake_gpsd(sessions: &'static [&'static str]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut last = None;
+            for session in sessions {
+                // closing the previous connection makes the client reconnect
+                last.take();
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut command = String::new();
+                BufReader::new(reader)
+                    .read_line(&mut command)
+                    .await
+                    .unwrap();
+                assert_eq!(command.as_bytes(), WATCH_COMMAND);
+
+                writer.write_all(session.as_bytes()).await.unwrap();
+                last = Some(writer);
+            }
+            std::future::pending::<()>().await;
+        });
+        address
+    }
+
+    fn source(address: SocketAddr) -> Arc<GpsdSource> {
+        Arc::new(GpsdSource {
+            reconnect_delay: Duration::from_millis(50),
+            ..GpsdSource::new(address.to_string())
+        })
+    }
+
+    async fn fix_at(source: &GpsdSource, latitude: f64) -> Fix {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match source.latest() {
+                    Some(fix) if fix.position.latitude == latitude => return fix,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("no fix from the fake gpsd")
+    }
+
+    #[tokio::test]
+    async fn reads_errors_from_tpv() {
+        let gpsd = source(fake_gpsd(&[SESSION_3D]).await);
+        tokio::spawn(gpsd.clone().run());
+
+        let fix = fix_at(&gpsd, 52.516275).await;
+        assert_eq!(fix.position.longitude, 13.377704);
+        assert_eq!(fix.position.accuracy, 5.0);
+        assert_eq!(fix.position.altitude, 34.2);
+        assert_eq!(fix.position.altitudeAccuracy, 6.1);
+        assert_eq!(fix.position.heading, Some(87.5));
+        assert_eq!(fix.position.speed, Some(1.2));
+        assert_eq!(fix.time, parse_time("2025-06-01T10:00:01.000Z").unwrap());
+
+        let status = gpsd.status();
+        assert!(status.connected);
+        assert_eq!(status.satellites_used, Some(9));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_hdop_and_reconnects() {
+        let gpsd = source(fake_gpsd(&[SESSION_3D, SESSION_2D]).await);
+        tokio::spawn(gpsd.clone().run());
+
+        fix_at(&gpsd, 52.516275).await;
+        // the first session closes; the second comes from a new connection
+        let fix = fix_at(&gpsd, 48.85837).await;
+        assert_eq!(fix.position.accuracy, 1.4 * GPS_UERE_METERS);
+        assert_eq!(fix.position.altitude, 0.0);
+        assert_eq!(fix.position.altitudeAccuracy, 0.0);
+
+        let status = gpsd.status();
+        assert!(status.connected);
+        assert_eq!(status.satellites_used, Some(3));
+    }
+
+    #[test]
+    fn skips_tpv_without_a_fix_or_error_estimate() {
+        let gpsd = GpsdSource::new(crate::config::GPSD_ADDRESS.to_string());
+        gpsd.handle_report(r#"{"class":"TPV","mode":1,"lat":48.0,"lon":2.0,"epx":3,"epy":4}"#);
+        gpsd.handle_report(r#"{"class":"TPV","mode":2,"lat":48.0,"lon":2.0}"#);
+        assert!(gpsd.latest().is_none());
+    }
+}
@@ -0,0 +1,431 @@
+//! NMEA 0183 source: positions straight from a GPS receiver's serial output
+//!
+//! Reads sentences line by line from a serial device (or a pty) or a recorded
+//! log. GGA and RMC sentences carry the position and each produce a fix; GSA
+//! supplies the fix type and dilution of precision, VTG speed and heading.
+//! Sentences with a missing or wrong checksum are dropped.
+//!
+//! A recorded log is replayed at the pace it was recorded, and its fixes are
+//! stamped with the time they're replayed, so they're as fresh as live ones.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::time::Instant;
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::clock::{now_millis, parse_time};
+use crate::config::{GPS_UERE_METERS, NMEA_REOPEN_SECS};
+use crate::error::Result;
+use crate::geosubmit::{LocationSource, Position};
+
+use super::source::{Fix, FixSlot, PositionSource, SourceStatus};
+
+const KNOTS_TO_METERS_PER_SECOND: f64 = 1852.0 / 3600.0;
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+/// What the sentences of the current epoch have told us so far
+#[derive(Debug, Default)]
+struct Receiver {
+    /// Midnight UTC of the date from the last RMC, in milliseconds
+    date: Option<i64>,
+    /// GSA fix type: 1 no fix, 2 2D, 3 3D
+    fix_type: Option<u8>,
+    hdop: Option<f64>,
+    vdop: Option<f64>,
+    satellites_used: Option<u32>,
+    altitude: Option<f64>,
+    /// Meters per second
+    speed: Option<f64>,
+    /// Degrees clockwise from true north
+    heading: Option<f64>,
+}
+
+pub struct NmeaSource {
+    path: PathBuf,
+    baud_rate: u32,
+    fix: FixSlot,
+    connected: AtomicBool,
+    receiver: Mutex<Receiver>,
+}
+
+impl NmeaSource {
+    /// `path` is a serial device such as `/dev/ttyACM0`, or a recorded NMEA log
+    pub fn new(path: PathBuf, baud_rate: u32) -> Self {
+        NmeaSource {
+            path,
+            baud_rate,
+            fix: FixSlot::default(),
+            connected: AtomicBool::new(false),
+            receiver: Mutex::new(Receiver::default()),
+        }
+    }
+
+    /// Read a device for as long as the process runs, reopening it whenever it
+    /// goes away. A regular file is replayed once.
+    pub async fn run(self: Arc<Self>) {
+        let delay = Duration::from_secs(NMEA_REOPEN_SECS);
+        loop {
+            let replay = tokio::fs::metadata(&self.path)
+                .await
+                .is_ok_and(|metadata| metadata.is_file());
+
+            match self.read(replay).await {
+                Ok(()) if replay => {
+                    tracing::info!("[NMEA] Finished replaying {}", self.path.display());
+                    self.connected.store(false, Ordering::Relaxed);
+                    return;
+                }
+                Ok(()) => tracing::warn!("[NMEA] {} closed", self.path.display()),
+                Err(e) => tracing::warn!("[NMEA] {}: {}", self.path.display(), e),
+            }
+            self.connected.store(false, Ordering::Relaxed);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn read(&self, replay: bool) -> Result<()> {
+        if replay {
+            let file = File::open(&self.path).await?;
+            self.connected.store(true, Ordering::Relaxed);
+            tracing::info!("[NMEA] Replaying {}", self.path.display());
+            return self.read_sentences(file, true).await;
+        }
+
+        // Opening the port makes it raw, so the line discipline doesn't touch the sentences
+        let port = tokio_serial::new(self.path.to_string_lossy(), self.baud_rate)
+            .open_native_async()
+            .map_err(std::io::Error::from)?;
+        self.connected.store(true, Ordering::Relaxed);
+        tracing::info!(
+            "[NMEA] Reading {} at {} baud",
+            self.path.display(),
+            self.baud_rate
+        );
+        self.read_sentences(port, false).await
+    }
+
+    /// Handle sentences until the end of `input`. When replaying, each fix waits
+    /// until as long after the first as it was recorded, then takes the current time.
+    async fn read_sentences(&self, input: impl AsyncRead + Unpin, replay: bool) -> Result<()> {
+        let mut input = BufReader::new(input);
+        let mut line = Vec::new();
+        // The first replayed fix's recorded time, and when it was replayed
+        let mut replay_start: Option<(i64, Instant)> = None;
+
+        loop {
+            line.clear();
+            if input.read_until(b'\n', &mut line).await? == 0 {
+                return Ok(());
+            }
+            // Noise on the line, e.g. at the wrong baud rate, fails the checksum
+            let Some(mut fix) = self.handle_sentence(&String::from_utf8_lossy(&line)) else {
+                continue;
+            };
+
+            if replay {
+                let (recorded, replayed) = *replay_start.get_or_insert((fix.time, Instant::now()));
+                let offset = u64::try_from(fix.time - recorded).unwrap_or(0);
+                tokio::time::sleep_until(replayed + Duration::from_millis(offset)).await;
+                fix.time = now_millis();
+            }
+            self.fix.set(fix);
+        }
+    }
+
+    /// Decode one NMEA sentence, updating the receiver state. Returns the fix
+    /// it completes, if any.
+    pub fn handle_sentence(&self, line: &str) -> Option<Fix> {
+        let Some(body) = checked_body(line.trim()) else {
+            tracing::debug!("[NMEA] Ignoring invalid sentence: {}", line.trim());
+            return None;
+        };
+
+        let fields: Vec<&str> = body.split(',').collect();
+        // The talker (GP, GN, GL, ...) doesn't matter, only the sentence type
+        let kind = fields[0].get(2..)?;
+
+        let mut receiver = self.receiver.lock().unwrap();
+        match kind {
+            "GGA" => receiver.gga(&fields),
+            "RMC" => receiver.rmc(&fields),
+            "GSA" => {
+                receiver.gsa(&fields);
+                None
+            }
+            "VTG" => {
+                receiver.vtg(&fields);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Receiver {
+    /// `$GPGGA,time,lat,N,lon,E,quality,satellites,hdop,altitude,M,...`
+    fn gga(&mut self, fields: &[&str]) -> Option<Fix> {
+        let quality: u8 = field(fields, 6)?;
+        if quality == 0 {
+            return None;
+        }
+        self.satellites_used = field(fields, 7).or(self.satellites_used);
+        self.hdop = field(fields, 8).or(self.hdop);
+        self.altitude = field(fields, 9);
+
+        let time = self.time(fields.get(1)?)?;
+        self.fix(fields.get(2..6)?, time)
+    }
+
+    /// `$GPRMC,time,status,lat,N,lon,E,knots,course,ddmmyy,...`
+    fn rmc(&mut self, fields: &[&str]) -> Option<Fix> {
+        if let Some(date) = fields.get(9).and_then(|date| parse_date(date)) {
+            self.date = Some(date);
+        }
+        if fields.get(2) != Some(&"A") {
+            return None;
+        }
+        if let Some(knots) = field::<f64>(fields, 7) {
+            self.speed = Some(knots * KNOTS_TO_METERS_PER_SECOND);
+        }
+        self.heading = field(fields, 8);
+
+        let time = self.time(fields.get(1)?)?;
+        self.fix(fields.get(3..7)?, time)
+    }
+
+    /// `$GPGSA,mode,fix type,12 satellite IDs,pdop,hdop,vdop`
+    fn gsa(&mut self, fields: &[&str]) {
+        self.fix_type = field(fields, 2);
+        if let Some(used) = fields.get(3..15) {
+            self.satellites_used = Some(used.iter().filter(|id| !id.is_empty()).count() as u32);
+        }
+        self.hdop = field(fields, 16).or(self.hdop);
+        self.vdop = field(fields, 17).or(self.vdop);
+    }
+
+    /// `$GPVTG,true course,T,magnetic course,M,knots,N,km/h,K`
+    fn vtg(&mut self, fields: &[&str]) {
+        self.heading = field(fields, 1);
+        if let Some(kmh) = field::<f64>(fields, 7) {
+            self.speed = Some(kmh / 3.6);
+        }
+    }
+
+    /// `hhmmss.ss` on the last RMC date, or on today's when no RMC has been seen
+    fn time(&self, field: &str) -> Option<i64> {
+        let of_day = parse_time_of_day(field)?;
+        if let Some(date) = self.date {
+            return Some(date + of_day);
+        }
+
+        // Just after midnight the receiver may still report yesterday's times
+        let now = now_millis();
+        let time = now - now.rem_euclid(MILLIS_PER_DAY) + of_day;
+        Some(if time > now + MILLIS_PER_DAY / 2 {
+            time - MILLIS_PER_DAY
+        } else {
+            time
+        })
+    }
+
+    /// Build a fix from `lat,N,lon,E` and what's known about the current epoch
+    fn fix(&self, coordinates: &[&str], time: i64) -> Option<Fix> {
+        if self.fix_type == Some(1) {
+            return None;
+        }
+        let latitude = parse_coordinate(coordinates[0], coordinates[1], 'S')?;
+        let longitude = parse_coordinate(coordinates[2], coordinates[3], 'W')?;
+
+        let three_d = self.fix_type != Some(2);
+        let position = Position {
+            latitude,
+            longitude,
+            accuracy: self.hdop? * GPS_UERE_METERS,
+            altitude: self.altitude.filter(|_| three_d).unwrap_or(0.0),
+            altitudeAccuracy: self
+                .vdop
+                .filter(|_| three_d)
+                .map(|vdop| vdop * GPS_UERE_METERS)
+                .unwrap_or(0.0),
+            heading: self.heading,
+            speed: self.speed,
+            source: LocationSource::Gps,
+        };
+        position.validate().ok()?;
+
+        Some(Fix { position, time })
+    }
+}
+
+impl PositionSource for NmeaSource {
+    fn name(&self) -> &'static str {
+        "nmea"
+    }
+
+    fn spawn(self: Arc<Self>) {
+        tokio::spawn(self.run());
+    }
+
+    fn latest(&self) -> Option<Fix> {
+        self.fix.get()
+    }
+
+    fn status(&self) -> SourceStatus {
+        let last_fix = self.latest();
+        SourceStatus {
+            name: self.name(),
+            connected: self.connected.load(Ordering::Relaxed),
+            last_fix_age_secs: last_fix.as_ref().map(|fix| fix.age().as_secs()),
+            last_fix,
+            satellites_used: self.receiver.lock().unwrap().satellites_used,
+        }
+    }
+}
+
+/// The part of `$<body>*<checksum>` between `$` and `*`, if the checksum matches
+fn checked_body(sentence: &str) -> Option<&str> {
+    let (body, checksum) = sentence.strip_prefix('$')?.split_once('*')?;
+    let expected = u8::from_str_radix(checksum, 16).ok()?;
+    let actual = body.bytes().fold(0, |sum, byte| sum ^ byte);
+    (actual == expected).then_some(body)
+}
+
+fn field<T: std::str::FromStr>(fields: &[&str], index: usize) -> Option<T> {
+    fields.get(index)?.parse().ok()
+}
+
+/// `ddmm.mmmm` or `dddmm.mmmm` and a hemisphere letter, as decimal degrees
+fn parse_coordinate(value: &str, hemisphere: &str, negative: char) -> Option<f64> {
+    let value: f64 = value.parse().ok()?;
+    let degrees = (value / 100.0).trunc();
+    let decimal = degrees + (value - degrees * 100.0) / 60.0;
+    Some(if hemisphere.starts_with(negative) {
+        -decimal
+    } else {
+        decimal
+    })
+}
+
+/// `hhmmss.ss` as milliseconds since midnight
+fn parse_time_of_day(value: &str) -> Option<i64> {
+    let hours: i64 = value.get(0..2)?.parse().ok()?;
+    let minutes: i64 = value.get(2..4)?.parse().ok()?;
+    let seconds: f64 = value.get(4..)?.parse().ok()?;
+    if hours > 23 || minutes > 59 || !(0.0..61.0).contains(&seconds) {
+        return None;
+    }
+    Some((hours * 60 + minutes) * 60_000 + (seconds * 1000.0).round() as i64)
+}
+
+/// RMC's `ddmmyy` as midnight UTC in milliseconds
+fn parse_date(value: &str) -> Option<i64> {
+    if value.len() != 6 || !value.is_ascii() {
+        return None;
+    }
+    // GPS dates start in 1980
+    let year: i64 = value[4..6].parse().ok()?;
+    let century = if year >= 80 { 1900 } else { 2000 };
+    let date = format!("{}-{}-{}", century + year, &value[2..4], &value[0..2]);
+    parse_time(&date).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MAX_FIX_AGE_SECS, NMEA_BAUD_RATE, NMEA_DEVICE};
+    use crate::position::source::recent_fixes;
+    use tokio::io::AsyncWriteExt;
+    use tokio_serial::{SerialPort, SerialStream};
+
+    /// A u-blox receiver at 1 Hz with a 3D fix, three epochs of walking east
+    const WALK_3D: &str = "tests/fixtures/nmea/walk-3d.nmea";
+    /// A GPS-only receiver with a 2D fix, with one sentence corrupted in transit
+    const FIX_2D: &str = include_str!("../../tests/fixtures/nmea/2d-fix.nmea");
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_a_log_at_its_pace_with_fresh_fixes() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(WALK_3D);
+        let source = Arc::new(NmeaSource::new(path, NMEA_BAUD_RATE));
+
+        let started = Instant::now();
+        source.clone().run().await;
+        // the first and last fixes were recorded two seconds apart
+        assert_eq!(started.elapsed().as_secs(), 2);
+        assert!(!source.status().connected);
+
+        let sources: Vec<Arc<dyn PositionSource>> = vec![source];
+        let fixes = recent_fixes(&sources, Duration::from_secs(MAX_FIX_AGE_SECS));
+        assert_eq!(fixes.len(), 1);
+
+        let position = &fixes[0].position;
+        assert_near(position.latitude, 52.0 + 30.97654 / 60.0);
+        assert_near(position.longitude, 13.0 + 22.66576 / 60.0);
+        assert_near(position.accuracy, 0.8 * GPS_UERE_METERS);
+        assert_near(position.altitude, 34.2);
+        assert_near(position.altitudeAccuracy, 1.21 * GPS_UERE_METERS);
+        assert_eq!(position.heading, Some(87.5));
+    }
+
+    #[tokio::test]
+    async fn reads_a_serial_device() {
+        let (mut receiver, device) = SerialStream::pair().unwrap();
+        let path = PathBuf::from(device.name().unwrap());
+        let source = Arc::new(NmeaSource::new(path, NMEA_BAUD_RATE));
+        tokio::spawn(source.clone().run());
+
+        receiver.write_all(FIX_2D.as_bytes()).await.unwrap();
+        let fix = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match source.latest() {
+                    Some(fix) if fix.time == parse_time("2025-06-01T11:00:03Z").unwrap() => {
+                        return fix;
+                    }
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("no fix from the pty");
+
+        assert_near(fix.position.latitude, 48.0 + 51.503 / 60.0);
+        assert_near(fix.position.longitude, 2.0 + 17.67 / 60.0);
+        assert_near(fix.position.accuracy, 1.4 * GPS_UERE_METERS);
+        // a 2D fix has no altitude
+        assert_eq!(fix.position.altitude, 0.0);
+        assert_eq!(fix.position.altitudeAccuracy, 0.0);
+
+        let status = source.status();
+        assert!(status.connected);
+        assert_eq!(status.satellites_used, Some(3));
+    }
+
+    #[test]
+    fn drops_sentences_with_a_bad_checksum() {
+        let source = NmeaSource::new(PathBuf::from(NMEA_DEVICE), NMEA_BAUD_RATE);
+        let corrupted = FIX_2D.lines().nth(2).unwrap();
+        let intact = FIX_2D.lines().nth(1).unwrap();
+
+        source.handle_sentence(FIX_2D.lines().next().unwrap());
+        assert!(source.handle_sentence(corrupted).is_none());
+        assert!(source.handle_sentence(intact).is_some());
+        assert!(
+            source
+                .handle_sentence(intact.trim_end_matches(|c| c != '*'))
+                .is_none()
+        );
+    }
+}
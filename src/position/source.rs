@@ -0,0 +1,80 @@
+//! Where positions come from when the phone doesn't send one
+//!
+//! Each source runs in the background and keeps its most recent fix, which
+//! submissions can use in place of a position from the client.
+
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::now_millis;
+use crate::geosubmit::Position;
+
+/// A position and when it was measured
+#[derive(Serialize, Debug, Clone)]
+pub struct Fix {
+    pub position: Position,
+    /// When the receiver took the fix, in milliseconds since the Unix epoch
+    pub time: i64,
+}
+
+impl Fix {
+    pub fn age(&self) -> Duration {
+        Duration::from_millis(now_millis().saturating_sub(self.time).max(0) as u64)
+    }
+}
+
+/// What `/status` reports about a position source
+#[derive(Serialize, Debug, Clone)]
+pub struct SourceStatus {
+    pub name: &'static str,
+    pub connected: bool,
+    pub last_fix: Option<Fix>,
+    pub last_fix_age_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub satellites_used: Option<u32>,
+}
+
+pub trait PositionSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Start the source's background task
+    fn spawn(self: Arc<Self>);
+
+    /// Most recent fix, however old
+    fn latest(&self) -> Option<Fix>;
+
+    fn status(&self) -> SourceStatus {
+        let last_fix = self.latest();
+        SourceStatus {
+            name: self.name(),
+            connected: true,
+            last_fix_age_secs: last_fix.as_ref().map(|fix| fix.age().as_secs()),
+            last_fix,
+            satellites_used: None,
+        }
+    }
+}
+
+/// Latest fix of a source, shared between its task and readers
+#[derive(Default)]
+pub struct FixSlot(Mutex<Option<Fix>>);
+
+impl FixSlot {
+    pub fn set(&self, fix: Fix) {
+        *self.0.lock().unwrap() = Some(fix);
+    }
+
+    pub fn get(&self) -> Option<Fix> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Every source's latest fix that is no older than `max_age`
+pub fn recent_fixes(sources: &[Arc<dyn PositionSource>], max_age: Duration) -> Vec<Fix> {
+    sources
+        .iter()
+        .filter_map(|source| source.latest())
+        .filter(|fix| fix.age() <= max_age)
+        .collect()
+}
@@ -0,0 +1,104 @@
+//! Whether the WiFi and Bluetooth adapters are there and usable, for `/status`
+//!
+//! BlueZ can take a while to answer, so the Bluetooth adapter isn't probed per
+//! request: it's checked once at startup, then updated by every Bluetooth scan.
+
+use btleplug::api::{Central, Manager as _};
+use btleplug::platform::Manager;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::WIFI_INTERFACE;
+
+/// How long to wait for BlueZ before reporting the adapter as unreachable
+const BLUETOOTH_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The Bluetooth adapter as last seen by the probe or a scan
+static BLUETOOTH: Mutex<Option<AdapterStatus>> = Mutex::new(None);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AdapterStatus {
+    pub present: bool,
+    pub name: Option<String>,
+    /// Operational state as the OS reports it, e.g. `up`, `down` or `dormant`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AdapterStatus {
+    pub(crate) fn present(name: String) -> Self {
+        AdapterStatus {
+            present: true,
+            name: Some(name),
+            state: None,
+            error: None,
+        }
+    }
+
+    pub(crate) fn missing(error: String) -> Self {
+        AdapterStatus {
+            present: false,
+            name: None,
+            state: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// The scanning interface, as seen in sysfs
+pub fn wifi_status() -> AdapterStatus {
+    let interface = Path::new("/sys/class/net").join(WIFI_INTERFACE);
+    if !interface.join("wireless").exists() && !interface.join("phy80211").exists() {
+        return AdapterStatus::missing(format!("no wireless interface {}", WIFI_INTERFACE));
+    }
+
+    AdapterStatus {
+        present: true,
+        name: Some(WIFI_INTERFACE.to_string()),
+        state: std::fs::read_to_string(interface.join("operstate"))
+            .ok()
+            .map(|state| state.trim().to_string()),
+        error: None,
+    }
+}
+
+/// The first Bluetooth adapter BlueZ knows about, as last seen
+pub fn bluetooth_status() -> AdapterStatus {
+    BLUETOOTH
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+        .unwrap_or_else(|| AdapterStatus::missing("not checked yet".to_string()))
+}
+
+/// Remember what a Bluetooth scan found out about the adapter
+pub(crate) fn record_bluetooth_status(status: AdapterStatus) {
+    *BLUETOOTH
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(status);
+}
+
+/// Ask BlueZ for the first Bluetooth adapter, and remember the answer
+pub async fn probe_bluetooth() {
+    let probe = async {
+        let manager = Manager::new().await.map_err(|e| e.to_string())?;
+        let adapters = manager.adapters().await.map_err(|e| e.to_string())?;
+        let adapter = adapters
+            .into_iter()
+            .next()
+            .ok_or_else(|| "no Bluetooth adapter found".to_string())?;
+        adapter.adapter_info().await.map_err(|e| e.to_string())
+    };
+
+    record_bluetooth_status(
+        match tokio::time::timeout(BLUETOOTH_PROBE_TIMEOUT, probe).await {
+            Ok(Ok(info)) => AdapterStatus::present(info),
+            Ok(Err(e)) => AdapterStatus::missing(e),
+            Err(_) => AdapterStatus::missing("BlueZ didn't answer".to_string()),
+        },
+    );
+}
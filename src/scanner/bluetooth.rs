@@ -6,6 +6,9 @@ use std::time::Duration;
 use tokio::time;
 
 use crate::config::SCAN_DURATION_SECS;
+use crate::metrics::metrics;
+
+use super::adapter::{AdapterStatus, record_bluetooth_status};
 
 #[derive(Serialize, Debug, Deserialize, Clone)]
 pub struct BleDevice {
@@ -18,12 +21,17 @@ pub struct BleDevice {
 }
 
 pub async fn fetch_ble_devices() -> Vec<BleDevice> {
+    metrics().time_scan("bluetooth", scan()).await
+}
+
+async fn scan() -> Vec<BleDevice> {
     let mut devices = vec![];
 
     let manager = match Manager::new().await {
         Ok(m) => m,
         Err(e) => {
             println!("[BLE] Manager error: {:?}", e);
+            record_bluetooth_status(AdapterStatus::missing(e.to_string()));
             return devices;
         }
     };
@@ -34,16 +42,26 @@ pub async fn fetch_ble_devices() -> Vec<BleDevice> {
         Some(a) => a,
         None => {
             println!("[BLE] No adapters found");
+            record_bluetooth_status(AdapterStatus::missing(
+                "no Bluetooth adapter found".to_string(),
+            ));
             return devices;
         }
     };
+    let mut status = match adapter.adapter_info().await {
+        Ok(info) => AdapterStatus::present(info),
+        Err(e) => AdapterStatus::missing(e.to_string()),
+    };
 
     println!("[BLE] Starting BLE scan...");
 
     if let Err(e) = adapter.start_scan(ScanFilter::default()).await {
         println!("[BLE] Scan failed: {:?}", e);
+        status.error = Some(e.to_string());
+        record_bluetooth_status(status);
         return devices;
     }
+    record_bluetooth_status(status);
 
     time::sleep(Duration::from_secs(SCAN_DURATION_SECS)).await;
 
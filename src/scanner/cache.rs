@@ -0,0 +1,212 @@
+//! Recent scan results, so on-demand scans can be answered without rescanning
+//!
+//! A scan takes several seconds. Requests arriving while one is running wait for
+//! it and share its result instead of starting another.
+
+use serde::Serialize;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::clock::now_millis;
+
+use super::bluetooth::{self, BleDevice};
+use super::wifi::{self, WifiBssid};
+
+/// One radio's observations and when they were made
+#[derive(Serialize, Debug, Clone)]
+pub struct Scan<T> {
+    /// When the scan finished, in milliseconds since the Unix epoch
+    pub scanned_at: i64,
+    /// Whether this came from an earlier scan rather than a new one
+    pub cached: bool,
+    pub observations: Vec<T>,
+}
+
+/// The last scan of one radio, without its observations
+#[derive(Serialize, Debug, Clone)]
+pub struct ScanSummary {
+    pub scanned_at: Option<i64>,
+    pub observations: usize,
+    /// A scan is running right now
+    pub scanning: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScanSummaries {
+    pub wifi: ScanSummary,
+    pub bluetooth: ScanSummary,
+}
+
+#[derive(Default)]
+pub struct ScanCache {
+    wifi: Mutex<Option<Scan<WifiBssid>>>,
+    bluetooth: Mutex<Option<Scan<BleDevice>>>,
+}
+
+impl ScanCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The last WiFi scan if it's no older than `max_age`, or a new one
+    pub async fn wifi(&self, max_age: Duration) -> Scan<WifiBssid> {
+        cached(&self.wifi, max_age, wifi::fetch_wifi_stats).await
+    }
+
+    /// The last Bluetooth scan if it's no older than `max_age`, or a new one
+    pub async fn bluetooth(&self, max_age: Duration) -> Scan<BleDevice> {
+        cached(&self.bluetooth, max_age, bluetooth::fetch_ble_devices).await
+    }
+
+    /// When each radio last scanned, without waiting for a running scan
+    pub fn summaries(&self) -> ScanSummaries {
+        ScanSummaries {
+            wifi: summarize(&self.wifi),
+            bluetooth: summarize(&self.bluetooth),
+        }
+    }
+}
+
+fn summarize<T>(slot: &Mutex<Option<Scan<T>>>) -> ScanSummary {
+    match slot.try_lock() {
+        Ok(last) => ScanSummary {
+            scanned_at: last.as_ref().map(|scan| scan.scanned_at),
+            observations: last.as_ref().map_or(0, |scan| scan.observations.len()),
+            scanning: false,
+        },
+        Err(_) => ScanSummary {
+            scanned_at: None,
+            observations: 0,
+            scanning: true,
+        },
+    }
+}
+
+async fn cached<T, F>(
+    slot: &Mutex<Option<Scan<T>>>,
+    max_age: Duration,
+    scan: impl FnOnce() -> F,
+) -> Scan<T>
+where
+    T: Clone,
+    F: Future<Output = Vec<T>>,
+{
+    // Held through the scan, so concurrent requests wait for it and reuse it
+    let mut slot = slot.lock().await;
+
+    if let Some(last) = slot.as_ref() {
+        let age = now_millis().saturating_sub(last.scanned_at);
+        if age >= 0 && (age as u128) < max_age.as_millis() {
+            return Scan {
+                cached: true,
+                ..last.clone()
+            };
+        }
+    }
+
+    let fresh = Scan {
+        observations: scan().await,
+        scanned_at: now_millis(),
+        cached: false,
+    };
+    *slot = Some(fresh.clone());
+    fresh
+}
+
+#[cfg(test)]
+impl ScanCache {
+    /// Record a scan of each radio made just now, as if it had been requested
+    pub(crate) async fn record(&self, wifi: Vec<WifiBssid>, bluetooth: Vec<BleDevice>) {
+        let now = now_millis();
+        *self.wifi.lock().await = Some(Scan {
+            scanned_at: now,
+            cached: false,
+            observations: wifi,
+        });
+        *self.bluetooth.lock().await = Some(Scan {
+            scanned_at: now,
+            cached: false,
+            observations: bluetooth,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A scan that sees one more observation each time it runs
+    fn counting(scans: &AtomicUsize) -> impl Future<Output = Vec<usize>> {
+        let count = scans.fetch_add(1, Ordering::SeqCst) + 1;
+        async move { (0..count).collect() }
+    }
+
+    #[tokio::test]
+    async fn reuses_scans_younger_than_max_age() {
+        let slot = Mutex::new(None);
+        let scans = AtomicUsize::new(0);
+        let max_age = Duration::from_secs(30);
+
+        let first = cached(&slot, max_age, || counting(&scans)).await;
+        assert!(!first.cached);
+        assert_eq!(first.observations, [0]);
+
+        let second = cached(&slot, max_age, || counting(&scans)).await;
+        assert!(second.cached);
+        assert_eq!(second.scanned_at, first.scanned_at);
+        assert_eq!(second.observations, [0]);
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rescans_once_the_last_scan_is_too_old() {
+        let slot = Mutex::new(None);
+        let scans = AtomicUsize::new(0);
+        let max_age = Duration::from_secs(30);
+
+        cached(&slot, max_age, || counting(&scans)).await;
+        slot.lock().await.as_mut().unwrap().scanned_at -= 31_000;
+
+        let fresh = cached(&slot, max_age, || counting(&scans)).await;
+        assert!(!fresh.cached);
+        assert_eq!(fresh.observations, [0, 1]);
+
+        // a max age of 0 always scans
+        let fresh = cached(&slot, Duration::ZERO, || counting(&scans)).await;
+        assert!(!fresh.cached);
+        assert_eq!(scans.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_scan() {
+        let slot = Mutex::new(None);
+        let scans = AtomicUsize::new(0);
+        let max_age = Duration::from_secs(30);
+
+        let (first, second) = tokio::join!(
+            cached(&slot, max_age, || counting(&scans)),
+            cached(&slot, max_age, || counting(&scans)),
+        );
+        assert_ne!(first.cached, second.cached);
+        assert_eq!(first.observations, second.observations);
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn summaries_report_the_last_scans() {
+        let cache = ScanCache::new();
+        let summaries = cache.summaries();
+        assert_eq!(summaries.wifi.scanned_at, None);
+        assert!(!summaries.wifi.scanning);
+
+        cache
+            .record(vec![crate::test_support::access_point(1)], Vec::new())
+            .await;
+        let summaries = cache.summaries();
+        assert!(summaries.wifi.scanned_at.is_some());
+        assert_eq!(summaries.wifi.observations, 1);
+        assert_eq!(summaries.bluetooth.observations, 0);
+    }
+}
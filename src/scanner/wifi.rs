@@ -6,7 +6,8 @@ use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::config::SCAN_DURATION_SECS;
+use crate::config::{SCAN_DURATION_SECS, WIFI_INTERFACE};
+use crate::metrics::metrics;
 
 // oh my gosh I wrote all this code before discovering:
 // "Do NOT screenscrape this tool, we don't consider its output stable."
@@ -85,16 +86,20 @@ impl WifiBssid {
 }
 
 pub async fn fetch_wifi_stats() -> Vec<WifiBssid> {
+    metrics().time_scan("wifi", scan()).await
+}
+
+async fn scan() -> Vec<WifiBssid> {
     println!("[WiFi] Running scan...");
     let _ = tokio::process::Command::new("sudo")
-        .args(["iw", "dev", "wlan0", "scan", "trigger"])
+        .args(["iw", "dev", WIFI_INTERFACE, "scan", "trigger"])
         .output()
         .await
         .expect("[WiFi] Failed to trigger scan - Is IW installed?"); // Wait 10 seconds for scan to complete 
 
     tokio::time::sleep(Duration::from_secs(SCAN_DURATION_SECS)).await; // Dump the scan results 
     let output = tokio::process::Command::new("sudo")
-        .args(["iw", "dev", "wlan0", "scan", "dump"])
+        .args(["iw", "dev", WIFI_INTERFACE, "scan", "dump"])
         .output()
         .await
         .expect("[WiFi] Failed to dump scan results");
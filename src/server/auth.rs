@@ -0,0 +1,417 @@
+//! Pairing over HTTP, and refusing unpaired callers when pairing is required
+//!
+//! A paired device proves itself with its token, or with mutual TLS when
+//! `pairing.mutual_tls` is set: the handshake only accepts client certificates
+//! pinned for a paired device, and each request carries the one presented.
+
+use axum::Json;
+use axum::extract::{Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::{WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use serde::Deserialize;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+use crate::config::PairingSettings;
+use crate::devices::{self, DeviceRegistry, PairedDevice};
+use crate::error::{Error, Result};
+use crate::server::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct PairRequest {
+    pub pin: String,
+    /// Shown in `service_berry devices list`
+    pub name: Option<String>,
+    /// PEM client certificate to pin, for mutual TLS
+    pub certificate: Option<String>,
+}
+
+/// Exchange the PIN shown by the daemon for a device token
+pub async fn handle_pair(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PairRequest>,
+) -> Result<Json<PairedDevice>> {
+    let certificate = request
+        .certificate
+        .as_deref()
+        .map(devices::parse_certificate_pem)
+        .transpose()?;
+
+    let paired = tokio::task::spawn_blocking(move || {
+        state.devices.pair(
+            &request.pin,
+            request.name.as_deref(),
+            certificate.as_deref(),
+        )
+    })
+    .await
+    .map_err(|e| Error::Other(e.to_string()))??;
+    Ok(Json(paired))
+}
+
+/// The certificate a client presented during the TLS handshake, added to each
+/// request on that connection
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub CertificateDer<'static>);
+
+/// Middleware letting only paired devices through, when `pairing.required` is set.
+/// The device is added to the request's extensions.
+pub async fn require_device(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let pairing = &state.settings.pairing;
+    if !pairing.required {
+        return next.run(request).await;
+    }
+
+    // the registry is a file that may need reloading or saving, so check it off the runtime
+    let devices = Arc::clone(&state.devices);
+    let device = match request.extensions().get::<ClientCertificate>() {
+        // checked again here, as the device may have been revoked since the handshake
+        Some(certificate) if pairing.mutual_tls => {
+            let certificate = certificate.0.clone();
+            tokio::task::spawn_blocking(move || devices.authenticate_certificate(&certificate))
+                .await
+        }
+        _ if !pairing.accept_tokens => {
+            return Error::Unauthorized(
+                "present this device's pinned client certificate".to_string(),
+            )
+            .into_response();
+        }
+        _ => {
+            let token = request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|token| token.trim().to_string());
+            let Some(token) = token else {
+                return Error::Unauthorized(
+                    "pair this device first, then send its token".to_string(),
+                )
+                .into_response();
+            };
+            tokio::task::spawn_blocking(move || devices.authenticate(&token)).await
+        }
+    };
+    let device = device.unwrap_or_else(|e| Err(Error::Other(e.to_string())));
+
+    match device {
+        Ok(Some(device)) => {
+            request.extensions_mut().insert(device);
+            next.run(request).await
+        }
+        Ok(None) => Error::Unauthorized("unknown or revoked device".to_string()).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// How a plain-HTTP listener is reached: where it binds, and the token it
+/// requires, if any. Device tokens could be sniffed there and replayed over
+/// HTTPS, so they're never accepted. Without a token of its own, a listener is
+/// only reachable from this machine while pairing is required.
+pub fn plain_http_access(
+    pairing: &PairingSettings,
+    token: Option<&str>,
+) -> (Ipv4Addr, Option<Arc<str>>) {
+    match token {
+        Some(token) => (Ipv4Addr::UNSPECIFIED, Some(Arc::from(token))),
+        None if pairing.required => (Ipv4Addr::LOCALHOST, None),
+        None => (Ipv4Addr::UNSPECIFIED, None),
+    }
+}
+
+/// Middleware letting through only requests with a plain-HTTP listener's own token
+pub async fn require_listener_token(
+    State(expected): State<Arc<str>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match token {
+        Some(token) if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) => {
+            next.run(request).await
+        }
+        _ => Error::Unauthorized("send this listener's token".to_string()).into_response(),
+    }
+}
+
+/// Accepts client certificates pinned for a paired device, and connections
+/// without one, which can still pair or use a token
+#[derive(Debug)]
+pub struct PinnedClientCerts {
+    devices: Arc<DeviceRegistry>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedClientCerts {
+    pub fn new(devices: Arc<DeviceRegistry>, algorithms: WebPkiSupportedAlgorithms) -> Self {
+        PinnedClientCerts {
+            devices,
+            algorithms,
+        }
+    }
+}
+
+impl ClientCertVerifier for PinnedClientCerts {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        match self.devices.is_pinned(end_entity) {
+            Ok(true) => Ok(ClientCertVerified::assertion()),
+            Ok(false) => {
+                tracing::warn!(
+                    "[Pairing] Refused client certificate {}",
+                    devices::certificate_fingerprint(end_entity)
+                );
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            Err(e) => Err(rustls::Error::General(e.to_string())),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use rcgen::{CertifiedKey, KeyPair, generate_simple_self_signed};
+    use rustls::pki_types::PrivateKeyDer;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::config::Settings;
+    use crate::error::ErrorCategory;
+    use crate::test_support;
+
+    /// The HTTPS server as the daemon runs it, on a free loopback port
+    async fn serve(identity: &CertifiedKey<KeyPair>, state: &Arc<AppState>) -> String {
+        let config = crate::server::tls_config(
+            vec![identity.cert.der().clone()],
+            PrivateKeyDer::Pkcs8(identity.signing_key.serialize_der().into()),
+            state.settings.pairing.mutual_tls,
+            &state.devices,
+        )
+        .unwrap();
+        let router = crate::server::create_router(Arc::clone(state));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        tokio::spawn(crate::server::serve_tls(listener, acceptor, router));
+        format!("https://localhost:{}", address.port())
+    }
+
+    fn mutual_tls_state(accept_tokens: bool) -> Arc<AppState> {
+        test_support::app_state(Settings {
+            pairing: PairingSettings {
+                required: true,
+                mutual_tls: true,
+                accept_tokens,
+                ..PairingSettings::default()
+            },
+            ..Settings::default()
+        })
+    }
+
+    async fn status(request: reqwest::RequestBuilder) -> StatusCode {
+        request.send().await.unwrap().status()
+    }
+
+    /// A client trusting the server, presenting `certificate` if given
+    fn client(
+        server: &CertifiedKey<KeyPair>,
+        certificate: Option<&CertifiedKey<KeyPair>>,
+    ) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(server.cert.der()).unwrap())
+            .timeout(Duration::from_secs(5));
+        if let Some(certificate) = certificate {
+            let pem = certificate.cert.pem() + &certificate.signing_key.serialize_pem();
+            builder = builder.identity(reqwest::Identity::from_pem(pem.as_bytes()).unwrap());
+        }
+        builder.build().unwrap()
+    }
+
+    fn phone_certificate() -> CertifiedKey<KeyPair> {
+        generate_simple_self_signed(vec!["phone".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn plain_http_listeners_without_a_token_stay_local_while_pairing_is_required() {
+        let mut pairing = PairingSettings::default();
+        assert_eq!(
+            plain_http_access(&pairing, None),
+            (Ipv4Addr::UNSPECIFIED, None)
+        );
+
+        pairing.required = true;
+        assert_eq!(
+            plain_http_access(&pairing, None),
+            (Ipv4Addr::LOCALHOST, None)
+        );
+        assert_eq!(
+            plain_http_access(&pairing, Some("scraper")),
+            (Ipv4Addr::UNSPECIFIED, Some(Arc::from("scraper")))
+        );
+    }
+
+    #[tokio::test]
+    async fn plain_http_listeners_accept_only_their_own_token() {
+        let router = Router::new()
+            .route("/metrics", get(|| async { "ok" }))
+            .route_layer(axum::middleware::from_fn_with_state(
+                Arc::from("scraper"),
+                require_listener_token,
+            ));
+        let url = format!("http://{}/metrics", test_support::serve(router).await);
+        let http = reqwest::Client::new();
+
+        let status = |token: Option<&'static str>| {
+            let mut request = http.get(&url);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            async move { request.send().await.unwrap().status() }
+        };
+        assert_eq!(status(Some("scraper")).await, StatusCode::OK);
+        assert_eq!(
+            status(Some("a device token")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn accepts_only_pinned_client_certificates() {
+        let identity = test_support::self_signed();
+        let state = mutual_tls_state(true);
+        let server = serve(&identity, &state).await;
+        let queue = format!("{}/queue", server);
+
+        // an unpaired phone still connects without a certificate, to pair with one
+        let pinned = phone_certificate();
+        let pin = state.devices.open_pin(Duration::from_secs(60)).unwrap();
+        let response = client(&identity, None)
+            .post(format!("{}/pair", server))
+            .json(&serde_json::json!({"pin": pin, "certificate": pinned.cert.pem()}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let paired: serde_json::Value = response.json().await.unwrap();
+
+        let with_pinned = client(&identity, Some(&pinned));
+        assert_eq!(status(with_pinned.get(&queue)).await, StatusCode::OK);
+
+        // refused in the handshake
+        let error = client(&identity, Some(&phone_certificate()))
+            .get(&queue)
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(Error::from(error).category(), ErrorCategory::Tls);
+
+        // without a certificate, the token is checked instead
+        let without = client(&identity, None);
+        assert_eq!(status(without.get(&queue)).await, StatusCode::UNAUTHORIZED);
+        let token = paired["token"].as_str().unwrap();
+        assert_eq!(
+            status(without.get(&queue).bearer_auth(token)).await,
+            StatusCode::OK
+        );
+
+        // revoked: the open connection is refused by the middleware, new ones in the handshake
+        let id = paired["device_id"].as_str().unwrap();
+        state.devices.revoke(id).unwrap();
+        assert_eq!(
+            status(with_pinned.get(&queue)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let error = client(&identity, Some(&pinned))
+            .get(&queue)
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(Error::from(error).category(), ErrorCategory::Tls);
+    }
+
+    #[tokio::test]
+    async fn can_refuse_tokens_in_favour_of_certificates() {
+        let identity = test_support::self_signed();
+        let state = mutual_tls_state(false);
+        let server = serve(&identity, &state).await;
+        let queue = format!("{}/queue", server);
+
+        let pinned = phone_certificate();
+        let pin = state.devices.open_pin(Duration::from_secs(60)).unwrap();
+        let paired = state
+            .devices
+            .pair(&pin, Some("Pixel 8"), Some(pinned.cert.der()))
+            .unwrap();
+
+        let without = client(&identity, None);
+        assert_eq!(
+            status(without.get(&queue).bearer_auth(&paired.token)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(client(&identity, Some(&pinned)).get(&queue)).await,
+            StatusCode::OK
+        );
+    }
+}
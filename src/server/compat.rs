@@ -0,0 +1,272 @@
+//! `/network_json`, for the Scriptable phone client in `script.js`
+//!
+//! That script fetches a geosubmit document shaped like `sample.json`, puts the
+//! phone's position into its item and posts it to BeaconDB itself. It predates the
+//! HTTPS server, so besides the route on the main server this can be served on
+//! its own plain-HTTP port.
+
+use axum::Json;
+use axum::Router;
+use axum::extract::State;
+use axum::middleware;
+use axum::routing::get;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use crate::config;
+use crate::error::{Error, Result};
+use crate::geosubmit::Position;
+use crate::geosubmit::zones::ZoneVerdict;
+use crate::scanner::wifi::PhyType;
+use crate::scanner::{BleDevice, WifiBssid};
+use crate::server::{AppState, auth};
+
+#[derive(Serialize, Debug)]
+pub struct NetworkJson {
+    pub items: Vec<NetworkItem>,
+}
+
+#[derive(Serialize, Debug)]
+#[allow(non_snake_case)]
+pub struct NetworkItem {
+    pub wifiAccessPoints: Vec<NetworkAccessPoint>,
+    pub timestamp: i64,
+    /// Left out when no local source has a fix; the script adds the phone's own position
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<Position>,
+    pub bluetoothBeacons: Vec<BleDevice>,
+}
+
+#[derive(Serialize, Debug)]
+#[allow(non_snake_case)]
+pub struct NetworkAccessPoint {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    pub frequency: u16,
+    pub signalStrength: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    pub macAddress: String,
+    pub wifiVersion: &'static str,
+}
+
+impl From<WifiBssid> for NetworkAccessPoint {
+    fn from(ap: WifiBssid) -> Self {
+        NetworkAccessPoint {
+            channel: ap.channel,
+            frequency: ap.frequency,
+            signalStrength: ap.rssi,
+            age: ap.age,
+            ssid: ap.ssid,
+            macAddress: ap.bssid.to_string().to_lowercase(),
+            wifiVersion: wifi_version(&ap.phy, ap.frequency),
+        }
+    }
+}
+
+/// The IEEE 802.11 amendment name for a PHY type
+fn wifi_version(phy: &PhyType, frequency: u16) -> &'static str {
+    match phy {
+        PhyType::Uhr => "802.11bn",
+        PhyType::Eht => "802.11be",
+        PhyType::He => "802.11ax",
+        PhyType::Vht => "802.11ac",
+        PhyType::Ht => "802.11n",
+        PhyType::Legacy if frequency >= 5000 => "802.11a",
+        PhyType::Legacy => "802.11g",
+    }
+}
+
+/// Scan (or reuse a recent scan) and return it as a one-item geosubmit document,
+/// screened and with the privacy policy applied since the client uploads it directly
+pub async fn handle_network_json(State(state): State<Arc<AppState>>) -> Result<Json<NetworkJson>> {
+    let max_age = Duration::from_secs(config::REQUEST_SCAN_MAX_AGE_SECS);
+    let (wifi, bluetooth) = tokio::join!(state.scans.wifi(max_age), state.scans.bluetooth(max_age));
+    let position = state.position_fix(None).map(|fix| fix.position);
+
+    // screening may save newly excluded emitters
+    let item = tokio::task::spawn_blocking(move || {
+        network_item(
+            &state,
+            wifi.observations,
+            bluetooth.observations,
+            wifi.scanned_at,
+            position,
+        )
+    })
+    .await
+    .map_err(|e| Error::Other(e.to_string()))??;
+
+    Ok(Json(NetworkJson {
+        items: item.into_iter().collect(),
+    }))
+}
+
+/// Screen a scan against the exclusion zones like a submission, then apply the
+/// privacy policy. `None` when the position is inside a zone.
+fn network_item(
+    state: &AppState,
+    mut access_points: Vec<WifiBssid>,
+    mut beacons: Vec<BleDevice>,
+    timestamp: i64,
+    mut position: Option<Position>,
+) -> Result<Option<NetworkItem>> {
+    let verdict = state.zones.screen_scan(
+        position.as_ref(),
+        &mut access_points,
+        &mut beacons,
+        0,
+        false,
+    )?;
+    match verdict {
+        ZoneVerdict::Suppressed => {
+            tracing::info!("[Compat] Not serving a scan made inside an exclusion zone");
+            return Ok(None);
+        }
+        ZoneVerdict::Allowed { removed } if removed > 0 => {
+            tracing::info!(
+                "[Compat] Removed {} observation(s) of emitters seen inside an exclusion zone",
+                removed
+            );
+        }
+        ZoneVerdict::Allowed { .. } => {}
+    }
+
+    let privacy = &state.settings.privacy;
+    if let Some(position) = position.as_mut() {
+        if privacy.strip_heading {
+            position.heading = None;
+        }
+        if privacy.strip_speed {
+            position.speed = None;
+        }
+    }
+
+    Ok(Some(NetworkItem {
+        wifiAccessPoints: access_points
+            .into_iter()
+            .map(|mut ap| {
+                if privacy.strip_ssid {
+                    ap.ssid = None;
+                }
+                NetworkAccessPoint::from(ap)
+            })
+            .collect(),
+        timestamp,
+        position,
+        bluetoothBeacons: beacons
+            .into_iter()
+            .map(|mut beacon| {
+                if privacy.strip_ble_name {
+                    beacon.name = None;
+                }
+                beacon
+            })
+            .collect(),
+    }))
+}
+
+/// Serve only `/network_json`, over plain HTTP, on `port`. See
+/// `auth::plain_http_access` for who can reach it.
+pub async fn start_plain_http(port: u16, state: Arc<AppState>) -> Result<()> {
+    let (address, token) = auth::plain_http_access(
+        &state.settings.pairing,
+        state.settings.compat.token.as_deref(),
+    );
+    let listener = TcpListener::bind((address, port))
+        .await
+        .map_err(|e| Error::Bind(e.to_string()))?;
+    tracing::info!(
+        "[Compat] Serving /network_json over HTTP on {}:{}",
+        address,
+        port
+    );
+
+    let mut router = Router::new().route("/network_json", get(handle_network_json));
+    if let Some(token) = token {
+        router = router.route_layer(middleware::from_fn_with_state(
+            token,
+            auth::require_listener_token,
+        ));
+    }
+    let router = router.with_state(state);
+    axum::serve(listener, router)
+        .await
+        .map_err(|e| Error::Bind(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::geosubmit::zones::ExclusionZone;
+    use crate::test_support::{self, access_point, position};
+    use btleplug::api::BDAddr;
+
+    fn beacon(last_byte: u8) -> BleDevice {
+        BleDevice {
+            mac_address: BDAddr::from([0x02, 0, 0, 0, 1, last_byte]),
+            rssi: Some(-70),
+            name: Some("Watch".to_string()),
+        }
+    }
+
+    fn macs(item: &NetworkItem) -> Vec<String> {
+        item.wifiAccessPoints
+            .iter()
+            .map(|ap| ap.macAddress.clone())
+            .chain(
+                item.bluetoothBeacons
+                    .iter()
+                    .map(|b| b.mac_address.to_string()),
+            )
+            .collect()
+    }
+
+    #[test]
+    fn screens_scans_against_exclusion_zones() {
+        let state = test_support::app_state(Settings {
+            exclusion_zones: vec![ExclusionZone::Circle {
+                name: Some("home".to_string()),
+                latitude: 52.0,
+                longitude: 13.0,
+                radius_meters: 100.0,
+            }],
+            ..Settings::default()
+        });
+
+        let home = position(52.0, 13.0);
+        let item = network_item(
+            &state,
+            vec![access_point(1)],
+            vec![beacon(1)],
+            0,
+            Some(home),
+        )
+        .unwrap();
+        assert!(item.is_none());
+
+        // what was seen at home is stripped elsewhere, with or without a local fix
+        for position in [Some(position(52.1, 13.1)), None] {
+            let item = network_item(
+                &state,
+                vec![access_point(1), access_point(2)],
+                vec![beacon(1), beacon(2)],
+                0,
+                position,
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(
+                macs(&item),
+                [access_point(2).bssid, beacon(2).mac_address]
+                    .map(|mac| mac.to_string().to_lowercase())
+            );
+        }
+        assert_eq!(state.zones.stats().excluded_emitters, 2);
+    }
+}
@@ -1,14 +1,41 @@
+use axum::Json;
+use axum::body::{Body, Bytes};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::time::timeout;
-use tracing::{error, info};
+use tracing::info;
 
-use crate::geosubmit::{self, items};
+use crate::config;
+use crate::export::{self, ExportFormat};
+use crate::geosubmit::{
+    self, CellTower, Position, PrivacyPolicy, SelfCheck,
+    emitters::EmitterStats,
+    filter::{FilterStats, SkipReason},
+    geolocate::GeolocateRequest,
+    items,
+    provider::{BreakerState, ProviderStatus},
+    queue::{QueueEntrySummary, QueueStatus},
+    zones::{ZoneStats, ZoneVerdict},
+};
+use crate::history::HistoryStats;
+use crate::position::SourceStatus;
+use crate::scanner::adapter::{self, AdapterStatus};
+use crate::scanner::cache::ScanSummaries;
+use crate::scanner::{BleDevice, Scan, WifiBssid};
+use crate::server::AppState;
+use crate::server::health::{CertificateStatus, ErrorCounts, PeripheralState, PeripheralStatus};
+use crate::stationary::StationaryStatus;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PartialPayload {
+    /// Null when the client has no position; a local source fills it in
+    #[serde(default)]
     pub position: serde_json::Value,
     pub cell_towers: Option<serde_json::Value>,
     #[serde(flatten)]
@@ -16,47 +43,587 @@ pub struct PartialPayload {
 }
 
 pub async fn process_submit_http(
-    axum::Json(value): axum::Json<serde_json::Value>,
+    State(state): State<Arc<AppState>>,
+    body: Result<Json<serde_json::Value>, JsonRejection>,
 ) -> Result<String, crate::error::Error> {
+    let Json(value) = body?;
     let payload: PartialPayload = serde_json::from_value(value)
-        .map_err(|e| crate::error::Error::Other(format!("JSON Parse Error: {}", e)))?;
+        .map_err(|e| crate::error::Error::Serialization(format!("JSON Parse Error: {}", e)))?;
 
-    process_submit(payload).await
+    process_submit(&state, payload).await
 }
 
-pub async fn process_submit(payload: PartialPayload) -> Result<String, crate::error::Error> {
+/// Assemble a payload from the current scans and persist it to the submission queue.
+/// The queue worker takes care of delivery.
+pub async fn process_submit(
+    state: &Arc<AppState>,
+    payload: PartialPayload,
+) -> Result<String, crate::error::Error> {
     info!("[Server] Processing submission...");
 
-    let geo_items: items = geosubmit::assemble_geo_payload(payload.position, payload.cell_towers)
+    let reported: Option<Position> = serde_json::from_value(payload.position)
+        .map_err(|e| crate::error::Error::Serialization(e.to_string()))?;
+    if let Some(position) = &reported {
+        position.validate()?;
+    }
+    let fix = state.position_fix(reported).ok_or_else(|| {
+        crate::error::Error::Validation(
+            "no position given and no local position source has a fix".to_string(),
+        )
+    })?;
+    info!(
+        "[Server] Using a {} position accurate to {:.0} m",
+        fix.position.source, fix.position.accuracy
+    );
+    let position = serde_json::to_value(fix.position)?;
+
+    let geo_items: items = geosubmit::assemble_geo_payload(position, payload.cell_towers).await?;
+    Ok(spawn_accept_items(Arc::clone(state), geo_items)
+        .await?
+        .to_string())
+}
+
+/// What became of a payload offered for submission
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acceptance {
+    Queued,
+    InsideExclusionZone,
+    Skipped(SkipReason),
+}
+
+impl std::fmt::Display for Acceptance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Acceptance::Queued => write!(f, "Successful"),
+            Acceptance::InsideExclusionZone => write!(f, "Skipped: inside an exclusion zone"),
+            Acceptance::Skipped(reason) => write!(f, "Skipped: {}", reason),
+        }
+    }
+}
+
+/// Screen a payload, record it, and queue it for upload. Live and imported
+/// submissions all go through here. A dry run screens it the same way but
+/// records and queues nothing, to tell what would become of it.
+pub fn accept_items(
+    state: &AppState,
+    mut geo_items: items,
+    dry_run: bool,
+) -> Result<Acceptance, crate::error::Error> {
+    geo_items.position.validate()?;
+
+    match state.zones.screen(&mut geo_items, dry_run)? {
+        ZoneVerdict::Suppressed => {
+            info!("[Server] Skipping submission made inside an exclusion zone");
+            return Ok(Acceptance::InsideExclusionZone);
+        }
+        ZoneVerdict::Allowed { removed } if removed > 0 => {
+            info!(
+                "[Server] Removed {} observation(s) of emitters seen inside an exclusion zone",
+                removed
+            );
+        }
+        ZoneVerdict::Allowed { .. } => {}
+    }
+
+    let fingerprint = match state.filter.check(&geo_items) {
+        Ok(fingerprint) => fingerprint,
+        Err(reason) => {
+            info!("[Server] Skipping submission: {}", reason);
+            return Ok(Acceptance::Skipped(reason));
+        }
+    };
+    geo_items.validate()?;
+    if dry_run {
+        state.filter.remember(fingerprint);
+        return Ok(Acceptance::Queued);
+    }
+
+    let learned = state.emitters.learn(&geo_items);
+    if let Some(history) = &state.history {
+        history.record(&geo_items, &learned)?;
+    }
+
+    let release = state.settings.privacy.release_time();
+    state.queue.enqueue_until(geo_items, release)?;
+    state.filter.remember(fingerprint);
+
+    Ok(Acceptance::Queued)
+}
+
+/// `accept_items` on the blocking pool, as it syncs queue files and writes to the history
+pub async fn spawn_accept_items(
+    state: Arc<AppState>,
+    geo_items: items,
+) -> Result<Acceptance, crate::error::Error> {
+    tokio::task::spawn_blocking(move || accept_items(&state, geo_items, false))
         .await
-        .map_err(|e| crate::error::Error::Other(format!("Assembly Error: {}", e)))?;
+        .map_err(|e| crate::error::Error::Other(e.to_string()))?
+}
 
-    let handle = tokio::spawn(async move { geosubmit::submit_geo_payload(geo_items).await });
+/// Query parameters of `/status`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct StatusQuery {
+    /// Present (`?verbose`, or anything but `false`/`0`) to include details
+    pub verbose: Option<String>,
+}
 
-    match timeout(Duration::from_secs(3), handle).await {
-        Ok(join_result) => match join_result {
-            Ok(Ok(())) => {
-                info!("Successfully sent geolocation data to service");
-            }
-            Ok(Err(e)) => {
-                error!("Geosubmit network error: {}", e);
+impl StatusQuery {
+    fn is_verbose(&self) -> bool {
+        self.verbose
+            .as_deref()
+            .is_some_and(|value| !matches!(value, "false" | "0"))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct AdapterStatuses {
+    pub wifi: AdapterStatus,
+    pub bluetooth: AdapterStatus,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueueSummary {
+    pub pending: usize,
+    pub quarantined: usize,
+    /// Only with `?verbose`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<QueueEntrySummary>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PairingStatus {
+    pub required: bool,
+    /// Whether `/pair` would accept a PIN right now
+    pub pin_open: bool,
+    /// Number of paired devices; `service_berry devices list` shows them
+    pub devices: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusResponse {
+    /// `ok`, or `degraded` when something listed in `problems` is stopping contributions
+    pub status: &'static str,
+    pub problems: Vec<String>,
+    pub version: &'static str,
+    pub started_at: i64,
+    pub uptime_secs: u64,
+    pub certificate: Option<CertificateStatus>,
+    pub adapters: AdapterStatuses,
+    pub peripheral: PeripheralStatus,
+    pub queue: QueueSummary,
+    pub providers: Vec<ProviderStatus>,
+    pub errors: ErrorCounts,
+    pub skipped: FilterStats,
+    pub privacy: PrivacyPolicy,
+    pub exclusion_zones: ZoneStats,
+    pub learned_emitters: EmitterStats,
+    pub history: Option<HistoryStats>,
+    pub position_sources: Vec<SourceStatus>,
+    pub stationary: Option<StationaryStatus>,
+    pub pairing: PairingStatus,
+    /// Only with `?verbose`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scans: Option<ScanSummaries>,
+}
+
+/// Built on the blocking pool, as it reads sysfs, the history database and the device registry
+pub async fn handle_status(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StatusQuery>,
+) -> Result<Json<StatusResponse>, crate::error::Error> {
+    let verbose = query.is_verbose();
+    tokio::task::spawn_blocking(move || status(&state, verbose))
+        .await
+        .map_err(|e| crate::error::Error::Other(e.to_string()))?
+        .map(Json)
+}
+
+fn status(state: &AppState, verbose: bool) -> Result<StatusResponse, crate::error::Error> {
+    let health = &state.health;
+
+    let adapters = AdapterStatuses {
+        wifi: adapter::wifi_status(),
+        bluetooth: adapter::bluetooth_status(),
+    };
+    let queue = if verbose {
+        let status = state.queue.status();
+        QueueSummary {
+            pending: status.pending,
+            quarantined: status.quarantined,
+            entries: Some(status.entries),
+        }
+    } else {
+        QueueSummary {
+            pending: state.queue.len(),
+            quarantined: state.queue.quarantined(),
+            entries: None,
+        }
+    };
+    let providers = vec![state.provider.status()];
+    let certificate = health.certificate();
+    let peripheral = health.peripheral();
+
+    let mut problems = Vec::new();
+    if !adapters.wifi.present {
+        problems.push("WiFi adapter missing".to_string());
+    }
+    if peripheral.state == PeripheralState::Failed {
+        problems.push("BLE peripheral not advertising".to_string());
+    }
+    for provider in &providers {
+        if provider.breaker == BreakerState::Open {
+            problems.push(format!("submissions to {} paused", provider.name));
+        }
+    }
+    if let Some(days) = certificate.as_ref().and_then(|c| c.expires_in_days) {
+        if days < 0 {
+            problems.push("certificate expired".to_string());
+        } else if days < config::CERTIFICATE_EXPIRY_WARNING_DAYS {
+            problems.push(format!("certificate expires in {} day(s)", days));
+        }
+    }
+
+    Ok(StatusResponse {
+        status: if problems.is_empty() {
+            "ok"
+        } else {
+            "degraded"
+        },
+        problems,
+        version: env!("CARGO_PKG_VERSION"),
+        started_at: health.started_at(),
+        uptime_secs: health.uptime_secs(),
+        certificate,
+        adapters,
+        peripheral,
+        queue,
+        providers,
+        errors: health.error_counts(verbose),
+        skipped: state.filter.stats(),
+        privacy: state.settings.privacy.clone(),
+        exclusion_zones: state.zones.stats(),
+        learned_emitters: state.emitters.stats(),
+        history: state.history.as_ref().map(|h| h.stats()).transpose()?,
+        position_sources: state.position_sources.iter().map(|s| s.status()).collect(),
+        stationary: state.stationary.as_ref().map(|node| node.status()),
+        pairing: PairingStatus {
+            required: state.settings.pairing.required,
+            pin_open: state.devices.pin_open()?,
+            devices: state.devices.devices()?.len(),
+        },
+        scans: verbose.then(|| state.scans.summaries()),
+    })
+}
+
+/// Query parameters of an on-demand scan
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ScanQuery {
+    /// Comma-separated: `wifi`, `bluetooth`; both when absent
+    pub radios: Option<String>,
+    /// Reuse a scan up to this many seconds old; 0 always scans
+    pub max_age: Option<u64>,
+    /// Defaults to the opposite of `privacy.strip_ssid`
+    pub include_ssid: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScanResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wifi: Option<Scan<WifiBssid>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bluetooth: Option<Scan<BleDevice>>,
+}
+
+/// Scan now, or reuse a recent scan, and return what was seen without submitting it
+pub async fn handle_request(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ScanQuery>,
+) -> Result<Json<ScanResponse>, crate::error::Error> {
+    let (mut scan_wifi, mut scan_bluetooth) = (query.radios.is_none(), query.radios.is_none());
+    for radio in query.radios.iter().flat_map(|radios| radios.split(',')) {
+        match radio.trim().to_ascii_lowercase().as_str() {
+            "wifi" => scan_wifi = true,
+            "bluetooth" | "ble" => scan_bluetooth = true,
+            "" => {}
+            other => {
+                return Err(crate::error::Error::Validation(format!(
+                    "unknown radio '{}', expected wifi or bluetooth",
+                    other
+                )));
             }
-            Err(join_err) => {
-                error!("Submission task panicked: {:?}", join_err);
+        }
+    }
+
+    let max_age = Duration::from_secs(query.max_age.unwrap_or(config::REQUEST_SCAN_MAX_AGE_SECS));
+    let (mut wifi, bluetooth) = tokio::join!(
+        async {
+            if scan_wifi {
+                Some(state.scans.wifi(max_age).await)
+            } else {
+                None
             }
         },
-        Err(_) => {
-            info!("Submission taking longer than 3s; continuing in background.");
+        async {
+            if scan_bluetooth {
+                Some(state.scans.bluetooth(max_age).await)
+            } else {
+                None
+            }
+        }
+    );
+
+    if !query
+        .include_ssid
+        .unwrap_or(!state.settings.privacy.strip_ssid)
+    {
+        for ap in wifi.iter_mut().flat_map(|scan| &mut scan.observations) {
+            ap.ssid = None;
         }
     }
 
-    Ok(String::from("Successful"))
+    Ok(Json(ScanResponse { wifi, bluetooth }))
+}
+
+pub async fn handle_queue(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<QueueStatus>, crate::error::Error> {
+    Ok(Json(state.queue.status()))
 }
 
-pub async fn handle_status() -> (StatusCode, String) {
-    (StatusCode::OK, "ok".to_string())
+/// Optional body of a self-check request: where the phone thinks we are
+#[derive(Deserialize, Debug, Default)]
+pub struct SelfCheckRequest {
+    #[serde(default)]
+    pub position: Option<Position>,
+    #[serde(default)]
+    pub cell_towers: Vec<CellTower>,
 }
 
-pub async fn handle_request() -> (StatusCode, String) {
-    (StatusCode::OK, "ok".to_string())
+/// Scan now and compare the provider's geolocate answer with the phone's position
+pub async fn handle_geolocate_check(
+    State(state): State<Arc<AppState>>,
+    body: Result<Json<SelfCheckRequest>, JsonRejection>,
+) -> Result<Json<SelfCheck>, crate::error::Error> {
+    let request = match body {
+        Ok(Json(request)) => request,
+        Err(JsonRejection::MissingJsonContentType(_)) => SelfCheckRequest::default(),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(position) = &request.position {
+        position.validate()?;
+    }
+
+    let check = geosubmit::self_check(
+        &state.http,
+        &state.settings.submission.geolocate_endpoint,
+        request.position,
+        request.cell_towers,
+    )
+    .await?;
+    Ok(Json(check))
+}
+
+/// Ichnaea-compatible geolocate, answered from the locally learned emitter locations
+pub async fn handle_geolocate(
+    State(state): State<Arc<AppState>>,
+    body: Result<Json<GeolocateRequest>, JsonRejection>,
+) -> Result<Response, crate::error::Error> {
+    let Json(request) = body?;
+
+    match state.emitters.locate(&request) {
+        Some(estimate) => Ok(Json(estimate).into_response()),
+        // Same body Ichnaea sends, so existing clients recognise it
+        None => Ok((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "error": {
+                    "errors": [{
+                        "domain": "geolocation",
+                        "reason": "notFound",
+                        "message": "Not found",
+                    }],
+                    "code": 404,
+                    "message": "Not found",
+                }
+            })),
+        )
+            .into_response()),
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ExportQuery {
+    pub since: Option<String>,
+    pub until: Option<String>,
+    /// `west,south,east,north`
+    pub bbox: Option<String>,
+}
+
+/// Stream the history as a download in the requested format
+pub async fn handle_export(
+    State(state): State<Arc<AppState>>,
+    Path(format): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, crate::error::Error> {
+    let format: ExportFormat = format.parse()?;
+    let filter = export::history_filter(
+        query.since.as_deref(),
+        query.until.as_deref(),
+        query.bbox.as_deref(),
+    )?;
+    if state.history.is_none() {
+        return Err(crate::error::Error::Validation(
+            "history is disabled in the settings".into(),
+        ));
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let Some(history) = &state.history else {
+            return;
+        };
+        let mut out = BufWriter::with_capacity(64 * 1024, ChunkWriter(tx.clone()));
+        if let Err(e) = export::export(history, format, &filter, &mut out) {
+            tracing::warn!("[Export] Stopped early: {}", e);
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+
+    Ok((
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                format.content_type().to_string(),
+            ),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"serviceberry.{}\"",
+                    format.extension()
+                ),
+            ),
+        ],
+        Body::from_stream(tokio_stream::wrappers::ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
+/// Hands each write to the response body; fails once the client has gone away
+struct ChunkWriter(tokio::sync::mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::test_support::{self, payload, position};
+    use btleplug::api::BDAddr;
+
+    #[test]
+    fn status_reports_the_queue_and_the_last_known_bluetooth_adapter() {
+        let state = test_support::app_state(Settings::default());
+        state
+            .queue
+            .enqueue(payload(1, position(52.0, 13.0), &[1]))
+            .unwrap();
+        adapter::record_bluetooth_status(AdapterStatus::present("hci0".to_string()));
+
+        let status = status(&state, true).unwrap();
+        assert_eq!(status.queue.pending, 1);
+        assert_eq!(status.queue.entries.unwrap().len(), 1);
+        assert!(status.adapters.bluetooth.present);
+        assert_eq!(status.adapters.bluetooth.name.as_deref(), Some("hci0"));
+    }
+
+    /// Answer a scan request from a scan just recorded in the cache
+    async fn scan(
+        settings: Settings,
+        radios: Option<&str>,
+        include_ssid: Option<bool>,
+    ) -> Result<ScanResponse, crate::error::Error> {
+        let state = test_support::app_state(settings);
+        let mut access_point = test_support::access_point(1);
+        access_point.ssid = Some("home".to_string());
+        let device = BleDevice {
+            mac_address: BDAddr::from([0x02, 0, 0, 0, 0, 0x10]),
+            rssi: Some(-70),
+            name: None,
+        };
+        state.scans.record(vec![access_point], vec![device]).await;
+
+        let query = ScanQuery {
+            radios: radios.map(str::to_string),
+            max_age: None,
+            include_ssid,
+        };
+        handle_request(State(state), Query(query))
+            .await
+            .map(|Json(response)| response)
+    }
+
+    #[tokio::test]
+    async fn scans_only_the_requested_radios() {
+        let both = scan(Settings::default(), None, None).await.unwrap();
+        assert!(both.wifi.unwrap().cached);
+        assert!(both.bluetooth.unwrap().cached);
+
+        let wifi = scan(Settings::default(), Some("wifi"), None).await.unwrap();
+        assert!(wifi.wifi.is_some());
+        assert!(wifi.bluetooth.is_none());
+
+        let bluetooth = scan(Settings::default(), Some(" BLE ,"), None)
+            .await
+            .unwrap();
+        assert!(bluetooth.wifi.is_none());
+        assert_eq!(bluetooth.bluetooth.unwrap().observations.len(), 1);
+
+        let both = scan(Settings::default(), Some("wifi,bluetooth"), None)
+            .await
+            .unwrap();
+        assert!(both.wifi.is_some() && both.bluetooth.is_some());
+
+        let unknown = scan(Settings::default(), Some("wifi,lte"), None).await;
+        assert!(matches!(unknown, Err(crate::error::Error::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn strips_ssids_unless_asked_to_include_them() {
+        let ssid = |response: ScanResponse| response.wifi.unwrap().observations[0].ssid.clone();
+
+        let stripped = scan(Settings::default(), Some("wifi"), Some(false))
+            .await
+            .unwrap();
+        assert_eq!(ssid(stripped), None);
+
+        let included = scan(Settings::default(), Some("wifi"), Some(true))
+            .await
+            .unwrap();
+        assert_eq!(ssid(included).as_deref(), Some("home"));
+
+        // without a preference, the privacy setting decides
+        assert_eq!(
+            ssid(scan(Settings::default(), Some("wifi"), None).await.unwrap()).as_deref(),
+            Some("home")
+        );
+        let mut settings = Settings::default();
+        settings.privacy.strip_ssid = true;
+        assert_eq!(
+            ssid(scan(settings, Some("wifi"), None).await.unwrap()),
+            None
+        );
+    }
 }
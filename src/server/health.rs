@@ -0,0 +1,181 @@
+//! Health of the parts of the daemon that can quietly stop contributions:
+//! the TLS certificate, the BLE peripheral, and recent errors
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use crate::clock::now_millis;
+use crate::config::{HEALTH_ERROR_WINDOW_SECS, HEALTH_RECENT_ERRORS};
+use crate::error::{Error, ErrorCategory, ErrorReport};
+use crate::server::AppState;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CertificateStatus {
+    /// SHA-256 of the certificate, as advertised over mDNS
+    pub fingerprint_sha256: String,
+    /// Milliseconds since the Unix epoch
+    pub expires_at: Option<i64>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeripheralState {
+    Starting,
+    Advertising,
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PeripheralStatus {
+    pub state: PeripheralState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Payloads received over BLE since startup
+    pub payloads_received: u64,
+    pub last_payload: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RecentError {
+    pub time: i64,
+    /// What was going on, e.g. `http`, `ble` or `stationary`
+    pub context: &'static str,
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ErrorCounts {
+    pub window_secs: u64,
+    pub total: usize,
+    pub by_category: BTreeMap<ErrorCategory, usize>,
+    /// Only with `?verbose`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent: Option<Vec<RecentError>>,
+}
+
+pub struct Health {
+    started: Instant,
+    started_at: i64,
+    certificate: OnceLock<(String, Option<i64>)>,
+    peripheral: Mutex<PeripheralStatus>,
+    errors: Mutex<VecDeque<RecentError>>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            started: Instant::now(),
+            started_at: now_millis(),
+            certificate: OnceLock::new(),
+            peripheral: Mutex::new(PeripheralStatus {
+                state: PeripheralState::Starting,
+                error: None,
+                payloads_received: 0,
+                last_payload: None,
+            }),
+            errors: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn started_at(&self) -> i64 {
+        self.started_at
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Remember the served certificate's fingerprint and expiry
+    pub fn set_certificate(&self, fingerprint: &[u8], expires_at: Option<i64>) {
+        let _ = self.certificate.set((hex::encode(fingerprint), expires_at));
+    }
+
+    pub fn certificate(&self) -> Option<CertificateStatus> {
+        let (fingerprint, expires_at) = self.certificate.get()?.clone();
+        Some(CertificateStatus {
+            fingerprint_sha256: fingerprint,
+            expires_in_days: expires_at.map(|at| (at - now_millis()).div_euclid(86_400_000)),
+            expires_at,
+        })
+    }
+
+    pub fn set_peripheral_state(&self, state: PeripheralState, error: Option<String>) {
+        let mut peripheral = self.peripheral.lock().unwrap();
+        peripheral.state = state;
+        peripheral.error = error;
+    }
+
+    pub fn record_ble_payload(&self) {
+        let mut peripheral = self.peripheral.lock().unwrap();
+        peripheral.payloads_received += 1;
+        peripheral.last_payload = Some(now_millis());
+    }
+
+    pub fn peripheral(&self) -> PeripheralStatus {
+        self.peripheral.lock().unwrap().clone()
+    }
+
+    pub fn record_error(&self, context: &'static str, error: &Error) {
+        self.record(context, error.category(), error.to_string());
+    }
+
+    fn record(&self, context: &'static str, category: ErrorCategory, message: String) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() >= HEALTH_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            time: now_millis(),
+            context,
+            category,
+            message,
+        });
+    }
+
+    /// Errors within the last `HEALTH_ERROR_WINDOW_SECS`, by category
+    pub fn error_counts(&self, verbose: bool) -> ErrorCounts {
+        let since = now_millis() - HEALTH_ERROR_WINDOW_SECS as i64 * 1000;
+        let errors = self.errors.lock().unwrap();
+        let recent: Vec<&RecentError> = errors.iter().filter(|e| e.time >= since).collect();
+
+        let mut by_category = BTreeMap::new();
+        for error in &recent {
+            *by_category.entry(error.category).or_insert(0) += 1;
+        }
+
+        ErrorCounts {
+            window_secs: HEALTH_ERROR_WINDOW_SECS,
+            total: recent.len(),
+            by_category,
+            recent: verbose.then(|| recent.into_iter().rev().cloned().collect()),
+        }
+    }
+}
+
+/// Middleware counting the errors handlers answer with
+pub async fn record_errors(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    if let Some(report) = response.extensions().get::<ErrorReport>() {
+        state
+            .health
+            .record("http", report.category, report.message.clone());
+    }
+    response
+}
@@ -11,15 +11,21 @@ pub fn register_mdns_service(
     version: &str,
     cert_fingerprint: &[u8; 32],
     username: &str,
+    pairing_required: bool,
 ) -> Result<ServiceDaemon, Box<dyn std::error::Error>> {
     let service_type = format!("_{}._tcp.local.", MDNS_SERVICE_TYPE.to_lowercase());
 
     let properties = HashMap::from([
         ("version".into(), version.into()),
-        ("paths".into(), "/submit, /status, /request".into()),
         (
-            "cert_fingerprint".into(),
-            hex::encode(cert_fingerprint).into(),
+            "paths".into(),
+            "/submit, /status, /request, /pair, /queue, /geolocate/check, /v1/geolocate, /export, /network_json"
+                .into(),
+        ),
+        ("cert_fingerprint".into(), hex::encode(cert_fingerprint)),
+        (
+            "pairing".into(),
+            if pairing_required { "required" } else { "optional" }.into(),
         ),
     ]);
 
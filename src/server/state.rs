@@ -0,0 +1,163 @@
+//! Shared application state handed to the HTTP handlers and background workers
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::now_millis;
+use crate::config::{self, Settings};
+use crate::devices::DeviceRegistry;
+use crate::error::Result;
+use crate::geosubmit::{
+    EmitterIndex, HttpClient, JsonlArchive, Position, Provider, SubmissionFilter, SubmissionQueue,
+    ZoneGuard,
+};
+use crate::history::History;
+use crate::position::{
+    Fix, FixedSource, GpsdSource, NmeaSource, PositionSource, arbitrate, source,
+};
+use crate::scanner::ScanCache;
+use crate::server::health::Health;
+use crate::stationary::StationaryNode;
+
+pub struct AppState {
+    pub settings: Settings,
+    pub http: HttpClient,
+    pub queue: Arc<SubmissionQueue>,
+    pub provider: Arc<Provider>,
+    pub filter: SubmissionFilter,
+    pub zones: ZoneGuard,
+    pub emitters: EmitterIndex,
+    /// `None` when history is disabled in the settings
+    pub history: Option<History>,
+    /// Local positions for submissions that come without one
+    pub position_sources: Vec<Arc<dyn PositionSource>>,
+    /// `None` unless stationary mode is enabled
+    pub stationary: Option<Arc<StationaryNode>>,
+    /// Recent results of on-demand scans
+    pub scans: ScanCache,
+    pub health: Arc<Health>,
+    /// Paired phones
+    pub devices: Arc<DeviceRegistry>,
+}
+
+impl AppState {
+    /// Build the application state, opening persistent stores in the data directory
+    pub fn new(settings: Settings) -> Result<Self> {
+        Self::open(settings, &config::data_dir())
+    }
+
+    /// Build the application state with its persistent stores in `data_dir`
+    pub fn open(settings: Settings, data_dir: &Path) -> Result<Self> {
+        let queue = SubmissionQueue::open(data_dir.join("queue"), &settings.submission)?;
+        let zones = ZoneGuard::open(
+            settings.exclusion_zones.clone(),
+            data_dir.join("excluded_emitters.json"),
+        )?;
+
+        let history = if settings.history.enabled {
+            Some(History::open(&data_dir.join(config::HISTORY_FILE))?)
+        } else {
+            None
+        };
+        let emitters = match &history {
+            Some(history) => EmitterIndex::from_learned(history.emitters()?),
+            None => EmitterIndex::new(),
+        };
+
+        let provider = if settings.submission.dry_run {
+            let archive = JsonlArchive::open(
+                data_dir.join("archive"),
+                settings.submission.archive_max_bytes,
+                settings.submission.archive_max_age_hours,
+                settings.submission.archive_max_files,
+            )?;
+            tracing::warn!(
+                "Dry-run mode: submissions are archived to {} instead of uploaded",
+                archive.active_path().display()
+            );
+            Provider::dry_run(archive)
+        } else {
+            Provider::beacondb(settings.submission.gzip, &settings.provider)
+        };
+
+        let mut position_sources: Vec<Arc<dyn PositionSource>> = Vec::new();
+        if settings.positioning.gpsd.enabled {
+            position_sources.push(Arc::new(GpsdSource::new(
+                settings.positioning.gpsd.address.clone(),
+            )));
+        }
+        if settings.positioning.nmea.enabled {
+            position_sources.push(Arc::new(NmeaSource::new(
+                settings.positioning.nmea.path.clone(),
+                settings.positioning.nmea.baud_rate,
+            )));
+        }
+
+        let stationary = if settings.stationary.enabled {
+            Some(Arc::new(StationaryNode::open(
+                settings.stationary.clone(),
+                data_dir.join("stationary_baseline.json"),
+            )?))
+        } else {
+            None
+        };
+
+        // A stationary node's configured position is a source like any other
+        if let Some(node) = &stationary {
+            position_sources.push(Arc::new(FixedSource::new(node.position().clone())));
+        }
+
+        Ok(AppState {
+            position_sources,
+            stationary,
+            scans: ScanCache::new(),
+            health: Arc::new(Health::new()),
+            devices: Arc::new(DeviceRegistry::open(data_dir.join(config::DEVICES_FILE))?),
+            http: HttpClient::from_settings(&settings.http)?,
+            filter: SubmissionFilter::new(settings.submission.clone()),
+            zones,
+            emitters,
+            history,
+            settings,
+            queue: Arc::new(queue),
+            provider: Arc::new(provider),
+        })
+    }
+
+    /// The position for a submission: the best of the client's position, if it
+    /// sent one, and the recent fixes of the local sources, or several of them fused
+    pub fn position_fix(&self, reported: Option<Position>) -> Option<Fix> {
+        let max_age = Duration::from_secs(self.settings.positioning.max_fix_age_secs);
+        let mut candidates = source::recent_fixes(&self.position_sources, max_age);
+        if let Some(position) = reported {
+            candidates.push(Fix {
+                position,
+                time: now_millis(),
+            });
+        }
+
+        arbitrate(candidates, self.settings.positioning.fuse)
+    }
+
+    /// Apply the history retention settings, forgetting expired emitters too
+    pub fn prune_history(&self) -> Result<()> {
+        let Some(history) = &self.history else {
+            return Ok(());
+        };
+
+        let pruned = history.prune(&self.settings.history)?;
+        if let Some(cutoff) = pruned.cutoff {
+            self.emitters.forget_last_seen_before(cutoff);
+        }
+        if pruned.submissions > 0 || pruned.emitters > 0 {
+            tracing::info!(
+                "[History] Pruned {} submission(s) and {} emitter(s)",
+                pruned.submissions,
+                pruned.emitters
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+//! Stationary node mode: scan and submit on a schedule from a fixed, configured position
+//!
+//! Nothing tells a fixed node it has been moved, so every scan is compared with a
+//! baseline of the access points seen at the configured position. A scan is only
+//! submitted if most of its access points are already in the baseline. The baseline
+//! is kept in the data directory, so a node moved while switched off doesn't simply
+//! learn its new surroundings on the next start.
+
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::now_millis;
+use crate::config::StationarySettings;
+use crate::error::{Error, Result};
+use crate::geosubmit::{self, LocationSource, Position};
+use crate::server::AppState;
+use crate::server::handlers::{Acceptance, spawn_accept_items};
+
+/// Access points seen in recent accepted scans at one position
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Baseline {
+    latitude: f64,
+    longitude: f64,
+    scans: VecDeque<HashSet<BDAddr>>,
+}
+
+impl Baseline {
+    fn is_at(&self, position: &Position) -> bool {
+        self.latitude == position.latitude && self.longitude == position.longitude
+    }
+
+    /// Fraction of `scan` that the baseline already knows
+    fn overlap(&self, scan: &HashSet<BDAddr>) -> f64 {
+        let known = scan
+            .iter()
+            .filter(|bssid| self.scans.iter().any(|seen| seen.contains(bssid)))
+            .count();
+        known as f64 / scan.len() as f64
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeState {
+    /// Still collecting the baseline; nothing is submitted yet
+    WarmingUp,
+    Stable,
+    /// The last scan didn't match the baseline; nothing is submitted until one does
+    Moved,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StationaryStatus {
+    pub state: NodeState,
+    pub position: Position,
+    pub interval_secs: u64,
+    pub baseline_scans: usize,
+    pub last_scan: Option<i64>,
+    /// Fraction of the last scan's access points found in the baseline
+    pub last_overlap: Option<f64>,
+    pub last_result: Option<String>,
+    pub submissions: u64,
+}
+
+pub struct StationaryNode {
+    settings: StationarySettings,
+    position: Position,
+    baseline_path: PathBuf,
+    baseline: Mutex<Baseline>,
+    status: Mutex<StationaryStatus>,
+}
+
+impl StationaryNode {
+    /// Set up the node at its configured position, loading the baseline from
+    /// `baseline_path` unless it was collected somewhere else
+    pub fn open(settings: StationarySettings, baseline_path: PathBuf) -> Result<Self> {
+        let (Some(latitude), Some(longitude)) = (settings.latitude, settings.longitude) else {
+            return Err(Error::Validation(
+                "stationary mode needs a latitude and longitude".to_string(),
+            ));
+        };
+        let position = Position {
+            latitude,
+            longitude,
+            accuracy: settings.accuracy,
+            altitude: settings.altitude,
+            altitudeAccuracy: 0.0,
+            heading: None,
+            speed: None,
+            source: LocationSource::Manual,
+        };
+        position.validate()?;
+        if settings.baseline_scans == 0 {
+            return Err(Error::Validation(
+                "stationary mode needs at least one baseline scan".to_string(),
+            ));
+        }
+        if settings.interval_secs == 0 {
+            return Err(Error::Validation(
+                "stationary mode needs an interval_secs of at least 1".to_string(),
+            ));
+        }
+
+        let baseline = match fs::read(&baseline_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Baseline::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let baseline = if baseline.is_at(&position) {
+            baseline
+        } else {
+            Baseline {
+                latitude,
+                longitude,
+                scans: VecDeque::new(),
+            }
+        };
+
+        let status = StationaryStatus {
+            state: if baseline.scans.len() < settings.baseline_scans {
+                NodeState::WarmingUp
+            } else {
+                NodeState::Stable
+            },
+            position: position.clone(),
+            interval_secs: settings.interval_secs,
+            baseline_scans: baseline.scans.len(),
+            last_scan: None,
+            last_overlap: None,
+            last_result: None,
+            submissions: 0,
+        };
+
+        Ok(StationaryNode {
+            settings,
+            position,
+            baseline_path,
+            baseline: Mutex::new(baseline),
+            status: Mutex::new(status),
+        })
+    }
+
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
+
+    pub fn status(&self) -> StationaryStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Decide what to do with a scan's access points, updating the baseline.
+    /// Returns whether the scan may be submitted.
+    fn judge(&self, scan: HashSet<BDAddr>) -> Result<bool> {
+        let mut baseline = self.baseline.lock().unwrap();
+        let mut status = self.status.lock().unwrap();
+        status.last_scan = Some(now_millis());
+        status.last_overlap = None;
+
+        if scan.is_empty() || scan.len() < self.settings.min_access_points {
+            status.last_result = Some(format!(
+                "too few access points ({}) to tell whether the node has moved",
+                scan.len()
+            ));
+            return Ok(false);
+        }
+
+        if baseline.scans.len() < self.settings.baseline_scans {
+            baseline.scans.push_back(scan);
+            fs::write(&self.baseline_path, serde_json::to_vec(&*baseline)?)?;
+            status.baseline_scans = baseline.scans.len();
+            status.last_result = Some("collecting the baseline".to_string());
+            if baseline.scans.len() == self.settings.baseline_scans {
+                status.state = NodeState::Stable;
+            }
+            return Ok(false);
+        }
+
+        let overlap = baseline.overlap(&scan);
+        status.last_overlap = Some(overlap);
+        if overlap < self.settings.min_overlap {
+            if status.state != NodeState::Moved {
+                tracing::warn!(
+                    "[Stationary] Only {:.0}% of the access points match the baseline; not submitting until they do",
+                    overlap * 100.0
+                );
+            }
+            status.state = NodeState::Moved;
+            status.last_result = Some("access points don't match the baseline".to_string());
+            return Ok(false);
+        }
+
+        if status.state == NodeState::Moved {
+            tracing::info!("[Stationary] Access points match the baseline again");
+        }
+        status.state = NodeState::Stable;
+
+        // Keep the baseline current as access points come and go
+        baseline.scans.push_back(scan);
+        while baseline.scans.len() > self.settings.baseline_scans {
+            baseline.scans.pop_front();
+        }
+        fs::write(&self.baseline_path, serde_json::to_vec(&*baseline)?)?;
+        Ok(true)
+    }
+
+    /// Scan once and submit the result if the node still seems to be in place
+    pub async fn scan_and_submit(&self, state: &Arc<AppState>) -> Result<()> {
+        let payload =
+            geosubmit::assemble_geo_payload(serde_json::to_value(&self.position)?, None).await?;
+        let scan = payload
+            .wifiAccessPoints
+            .iter()
+            .map(|ap| ap.bssid)
+            .collect::<HashSet<_>>();
+
+        if !self.judge(scan)? {
+            return Ok(());
+        }
+
+        let acceptance = spawn_accept_items(Arc::clone(state), payload).await?;
+        let mut status = self.status.lock().unwrap();
+        if acceptance == Acceptance::Queued {
+            status.submissions += 1;
+        }
+        status.last_result = Some(acceptance.to_string());
+        Ok(())
+    }
+}
+
+/// Scan and submit every `interval_secs` for as long as the process runs
+pub async fn run(node: Arc<StationaryNode>, state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(node.settings.interval_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    tracing::info!(
+        "[Stationary] Scanning every {} s at {}, {}",
+        node.settings.interval_secs,
+        node.position.latitude,
+        node.position.longitude
+    );
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = node.scan_and_submit(&state).await {
+            tracing::error!("[Stationary] Scan failed: {}", e);
+            state.health.record_error("stationary", &e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn rejects_a_zero_interval() {
+        let settings = StationarySettings {
+            enabled: true,
+            latitude: Some(52.5),
+            longitude: Some(13.4),
+            interval_secs: 0,
+            ..StationarySettings::default()
+        };
+        let baseline_path = test_support::temp_dir("stationary").join("baseline.json");
+
+        let error = StationaryNode::open(settings.clone(), baseline_path.clone())
+            .err()
+            .unwrap();
+        assert!(matches!(error, Error::Validation(_)));
+        assert!(
+            StationaryNode::open(
+                StationarySettings {
+                    interval_secs: 1,
+                    ..settings
+                },
+                baseline_path
+            )
+            .is_ok()
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Certificates and loopback servers shared by the unit tests
+
+use axum::Router;
+use btleplug::api::BDAddr;
+use rcgen::{CertifiedKey, KeyPair, generate_simple_self_signed};
+use rustls::ServerConfig;
+use rustls::pki_types::PrivateKeyDer;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+use crate::config::Settings;
+use crate::geosubmit::{LocationSource, Position, items};
+use crate::scanner::WifiBssid;
+use crate::scanner::wifi::PhyType;
+use crate::server::AppState;
+
+/// A self-signed certificate for `localhost` and `127.0.0.1`
+pub fn self_signed() -> CertifiedKey<KeyPair> {
+    install_crypto_provider();
+    generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()]).unwrap()
+}
+
+pub fn install_crypto_provider() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+}
+
+pub fn server_config(identity: &CertifiedKey<KeyPair>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![identity.cert.der().clone()],
+            PrivateKeyDer::Pkcs8(identity.signing_key.serialize_der().into()),
+        )
+        .unwrap()
+}
+
+/// Serve `router` over plain HTTP on a free loopback port
+pub async fn serve(router: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    address
+}
+
+/// An empty directory under the system temp dir, unique to this test
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "serviceberry-test-{}-{}-{}",
+        name,
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Application state for `settings`, keeping its stores in a fresh temp dir
+pub fn app_state(settings: Settings) -> Arc<AppState> {
+    Arc::new(AppState::open(settings, &temp_dir("state")).unwrap())
+}
+
+pub fn position(latitude: f64, longitude: f64) -> Position {
+    Position {
+        latitude,
+        longitude,
+        accuracy: 10.0,
+        altitude: 0.0,
+        altitudeAccuracy: 0.0,
+        heading: None,
+        speed: None,
+        source: LocationSource::Gps,
+    }
+}
+
+pub fn access_point(last_byte: u8) -> WifiBssid {
+    WifiBssid {
+        ssid: None,
+        bssid: BDAddr::from([0x02, 0, 0, 0, 0, last_byte]),
+        age: None,
+        channel: Some(6),
+        frequency: 2437,
+        phy: PhyType::Legacy,
+        rssi: -60,
+    }
+}
+
+/// A payload observed at `timestamp` ms, seeing one access point per byte in `access_points`
+pub fn payload(timestamp: u128, position: Position, access_points: &[u8]) -> items {
+    items {
+        timestamp,
+        position,
+        bluetoothBeacons: Vec::new(),
+        wifiAccessPoints: access_points.iter().copied().map(access_point).collect(),
+        CellTowers: None,
+    }
+}